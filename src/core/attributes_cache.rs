@@ -1,9 +1,11 @@
-use super::fcall::{Stat, Time};
+use super::error::{errno::*, Error};
+use super::fcall::{Stat, Time, S_IFMT, XATTR_CREATE, XATTR_REPLACE};
 use super::lib_utils::Result;
 use log;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fs;
 use std::fs::Metadata;
+use std::path::{Path, PathBuf};
 #[cfg(target_os = "linux")]
 use std::time::Duration;
 
@@ -13,9 +15,57 @@ use std::os::unix::prelude::MetadataExt;
 #[cfg(target_os = "windows")]
 use std::os::windows::fs::MetadataExt;
 
+/// Attributes given to files the first time they are seen
+#[derive(Debug, Copy, Clone)]
+pub struct AttributeDefaults {
+    /// Owner reported for every file
+    pub uid: u32,
+    /// Group reported for every file
+    pub gid: u32,
+    /// Permission bits of regular files, taken from the file itself if not set
+    pub file_mode: Option<u32>,
+    /// Permission bits of directories, taken from the directory itself if not set
+    pub dir_mode: Option<u32>,
+}
+
+impl Default for AttributeDefaults {
+    fn default() -> Self {
+        Self {
+            uid: 1000,
+            gid: 1000,
+            file_mode: None,
+            dir_mode: None,
+        }
+    }
+}
+
+impl AttributeDefaults {
+    /// Replaces permission bits of `mode` if defaults set them for its file type
+    fn mode(&self, mode: u32, is_dir: bool) -> u32 {
+        let permissions = if is_dir {
+            self.dir_mode
+        } else {
+            self.file_mode
+        };
+        match permissions {
+            Some(permissions) => (mode & S_IFMT) | (permissions & !S_IFMT),
+            None => mode,
+        }
+    }
+}
+
+/// Device and inode of a file, same for all its hard links
+pub type FileId = (u64, u64);
+
 pub struct VirtualAttributesProvider {
-    pub attributes_map: HashMap<String, VirtualAttributes>,
+    /// Attributes by file, shared by all hard links to it
+    pub attributes_map: HashMap<FileId, VirtualAttributes>,
+    /// File of every path seen
+    pub paths: HashMap<PathBuf, FileId>,
+    /// Extended attributes by file, for hosts which don't keep them
+    pub xattrs: HashMap<FileId, BTreeMap<String, Vec<u8>>>,
     pub next_inode: u64,
+    pub defaults: AttributeDefaults,
 }
 
 #[derive(Debug, Copy, Clone)]
@@ -31,6 +81,11 @@ pub struct VirtualAttributes {
     // TODO: filetype is unused
     pub file_type: VAFileType,
     pub mode: u32,
+    pub uid: u32,
+    pub gid: u32,
+    pub nlink: u64,
+    /// Device number of device nodes
+    pub rdev: u64,
     pub creation_time: u64,
     pub access_time: u64,
     pub write_time: u64,
@@ -38,67 +93,223 @@ pub struct VirtualAttributes {
 
 impl VirtualAttributesProvider {
     pub fn new() -> VirtualAttributesProvider {
+        Self::with_defaults(AttributeDefaults::default())
+    }
+
+    pub fn with_defaults(defaults: AttributeDefaults) -> VirtualAttributesProvider {
         VirtualAttributesProvider {
             attributes_map: HashMap::new(),
+            paths: HashMap::new(),
+            xattrs: HashMap::new(),
             next_inode: 100,
+            defaults,
         }
     }
 
     pub fn get_or_create_virtual_attributes(
         &mut self,
-        file_path: String,
+        file_path: PathBuf,
     ) -> Result<VirtualAttributes> {
-        match self.attributes_map.get_mut(&file_path) {
-            Some(el) => {
-                let metadata = fs::metadata(&file_path).map_err(|error| {
-                    #[cfg(feature = "debug-msg")]
-                    log::error!("File not found despite existing attributes: {}", file_path);
-                    error
-                })?;
+        let metadata = fs::symlink_metadata(&file_path).map_err(|error| {
+            #[cfg(feature = "debug-msg")]
+            log::debug!("File not found: {}", file_path.display());
+            error
+        })?;
 
+        let id = file_id(&metadata).or_else(|| self.paths.get(&file_path).copied());
+        if let Some(id) = id {
+            if let Some(el) = self.attributes_map.get_mut(&id) {
                 el.update(metadata);
+                let va = *el;
+                self.paths.insert(file_path, id);
+                return Ok(va);
+            }
+        }
+
+        let va = VirtualAttributes::new(self.next_inode, &metadata, &self.defaults);
+        let id = id.unwrap_or((0, va.inode));
+
+        log::debug!(
+            "Created new virtual attributes for file: {} inode: {}",
+            file_path.display(),
+            va.inode
+        );
+        self.next_inode += 1;
+        self.paths.insert(file_path, id);
+        self.attributes_map.insert(id, va);
+        Ok(va)
+    }
+
+    /// Gives fresh attributes to `file_path`, just made on the host
+    ///
+    /// The host may have reused the inode of a removed file, whose attributes must not be kept.
+    pub fn replace_virtual_attributes(&mut self, file_path: PathBuf) -> Result<VirtualAttributes> {
+        let metadata = fs::symlink_metadata(&file_path)?;
+        if let Some(id) = file_id(&metadata).or_else(|| self.paths.remove(&file_path)) {
+            self.attributes_map.remove(&id);
+            self.xattrs.remove(&id);
+        }
+        self.get_or_create_virtual_attributes(file_path)
+    }
 
-                Ok(*el)
+    /// Forgets `file_path`, removed from the host, and attributes of its file if it was the
+    /// last link to it, `metadata` is of the file before removal
+    pub fn unlink(&mut self, file_path: &Path, metadata: &Metadata) {
+        let id = file_id(metadata).or_else(|| self.paths.get(file_path).copied());
+        if self.paths.get(file_path) == id.as_ref() {
+            self.paths.remove(file_path);
+        }
+
+        let last_link = match file_links(metadata) {
+            Some(links) => metadata.is_dir() || links <= 1,
+            None => !self.paths.values().any(|other| Some(*other) == id),
+        };
+        if let (Some(id), true) = (id, last_link) {
+            self.attributes_map.remove(&id);
+            self.xattrs.remove(&id);
+        }
+    }
+
+    /// Makes `new_path`, a hard link to `file_path`, share its attributes
+    pub fn link(&mut self, file_path: &Path, new_path: PathBuf) -> Result<()> {
+        match self.paths.get(file_path) {
+            Some(&id) => {
+                self.paths.insert(new_path, id);
+                Ok(())
             }
-            None => {
-                let metadata = fs::metadata(&file_path).map_err(|error| {
-                    #[cfg(feature = "debug-msg")]
-                    log::debug!("File not found: {}", file_path);
-                    error
-                })?;
-
-                let va = VirtualAttributes::new(self.next_inode, &metadata);
-
-                log::debug!(
-                    "Created new virtual attributes for file: {} inode: {}",
-                    file_path,
-                    self.next_inode
-                );
-                self.next_inode += 1;
-                self.attributes_map.insert(file_path, va);
-                Ok(va)
+            None => res!(io_err!(
+                Other,
+                format!(
+                    "Virtual attributes not found for file {}",
+                    file_path.display()
+                )
+            )),
+        }
+    }
+
+    /// Moves `file_path`, renamed on the host, and paths beneath it to `new_path`
+    pub fn rename(&mut self, file_path: &Path, new_path: &Path) {
+        let moved: Vec<_> = self
+            .paths
+            .keys()
+            .filter_map(|path| {
+                let rest = path.strip_prefix(file_path).ok()?;
+                Some((path.clone(), new_path.join(rest)))
+            })
+            .collect();
+        for (path, renamed) in moved {
+            if let Some(id) = self.paths.remove(&path) {
+                self.paths.insert(renamed, id);
             }
         }
     }
+
+    fn file(&self, file_path: &Path) -> Result<FileId> {
+        self.paths.get(file_path).copied().ok_or_else(|| {
+            io_err!(
+                Other,
+                format!(
+                    "Virtual attributes not found for file {}",
+                    file_path.display()
+                )
+            )
+            .into()
+        })
+    }
+
+    /// Value of virtual extended attribute `name`
+    pub fn get_xattr(&self, file_path: &Path, name: &str) -> Result<Vec<u8>> {
+        let id = self.file(file_path)?;
+        self.xattrs
+            .get(&id)
+            .and_then(|xattrs| xattrs.get(name))
+            .cloned()
+            .ok_or(Error::No(ENODATA))
+    }
+
+    /// Names of virtual extended attributes, each one terminated by nul
+    pub fn list_xattrs(&self, file_path: &Path) -> Result<Vec<u8>> {
+        let id = self.file(file_path)?;
+        let mut names = Vec::new();
+        for name in self.xattrs.get(&id).into_iter().flat_map(|x| x.keys()) {
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        Ok(names)
+    }
+
+    /// Sets virtual extended attribute `name`, `flags` are `XATTR_CREATE` or `XATTR_REPLACE`
+    pub fn set_xattr(
+        &mut self,
+        file_path: &Path,
+        name: &str,
+        value: Vec<u8>,
+        flags: u32,
+    ) -> Result<()> {
+        let id = self.file(file_path)?;
+        let xattrs = self.xattrs.entry(id).or_default();
+        match (xattrs.contains_key(name), flags) {
+            (true, XATTR_CREATE) => Err(Error::No(EEXIST)),
+            (false, XATTR_REPLACE) => Err(Error::No(ENODATA)),
+            _ => {
+                xattrs.insert(name.to_owned(), value);
+                Ok(())
+            }
+        }
+    }
+
+    /// Removes virtual extended attribute `name`
+    pub fn remove_xattr(&mut self, file_path: &Path, name: &str) -> Result<()> {
+        let id = self.file(file_path)?;
+        self.xattrs
+            .get_mut(&id)
+            .and_then(|xattrs| xattrs.remove(name))
+            .map(|_| ())
+            .ok_or(Error::No(ENODATA))
+    }
+
     pub fn update_virtual_attributes<F: FnOnce(&mut VirtualAttributes)>(
         &mut self,
-        file_path: String,
+        file_path: PathBuf,
         f: F,
     ) -> Result<()> {
-        if let Some(el) = self.attributes_map.get_mut(&file_path) {
+        let el = self
+            .paths
+            .get(&file_path)
+            .and_then(|id| self.attributes_map.get_mut(id));
+        if let Some(el) = el {
             Ok(f(el))
         } else {
             res!(io_err!(
                 Other,
-                format!("Virtual attributes not found for directory {}", file_path)
+                format!(
+                    "Virtual attributes not found for directory {}",
+                    file_path.display()
+                )
             ))
         }
     }
 }
 
+/// Device and inode identifying the file whichever of its hard links is used, if the host has them
+#[cfg(target_os = "windows")]
+fn file_id(_metadata: &Metadata) -> Option<FileId> {
+    None
+}
+
+/// Number of hard links to the file, if the host reports it
+#[cfg(target_os = "windows")]
+fn file_links(_metadata: &Metadata) -> Option<u64> {
+    None
+}
+
 #[cfg(target_os = "windows")]
 impl VirtualAttributes {
-    pub(crate) fn new(next_inode: u64, metadata: &Metadata) -> VirtualAttributes {
+    pub(crate) fn new(
+        next_inode: u64,
+        metadata: &Metadata,
+        defaults: &AttributeDefaults,
+    ) -> VirtualAttributes {
         let (file_type, default_mode) = if metadata.is_dir() {
             (VAFileType::VaDirectory, 16895)
         } else {
@@ -109,7 +320,11 @@ impl VirtualAttributes {
             inode: next_inode,
             file_type: file_type,
             file_size: metadata.file_size(),
-            mode: default_mode,
+            mode: defaults.mode(default_mode, metadata.is_dir()),
+            uid: defaults.uid,
+            gid: defaults.gid,
+            nlink: 1,
+            rdev: 0,
             creation_time: metadata.creation_time(),
             access_time: metadata.last_access_time(),
             write_time: metadata.last_write_time(),
@@ -141,10 +356,10 @@ impl From<VirtualAttributes> for Stat {
 
         let stat = Stat {
             mode: va.mode,
-            uid: 1000,
-            gid: 1000,
-            nlink: 1,
-            rdev: 0,
+            uid: va.uid,
+            gid: va.gid,
+            nlink: va.nlink,
+            rdev: va.rdev,
             size: va.file_size,
             blksize: 4096,
             blocks: va.file_size / 4096,
@@ -165,9 +380,25 @@ impl From<VirtualAttributes> for Stat {
     }
 }
 
+/// Device and inode identifying the file whichever of its hard links is used, if the host has them
+#[cfg(target_os = "linux")]
+fn file_id(metadata: &Metadata) -> Option<FileId> {
+    Some((metadata.dev(), metadata.ino()))
+}
+
+/// Number of hard links to the file, if the host reports it
+#[cfg(target_os = "linux")]
+fn file_links(metadata: &Metadata) -> Option<u64> {
+    Some(metadata.nlink())
+}
+
 #[cfg(target_os = "linux")]
 impl VirtualAttributes {
-    pub(crate) fn new(_next_inode: u64, metadata: &Metadata) -> VirtualAttributes {
+    pub(crate) fn new(
+        _next_inode: u64,
+        metadata: &Metadata,
+        defaults: &AttributeDefaults,
+    ) -> VirtualAttributes {
         // TODO: this probably can be better for linux?
         let file_type = if metadata.is_dir() {
             VAFileType::VaDirectory
@@ -180,7 +411,11 @@ impl VirtualAttributes {
             file_type: file_type,
             file_size: metadata.size(),
 
-            mode: metadata.mode(),
+            mode: defaults.mode(metadata.mode(), metadata.is_dir()),
+            uid: defaults.uid,
+            gid: defaults.gid,
+            nlink: metadata.nlink(),
+            rdev: metadata.rdev(),
 
             creation_time: Duration::new(metadata.ctime() as u64, metadata.ctime_nsec() as u32)
                 .as_nanos() as u64,
@@ -201,6 +436,7 @@ impl VirtualAttributes {
         // TODO: confirm those values
         self.inode = metadata.ino();
         self.file_size = metadata.size();
+        self.nlink = metadata.nlink();
         self.creation_time =
             Duration::new(metadata.ctime() as u64, metadata.ctime_nsec() as u32).as_nanos() as u64;
         self.access_time =
@@ -219,10 +455,10 @@ impl From<VirtualAttributes> for Stat {
 
         let stat = Stat {
             mode: va.mode,
-            uid: 1000,
-            gid: 1000,
-            nlink: 1,
-            rdev: 0,
+            uid: va.uid,
+            gid: va.gid,
+            nlink: va.nlink,
+            rdev: va.rdev,
             size: va.file_size,
             blksize: 4096,
             blocks: va.file_size / 4096,
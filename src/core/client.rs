@@ -0,0 +1,877 @@
+//! Asynchronous client side 9P core.
+//!
+//! `P9Client` talks to a 9P2000.L server over any byte stream, or passes
+//! `Msg` values to a server in the same process without encoding them.
+//! Requests from concurrent tasks are multiplexed on the single connection,
+//! each one waits for the response carrying its tag. Calls dropped before
+//! their response are flushed with Tflush.
+//!
+//! # Protocol
+//! 9P2000.L
+
+use std::collections::{HashMap, HashSet};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::{Sink, SinkExt, Stream};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{oneshot, Mutex};
+use tokio::task::JoinHandle;
+use tokio_stream::StreamExt;
+use tokio_util::codec::Framed;
+
+use super::codec::P9Codec;
+use super::error::{self, errno::*};
+use super::fcall::*;
+use super::lib_utils::Result;
+use super::srv::DEFAULT_MAX_MSIZE;
+
+/// Largest number of names a single Twalk may carry
+const MAXWELEM: usize = 16;
+
+/// Fid on the server, obtained from `attach` or `walk` and released by `clunk`
+///
+/// Dropping it without `clunk` leaves the fid open on the server
+/// until the connection is closed.
+#[derive(Debug)]
+pub struct ClientFid {
+    fid: u32,
+    qid: Qid,
+    iounit: u32,
+}
+
+impl ClientFid {
+    /// Raw fid number used on the wire
+    pub fn id(&self) -> u32 {
+        self.fid
+    }
+
+    /// Qid of the file the fid points to
+    pub fn qid(&self) -> &Qid {
+        &self.qid
+    }
+
+    /// I/O unit reported when the fid was opened, `0` until then
+    pub fn iounit(&self) -> u32 {
+        self.iounit
+    }
+}
+
+/// Requests waiting for their responses, by tag
+#[derive(Default)]
+struct Tags {
+    pending: HashMap<u16, oneshot::Sender<Fcall>>,
+    /// Tags of requests given up by their callers, not reused until Rflush
+    flushing: HashSet<u16>,
+    next: u16,
+    closed: bool,
+}
+
+impl Tags {
+    /// Registers request, with the first unused tag unless `tag` is given
+    fn register(&mut self, tag: Option<u16>) -> Result<(u16, oneshot::Receiver<Fcall>)> {
+        if self.closed {
+            return res!(io_err!(
+                ConnectionAborted,
+                "Connection to the server is closed"
+            ));
+        }
+
+        let tag = match tag {
+            Some(tag) => tag,
+            None => loop {
+                if self.pending.len() + self.flushing.len() >= NOTAG as usize {
+                    return Err(error::Error::No(EAGAIN));
+                }
+                let tag = self.next;
+                self.next = self.next.wrapping_add(1);
+                if tag != NOTAG && !self.pending.contains_key(&tag) && !self.flushing.contains(&tag)
+                {
+                    break tag;
+                }
+            },
+        };
+
+        let (sender, receiver) = oneshot::channel();
+        self.pending.insert(tag, sender);
+        Ok((tag, receiver))
+    }
+
+    /// Fails requests still waiting and those made later
+    fn close(&mut self) {
+        self.closed = true;
+        self.pending.clear();
+        self.flushing.clear();
+    }
+}
+
+/// Request waiting for its response, flushed if the call is dropped before it comes
+struct PendingCall {
+    tag: u16,
+    tags: Arc<std::sync::Mutex<Tags>>,
+    writer: Arc<Mutex<Writer>>,
+    finished: bool,
+}
+
+impl Drop for PendingCall {
+    fn drop(&mut self) {
+        if self.finished || self.tag == NOTAG {
+            return;
+        }
+        // Without a runtime the tag stays reserved until the response comes
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            return;
+        };
+
+        {
+            let mut tags = self.tags.lock().unwrap();
+            if tags.pending.remove(&self.tag).is_none() {
+                return;
+            }
+            tags.flushing.insert(self.tag);
+        }
+        runtime.spawn(flush(self.tag, self.tags.clone(), self.writer.clone()));
+    }
+}
+
+/// Cancels request with `oldtag` on the server, the tag is free again once Rflush comes
+async fn flush(oldtag: u16, tags: Arc<std::sync::Mutex<Tags>>, writer: Arc<Mutex<Writer>>) {
+    let registered = tags.lock().unwrap().register(None);
+    let (tag, response) = match registered {
+        Ok(registered) => registered,
+        Err(e) => {
+            log::warn!("Failed flushing tag {}: {}", oldtag, e);
+            return;
+        }
+    };
+
+    let sent = writer
+        .lock()
+        .await
+        .send(Msg {
+            tag,
+            body: Fcall::Tflush { oldtag },
+        })
+        .await;
+    if sent.is_err() {
+        tags.lock().unwrap().pending.remove(&tag);
+        return;
+    }
+
+    if response.await.is_ok() {
+        tags.lock().unwrap().flushing.remove(&oldtag);
+    }
+}
+
+/// Fid numbers, reused once clunked
+#[derive(Default)]
+struct Fids {
+    next: u32,
+    free: Vec<u32>,
+}
+
+impl Fids {
+    fn alloc(&mut self) -> u32 {
+        self.free.pop().unwrap_or_else(|| {
+            let fid = self.next;
+            self.next += 1;
+            fid
+        })
+    }
+
+    fn release(&mut self, fid: u32) {
+        self.free.push(fid);
+    }
+}
+
+type Writer = Pin<Box<dyn Sink<Msg, Error = error::Error> + Send>>;
+
+/// 9P2000.L client multiplexing requests over a single connection
+pub struct P9Client {
+    writer: Arc<Mutex<Writer>>,
+    tags: Arc<std::sync::Mutex<Tags>>,
+    fids: std::sync::Mutex<Fids>,
+    msize: u32,
+    reader: JoinHandle<()>,
+}
+
+impl Drop for P9Client {
+    fn drop(&mut self) {
+        self.reader.abort();
+        self.tags.lock().unwrap().close();
+    }
+}
+
+/// Hands responses over to the requests waiting for them until the connection breaks
+async fn receive<R>(mut responses: R, tags: Arc<std::sync::Mutex<Tags>>)
+where
+    R: Stream<Item = Result<Msg>> + std::marker::Unpin,
+{
+    while let Some(response) = responses.next().await {
+        let msg = match response {
+            Ok(msg) => msg,
+            Err(e) => {
+                log::error!("Failed reading response: {}", e);
+                break;
+            }
+        };
+
+        let mut tags = tags.lock().unwrap();
+        match tags.pending.remove(&msg.tag) {
+            Some(sender) => {
+                let _ = sender.send(msg.body);
+            }
+            // Response which came before Rflush of a request given up
+            None if tags.flushing.contains(&msg.tag) => {}
+            None => log::warn!("Response with unknown tag {}", msg.tag),
+        }
+    }
+
+    // Requests still waiting see the connection closed
+    tags.lock().unwrap().close();
+}
+
+/// Error reported in Rlerror, 9P2000.L uses Linux errno values
+fn remote_error(ecode: u32) -> error::Error {
+    error::Error::Io(std::io::Error::from_raw_os_error(ecode as i32))
+}
+
+fn unexpected(response: Fcall) -> error::Error {
+    log::error!("Unexpected response: {:?}", MsgType::from(&response));
+    error::Error::No(EPROTO)
+}
+
+impl P9Client {
+    /// Negotiates 9P2000.L session over `stream`, proposing `DEFAULT_MAX_MSIZE`
+    pub async fn connect<S>(stream: S) -> Result<Self>
+    where
+        S: 'static + AsyncRead + AsyncWrite + Send,
+    {
+        Self::connect_with_msize(stream, DEFAULT_MAX_MSIZE).await
+    }
+
+    /// Negotiates 9P2000.L session over `stream`, proposing `msize`
+    pub async fn connect_with_msize<S>(stream: S, msize: u32) -> Result<Self>
+    where
+        S: 'static + AsyncRead + AsyncWrite + Send,
+    {
+        let codec = P9Codec::new(msize);
+
+        let client =
+            Self::connect_msgs_with_msize(Framed::new(stream, codec.clone()), msize).await?;
+        codec.set_msize(client.msize);
+        Ok(client)
+    }
+
+    /// Negotiates 9P2000.L session over `transport` passing `Msg` values as they are,
+    /// proposing `DEFAULT_MAX_MSIZE`
+    ///
+    /// Requests are sent to the transport and responses read from it, e.g. from
+    /// `InprocServer::attach_channel`, which skips encoding messages altogether.
+    pub async fn connect_msgs<T>(transport: T) -> Result<Self>
+    where
+        T: 'static + Sink<Msg, Error = error::Error> + Stream<Item = Result<Msg>> + Send,
+    {
+        Self::connect_msgs_with_msize(transport, DEFAULT_MAX_MSIZE).await
+    }
+
+    /// Negotiates 9P2000.L session over `transport` passing `Msg` values, proposing `msize`
+    pub async fn connect_msgs_with_msize<T>(transport: T, msize: u32) -> Result<Self>
+    where
+        T: 'static + Sink<Msg, Error = error::Error> + Stream<Item = Result<Msg>> + Send,
+    {
+        let (requests, responses) = futures::StreamExt::split(transport);
+        let tags: Arc<std::sync::Mutex<Tags>> = Default::default();
+
+        let mut client = Self {
+            writer: Arc::new(Mutex::new(Box::pin(requests))),
+            tags: tags.clone(),
+            fids: Default::default(),
+            msize,
+            reader: tokio::spawn(receive(responses, tags)),
+        };
+
+        let version = Fcall::Tversion {
+            msize,
+            version: P92000L.to_owned(),
+        };
+        match client.call(Some(NOTAG), version).await? {
+            Fcall::Rversion {
+                msize: agreed,
+                version,
+            } => {
+                if version != P92000L {
+                    return Err(error::Error::No(EPROTONOSUPPORT));
+                }
+                if agreed > msize || agreed <= IOHDRSZ {
+                    return Err(error::Error::No(EPROTO));
+                }
+                client.msize = agreed;
+            }
+            response => return Err(unexpected(response)),
+        }
+
+        Ok(client)
+    }
+
+    /// Msize agreed with the server
+    pub fn msize(&self) -> u32 {
+        self.msize
+    }
+
+    /// Sends request and waits for its response, Rlerror is turned into error
+    async fn call(&self, tag: Option<u16>, body: Fcall) -> Result<Fcall> {
+        let (tag, response) = self.tags.lock().unwrap().register(tag)?;
+        let mut pending = PendingCall {
+            tag,
+            tags: self.tags.clone(),
+            writer: self.writer.clone(),
+            finished: false,
+        };
+
+        let sent = self.writer.lock().await.send(Msg { tag, body }).await;
+        if let Err(e) = sent {
+            pending.finished = true;
+            self.tags.lock().unwrap().pending.remove(&tag);
+            return Err(e);
+        }
+
+        let response = response.await;
+        pending.finished = true;
+        match response {
+            Ok(Fcall::Rlerror { ecode }) => Err(remote_error(ecode)),
+            Ok(response) => Ok(response),
+            Err(_) => res!(io_err!(
+                ConnectionAborted,
+                "Connection to the server was closed"
+            )),
+        }
+    }
+
+    async fn rpc(&self, body: Fcall) -> Result<Fcall> {
+        self.call(None, body).await
+    }
+
+    /// Largest payload of a single read or write on `fid`
+    fn iounit(&self, fid: &ClientFid) -> u32 {
+        let max = self.msize - IOHDRSZ;
+        match fid.iounit {
+            0 => max,
+            iounit => iounit.min(max),
+        }
+    }
+
+    fn alloc_fid(&self) -> u32 {
+        self.fids.lock().unwrap().alloc()
+    }
+
+    fn release_fid(&self, fid: u32) {
+        self.fids.lock().unwrap().release(fid);
+    }
+
+    /// Attaches to the tree `aname` exported by the server as `uname`
+    pub async fn attach(&self, uname: &str, aname: &str) -> Result<ClientFid> {
+        self.attach_with_uid(uname, aname, NONUNAME).await
+    }
+
+    /// Attaches to the tree `aname` as `uname` with numeric uid `n_uname`
+    pub async fn attach_with_uid(
+        &self,
+        uname: &str,
+        aname: &str,
+        n_uname: u32,
+    ) -> Result<ClientFid> {
+        let fid = self.alloc_fid();
+        let attached = self
+            .rpc(Fcall::Tattach {
+                fid,
+                afid: NOFID,
+                uname: uname.to_owned(),
+                aname: aname.to_owned(),
+                n_uname,
+            })
+            .await;
+
+        match attached {
+            Ok(Fcall::Rattach { qid }) => Ok(ClientFid {
+                fid,
+                qid,
+                iounit: 0,
+            }),
+            Ok(response) => {
+                self.release_fid(fid);
+                Err(unexpected(response))
+            }
+            Err(e) => {
+                self.release_fid(fid);
+                Err(e)
+            }
+        }
+    }
+
+    /// Walks `names` from `fid` to `newfid`, returns qid of the last name
+    ///
+    /// Fails with `ENOENT` unless every name is found, `newfid` is then left as it was.
+    async fn walk_once(&self, fid: u32, newfid: u32, names: &[&str]) -> Result<Option<Qid>> {
+        match self
+            .rpc(Fcall::Twalk {
+                fid,
+                newfid,
+                wnames: names.iter().map(|name| name.to_string()).collect(),
+            })
+            .await?
+        {
+            Fcall::Rwalk { wqids } if wqids.len() == names.len() => Ok(wqids.last().copied()),
+            Fcall::Rwalk { .. } => Err(error::Error::No(ENOENT)),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Walks from `fid` through `names`, returns new fid for the file reached
+    ///
+    /// Empty `names` clone `fid`. Fails with `ENOENT` unless every name is found.
+    pub async fn walk(&self, fid: &ClientFid, names: &[&str]) -> Result<ClientFid> {
+        let mut chunks = names.chunks(MAXWELEM);
+
+        // First walk creates the new fid, even with nothing to walk
+        let newfid = self.alloc_fid();
+        let qid = match self
+            .walk_once(fid.fid, newfid, chunks.next().unwrap_or_default())
+            .await
+        {
+            Ok(qid) => qid.unwrap_or(fid.qid),
+            Err(e) => {
+                self.release_fid(newfid);
+                return Err(e);
+            }
+        };
+        let mut walked = ClientFid {
+            fid: newfid,
+            qid,
+            iounit: 0,
+        };
+
+        for chunk in chunks {
+            match self.walk_once(walked.fid, walked.fid, chunk).await {
+                Ok(qid) => walked.qid = qid.unwrap_or(walked.qid),
+                Err(e) => {
+                    self.clunk(walked).await?;
+                    return Err(e);
+                }
+            }
+        }
+
+        Ok(walked)
+    }
+
+    /// Opens the file `fid` points to with Linux open `flags`
+    pub async fn open(&self, fid: &mut ClientFid, flags: u32) -> Result<()> {
+        match self
+            .rpc(Fcall::Tlopen {
+                fid: fid.fid,
+                flags,
+            })
+            .await?
+        {
+            Fcall::Rlopen { qid, iounit } => {
+                fid.qid = qid;
+                fid.iounit = iounit;
+                Ok(())
+            }
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Creates and opens file `name` in directory `fid`, which then points to the new file
+    pub async fn create(
+        &self,
+        fid: &mut ClientFid,
+        name: &str,
+        flags: u32,
+        mode: u32,
+        gid: u32,
+    ) -> Result<()> {
+        match self
+            .rpc(Fcall::Tlcreate {
+                fid: fid.fid,
+                name: name.to_owned(),
+                flags,
+                mode,
+                gid,
+            })
+            .await?
+        {
+            Fcall::Rlcreate { qid, iounit } => {
+                fid.qid = qid;
+                fid.iounit = iounit;
+                Ok(())
+            }
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Creates directory `name` in directory `dfid`
+    pub async fn mkdir(&self, dfid: &ClientFid, name: &str, mode: u32, gid: u32) -> Result<Qid> {
+        match self
+            .rpc(Fcall::Tmkdir {
+                dfid: dfid.fid,
+                name: name.to_owned(),
+                mode,
+                gid,
+            })
+            .await?
+        {
+            Fcall::Rmkdir { qid } => Ok(qid),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Creates symlink `name` in directory `dfid` pointing to `target`
+    pub async fn symlink(
+        &self,
+        dfid: &ClientFid,
+        name: &str,
+        target: &str,
+        gid: u32,
+    ) -> Result<Qid> {
+        match self
+            .rpc(Fcall::Tsymlink {
+                fid: dfid.fid,
+                name: name.to_owned(),
+                symtgt: target.to_owned(),
+                gid,
+            })
+            .await?
+        {
+            Fcall::Rsymlink { qid } => Ok(qid),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Target of symlink `fid`
+    pub async fn readlink(&self, fid: &ClientFid) -> Result<String> {
+        match self.rpc(Fcall::Treadlink { fid: fid.fid }).await? {
+            Fcall::Rreadlink { target } => Ok(target),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Value of extended attribute `name` of `fid`, or the list of names if `name` is empty
+    pub async fn getxattr(&self, fid: &ClientFid, name: &str) -> Result<Vec<u8>> {
+        let newfid = self.alloc_fid();
+        let size = match self
+            .rpc(Fcall::Txattrwalk {
+                fid: fid.fid,
+                newfid,
+                name: name.to_owned(),
+            })
+            .await
+        {
+            Ok(Fcall::Rxattrwalk { size }) => size,
+            Ok(response) => {
+                self.release_fid(newfid);
+                return Err(unexpected(response));
+            }
+            Err(e) => {
+                self.release_fid(newfid);
+                return Err(e);
+            }
+        };
+
+        let xattr = ClientFid {
+            fid: newfid,
+            qid: fid.qid,
+            iounit: 0,
+        };
+        let value = self.read(&xattr, 0, size as u32).await;
+        self.clunk(xattr).await?;
+        value
+    }
+
+    /// Sets extended attribute `name` of `fid`, empty `value` removes it
+    ///
+    /// `flags` are `XATTR_CREATE` or `XATTR_REPLACE`.
+    pub async fn setxattr(
+        &self,
+        fid: &ClientFid,
+        name: &str,
+        value: &[u8],
+        flags: u32,
+    ) -> Result<()> {
+        let xattr = self.walk(fid, &[]).await?;
+        let created = self
+            .rpc(Fcall::Txattrcreate {
+                fid: xattr.fid,
+                name: name.to_owned(),
+                attr_size: value.len() as u64,
+                flags,
+            })
+            .await;
+        let written = match created {
+            Ok(Fcall::Rxattrcreate) => self.write(&xattr, 0, value).await.map(|_| ()),
+            Ok(response) => Err(unexpected(response)),
+            Err(e) => Err(e),
+        };
+
+        // Value is set when the fid is clunked
+        let committed = self.clunk(xattr).await;
+        written.and(committed)
+    }
+
+    /// Figures of the filesystem holding `fid`
+    pub async fn statfs(&self, fid: &ClientFid) -> Result<Statfs> {
+        match self.rpc(Fcall::Tstatfs { fid: fid.fid }).await? {
+            Fcall::Rstatfs { statfs } => Ok(statfs),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Creates special file `name` in directory `dfid`, type is given by `mode`
+    pub async fn mknod(
+        &self,
+        dfid: &ClientFid,
+        name: &str,
+        mode: u32,
+        major: u32,
+        minor: u32,
+        gid: u32,
+    ) -> Result<Qid> {
+        match self
+            .rpc(Fcall::Tmknod {
+                dfid: dfid.fid,
+                name: name.to_owned(),
+                mode,
+                major,
+                minor,
+                gid,
+            })
+            .await?
+        {
+            Fcall::Rmknod { qid } => Ok(qid),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Creates hard link `name` in directory `dfid` to file `fid`
+    pub async fn link(&self, dfid: &ClientFid, fid: &ClientFid, name: &str) -> Result<()> {
+        match self
+            .rpc(Fcall::Tlink {
+                dfid: dfid.fid,
+                fid: fid.fid,
+                name: name.to_owned(),
+            })
+            .await?
+        {
+            Fcall::Rlink => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Takes or releases a byte-range lock on open file `fid`
+    ///
+    /// A lock held by someone else gives `LockStatus::BLOCKED`, blocking requests are
+    /// for the caller to retry.
+    pub async fn lock(&self, fid: &ClientFid, flock: Flock) -> Result<LockStatus> {
+        match self
+            .rpc(Fcall::Tlock {
+                fid: fid.fid,
+                flock,
+            })
+            .await?
+        {
+            Fcall::Rlock { status } => Ok(status),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Lock which would prevent `flock` from being taken, `LockType::UNLOCK` if there is none
+    pub async fn getlock(&self, fid: &ClientFid, flock: Getlock) -> Result<Getlock> {
+        match self
+            .rpc(Fcall::Tgetlock {
+                fid: fid.fid,
+                flock,
+            })
+            .await?
+        {
+            Fcall::Rgetlock { flock } => Ok(flock),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Reads up to `count` bytes at `offset`, fewer only at the end of file
+    pub async fn read(&self, fid: &ClientFid, offset: u64, count: u32) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        while (data.len() as u32) < count {
+            let chunk = (count - data.len() as u32).min(self.iounit(fid));
+            let read = match self
+                .rpc(Fcall::Tread {
+                    fid: fid.fid,
+                    offset: offset + data.len() as u64,
+                    count: chunk,
+                })
+                .await?
+            {
+                Fcall::Rread { data } => data.0,
+                response => return Err(unexpected(response)),
+            };
+
+            if read.is_empty() {
+                break;
+            }
+            data.extend_from_slice(&read);
+        }
+        Ok(data)
+    }
+
+    /// Reads at most `count` bytes at `offset` with a single request, capped by iounit
+    ///
+    /// The buffer is the one sent by the server, nothing is copied over message channels.
+    pub async fn read_bytes(&self, fid: &ClientFid, offset: u64, count: u32) -> Result<Bytes> {
+        match self
+            .rpc(Fcall::Tread {
+                fid: fid.fid,
+                offset,
+                count: count.min(self.iounit(fid)),
+            })
+            .await?
+        {
+            Fcall::Rread { data } => Ok(data.0),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Writes `data` at `offset`, returns number of bytes written
+    pub async fn write(&self, fid: &ClientFid, offset: u64, data: &[u8]) -> Result<u32> {
+        self.write_bytes(fid, offset, Bytes::copy_from_slice(data))
+            .await
+    }
+
+    /// Writes `data` at `offset` sharing the buffer with requests instead of copying it
+    pub async fn write_bytes(&self, fid: &ClientFid, offset: u64, data: Bytes) -> Result<u32> {
+        let mut written = 0;
+        while written < data.len() {
+            let chunk = (data.len() - written).min(self.iounit(fid) as usize);
+            let count = match self
+                .rpc(Fcall::Twrite {
+                    fid: fid.fid,
+                    offset: offset + written as u64,
+                    data: Data(data.slice(written..written + chunk)),
+                })
+                .await?
+            {
+                Fcall::Rwrite { count } => count,
+                response => return Err(unexpected(response)),
+            };
+
+            if count == 0 {
+                break;
+            }
+            written += count as usize;
+        }
+        Ok(written as u32)
+    }
+
+    /// Lists all entries of the opened directory `fid`
+    pub async fn readdir(&self, fid: &ClientFid) -> Result<Vec<DirEntry>> {
+        let mut entries = Vec::new();
+        let mut offset = 0;
+        loop {
+            let data = match self
+                .rpc(Fcall::Treaddir {
+                    fid: fid.fid,
+                    offset,
+                    count: self.iounit(fid),
+                })
+                .await?
+            {
+                Fcall::Rreaddir { data } => data.data,
+                response => return Err(unexpected(response)),
+            };
+
+            match data.last() {
+                Some(last) => offset = last.offset,
+                None => break,
+            }
+            entries.extend(data);
+        }
+        Ok(entries)
+    }
+
+    /// Gets attributes selected by `req_mask` of the file `fid` points to
+    pub async fn getattr(&self, fid: &ClientFid, req_mask: GetattrMask) -> Result<Stat> {
+        match self
+            .rpc(Fcall::Tgetattr {
+                fid: fid.fid,
+                req_mask,
+            })
+            .await?
+        {
+            Fcall::Rgetattr { stat, .. } => Ok(stat),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Sets attributes selected by `valid` of the file `fid` points to
+    pub async fn setattr(&self, fid: &ClientFid, valid: SetattrMask, stat: SetAttr) -> Result<()> {
+        match self
+            .rpc(Fcall::Tsetattr {
+                fid: fid.fid,
+                valid,
+                stat,
+            })
+            .await?
+        {
+            Fcall::Rsetattr => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Renames `oldname` in directory `olddir` to `newname` in directory `newdir`
+    pub async fn renameat(
+        &self,
+        olddir: &ClientFid,
+        oldname: &str,
+        newdir: &ClientFid,
+        newname: &str,
+    ) -> Result<()> {
+        match self
+            .rpc(Fcall::Trenameat {
+                olddirfid: olddir.fid,
+                oldname: oldname.to_owned(),
+                newdirfid: newdir.fid,
+                newname: newname.to_owned(),
+            })
+            .await?
+        {
+            Fcall::Rrenameat => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Removes `name` from directory `dfid`, a directory only with `AT_REMOVEDIR` in `flags`
+    pub async fn unlinkat(&self, dfid: &ClientFid, name: &str, flags: u32) -> Result<()> {
+        match self
+            .rpc(Fcall::Tunlinkat {
+                dirfd: dfid.fid,
+                name: name.to_owned(),
+                flags,
+            })
+            .await?
+        {
+            Fcall::Runlinkat => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+
+    /// Releases `fid`, the fid is gone even if the server reports an error
+    pub async fn clunk(&self, fid: ClientFid) -> Result<()> {
+        let res = self.rpc(Fcall::Tclunk { fid: fid.fid }).await;
+        self.release_fid(fid.fid);
+
+        match res? {
+            Fcall::Rclunk => Ok(()),
+            response => Err(unexpected(response)),
+        }
+    }
+}
@@ -0,0 +1,154 @@
+//! Tokio codec framing 9P messages.
+//!
+//! Every message starts with its size as 4-byte little-endian integer,
+//! which includes the size field itself.
+
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Arc;
+
+use byteorder::{ByteOrder, LittleEndian};
+use bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+use super::error;
+use super::fcall::{Fcall, Msg, MsgType};
+use super::lib_utils::Result;
+use super::serialize;
+
+/// Size of the size, type and tag fields every message starts with
+const HEADER_SIZE: u32 = 4 + 1 + 2;
+
+/// Codec decoding and encoding 9P messages
+///
+/// Messages larger than msize are refused as soon as their size is read.
+/// Clones share msize, so decoding and encoding halves of a connection
+/// follow the same `set_msize` after Tversion.
+#[derive(Clone, Debug)]
+pub struct P9Codec {
+    msize: Arc<AtomicU32>,
+}
+
+impl P9Codec {
+    /// Codec accepting messages up to `msize` bytes
+    pub fn new(msize: u32) -> Self {
+        Self {
+            msize: Arc::new(AtomicU32::new(msize)),
+        }
+    }
+
+    /// Largest message accepted
+    pub fn msize(&self) -> u32 {
+        self.msize.load(Ordering::SeqCst)
+    }
+
+    /// Changes the largest message accepted, e.g. once Tversion agreed on msize
+    pub fn set_msize(&self, msize: u32) {
+        self.msize.store(msize, Ordering::SeqCst);
+    }
+}
+
+impl Decoder for P9Codec {
+    type Item = Msg;
+    type Error = error::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Msg>> {
+        if src.len() < 4 {
+            return Ok(None);
+        }
+
+        let size = LittleEndian::read_u32(&src[..4]);
+        if size < HEADER_SIZE {
+            return res!(io_err!(
+                InvalidData,
+                format!("Message size {} is too small", size)
+            ));
+        }
+        if size > self.msize() {
+            return res!(io_err!(
+                InvalidData,
+                format!("Message of {} bytes exceeds msize {}", size, self.msize())
+            ));
+        }
+
+        let size = size as usize;
+        if src.len() < size {
+            src.reserve(size - src.len());
+            return Ok(None);
+        }
+
+        let frame = src.split_to(size);
+        Ok(Some(serialize::read_msg(&mut &frame[4..])?))
+    }
+}
+
+impl Encoder<Msg> for P9Codec {
+    type Error = error::Error;
+
+    fn encode(&mut self, msg: Msg, dst: &mut BytesMut) -> Result<()> {
+        encode_msg(&msg, dst)
+    }
+}
+
+/// Appends `msg` with its size prefix to `dst`
+///
+/// Response which cannot be encoded is replaced with Rlerror,
+/// so the client still gets an answer for its tag.
+pub(crate) fn encode_msg(msg: &Msg, dst: &mut BytesMut) -> Result<()> {
+    let start = dst.len();
+    dst.put_u32_le(0);
+
+    if let Err(e) = serialize::write_msg(&mut (&mut *dst).writer(), msg) {
+        dst.truncate(start);
+        let e = error::Error::from(e);
+        if !MsgType::from(&msg.body).is_r() {
+            return Err(e);
+        }
+
+        log::error!("Failed encoding response for tag {}: {}", msg.tag, e);
+        let lerror = Msg {
+            tag: msg.tag,
+            body: Fcall::Rlerror {
+                ecode: e.errno() as u32,
+            },
+        };
+        return encode_msg(&lerror, dst);
+    }
+
+    let size = (dst.len() - start) as u32;
+    LittleEndian::write_u32(&mut dst[start..start + 4], size);
+    Ok(())
+}
+
+#[test]
+fn codec_encode_decode() {
+    let expected = Msg {
+        tag: 1,
+        body: Fcall::Tversion {
+            msize: 8192,
+            version: "9P2000.L".to_owned(),
+        },
+    };
+    let mut codec = P9Codec::new(8192);
+    let mut buf = BytesMut::new();
+    codec.encode(expected.clone(), &mut buf).unwrap();
+    assert_eq!(LittleEndian::read_u32(&buf[..4]) as usize, buf.len());
+
+    // Partial message waits for the rest
+    let mut partial = buf.split_to(buf.len() - 1);
+    assert!(codec.decode(&mut partial).unwrap().is_none());
+    partial.unsplit(buf);
+    assert_eq!(codec.decode(&mut partial).unwrap(), Some(expected));
+    assert!(partial.is_empty());
+}
+
+#[test]
+fn codec_refuses_messages_over_msize() {
+    let mut codec = P9Codec::new(8192);
+    let mut buf = BytesMut::new();
+    buf.put_u32_le(8193);
+    assert!(codec.decode(&mut buf).is_err());
+
+    let mut buf = BytesMut::new();
+    buf.put_u32_le(2);
+    assert!(codec.decode(&mut buf).is_err());
+}
@@ -850,7 +850,16 @@ fn errno_from_io_error(e: &io::Error) -> errno::Errno {
         AddrInUse => EADDRINUSE,
         AddrNotAvailable => EADDRNOTAVAIL,
         BrokenPipe => EPIPE,
-        AlreadyExists => EALREADY,
+        // Not EALREADY, which clients take for an operation in progress and so
+        // fail mkdir -p and O_EXCL creates of existing files with the wrong error
+        AlreadyExists => EEXIST,
+        NotADirectory => ENOTDIR,
+        IsADirectory => EISDIR,
+        DirectoryNotEmpty => ENOTEMPTY,
+        ReadOnlyFilesystem => EROFS,
+        StorageFull => ENOSPC,
+        CrossesDevices => EXDEV,
+        InvalidFilename => ENAMETOOLONG,
         WouldBlock => EAGAIN,
         InvalidInput => EINVAL,
         InvalidData => EINVAL,
@@ -6,6 +6,7 @@
 use std::mem::{size_of, size_of_val};
 
 use bitflags::bitflags;
+use bytes::Bytes;
 use enum_primitive::*;
 
 /// 9P2000 version string
@@ -41,6 +42,28 @@ pub const IOHDRSZ: u32 = 24;
 /// Room for readdir header
 pub const READDIRHDRSZ: u32 = 24;
 
+/// Flag of `Tunlinkat` removing a directory rather than a file
+pub const AT_REMOVEDIR: u32 = 0x200;
+
+/// Flag of `Txattrcreate` failing if the attribute already exists
+pub const XATTR_CREATE: u32 = 0x1;
+/// Flag of `Txattrcreate` failing if the attribute does not exist yet
+pub const XATTR_REPLACE: u32 = 0x2;
+/// Largest extended attribute value
+pub const XATTR_SIZE_MAX: u64 = 65536;
+
+/// File type bits of `Stat.mode`
+pub const S_IFMT: u32 = 0o170000;
+pub const S_IFSOCK: u32 = 0o140000;
+pub const S_IFREG: u32 = 0o100000;
+pub const S_IFBLK: u32 = 0o060000;
+pub const S_IFDIR: u32 = 0o040000;
+pub const S_IFCHR: u32 = 0o020000;
+pub const S_IFIFO: u32 = 0o010000;
+
+/// Filesystem type reported in `Rstatfs`, `V9FS_MAGIC` of Linux
+pub const V9FS_MAGIC: u32 = 0x01021997;
+
 /// v9fs default port
 pub const V9FS_PORT: u16 = 564;
 
@@ -334,22 +357,26 @@ pub struct Statfs {
     /// Maximum length of filenames
     pub namelen: u32,
 }
-/*
+
+#[cfg(target_os = "linux")]
 impl From<nix::sys::statvfs::Statvfs> for Statfs {
     fn from(buf: nix::sys::statvfs::Statvfs) -> Statfs {
         Statfs {
-            typ: 0,
-            bsize: buf.block_size() as u32,
+            typ: V9FS_MAGIC,
+            // Block counts are in units of the fragment size
+            bsize: buf.fragment_size() as u32,
             blocks: buf.blocks(),
             bfree: buf.blocks_free(),
             bavail: buf.blocks_available(),
             files: buf.files(),
             ffree: buf.files_free(),
+            // c_ulong is narrower on 32-bit targets
+            #[allow(clippy::unnecessary_cast)]
             fsid: buf.filesystem_id() as u64,
             namelen: buf.name_max() as u32,
         }
     }
-}*/
+}
 
 /// Time struct
 ///
@@ -460,8 +487,12 @@ pub struct DirEntry {
 }
 
 impl DirEntry {
+    /// Size of the entry on the wire
     pub fn size(&self) -> u32 {
-        (size_of_val(&self.qid)
+        // Qid is packed on the wire, unlike in memory
+        (size_of::<u8>()
+            + size_of::<u32>()
+            + size_of::<u64>()
             + size_of_val(&self.offset)
             + size_of_val(&self.typ)
             + size_of::<u16>()
@@ -495,10 +526,13 @@ impl DirEntryData {
 
 /// Data type used in `Rread` and `Twrite`
 ///
+/// Payload is a shared buffer, so messages passed between tasks in one
+/// process and their clones do not copy it.
+///
 /// # Protocol
 /// 9P2000/9P2000.L
 #[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct Data(pub Vec<u8>);
+pub struct Data(pub Bytes);
 
 /// Similar to Linux `struct flock`
 ///
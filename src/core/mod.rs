@@ -14,7 +14,15 @@
 #[macro_use]
 pub mod lib_utils;
 pub mod attributes_cache;
+pub mod client;
+pub mod codec;
 pub mod error;
 pub mod fcall;
 pub mod serialize;
 pub mod srv;
+#[cfg(feature = "tls")]
+pub mod tls;
+#[cfg(target_os = "linux")]
+pub mod vhost_user;
+#[cfg(target_os = "linux")]
+pub mod vsock;
@@ -631,10 +631,13 @@ impl Decodable for DirEntry {
 
 impl Decodable for DirEntryData {
     fn decode<R: ReadBytesExt>(r: &mut R) -> Result<Self> {
+        // Count is the size of the entries in bytes, not the number of entries
         let count: u32 = Decodable::decode(r)?;
-        let mut data: Vec<DirEntry> = Vec::with_capacity(count as usize);
-        for _ in 0..count {
-            data.push(Decodable::decode(r)?);
+        let buf = read_exact(r, count as usize)?;
+        let mut entries = &buf[..];
+        let mut data: Vec<DirEntry> = Vec::new();
+        while !entries.is_empty() {
+            data.push(Decodable::decode(&mut entries)?);
         }
         Ok(DirEntryData::with(data))
     }
@@ -643,7 +646,7 @@ impl Decodable for DirEntryData {
 impl Decodable for Data {
     fn decode<R: ReadBytesExt>(r: &mut R) -> Result<Self> {
         let len: u32 = Decodable::decode(r)?;
-        Ok(Data(read_exact(r, len as usize)?))
+        Ok(Data(read_exact(r, len as usize)?.into()))
     }
 }
 
@@ -951,3 +954,28 @@ fn msg_encode_decode1() {
 
     assert_eq!(expected, actual.unwrap());
 }
+
+#[test]
+fn msg_encode_decode_readdir() {
+    use std::io::Cursor;
+
+    let entry = |offset: u64, name: &str| DirEntry {
+        qid: Qid::default(),
+        offset,
+        typ: 0,
+        name: name.to_owned(),
+    };
+    let expected = Msg {
+        tag: 1,
+        body: Fcall::Rreaddir {
+            data: DirEntryData::with(vec![entry(0, "."), entry(1, ".."), entry(2, "file")]),
+        },
+    };
+    let mut buf = Vec::new();
+    let _ = expected.encode(&mut buf);
+
+    let mut readbuf = Cursor::new(buf);
+    let actual = Decodable::decode(&mut readbuf);
+
+    assert_eq!(expected, actual.unwrap());
+}
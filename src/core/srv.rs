@@ -4,20 +4,33 @@
 //! 9P2000.L
 
 use tokio::io::DuplexStream;
+#[cfg(unix)]
+use {
+    std::path::{Path, PathBuf},
+    tokio::net::UnixListener,
+};
+
+#[cfg(feature = "tls")]
+use super::tls::{TlsConfig, DEFAULT_TLS_HANDSHAKE_TIMEOUT};
+#[cfg(target_os = "linux")]
+use super::{vhost_user, vsock};
 
 use {
-    super::{error, error::errno::*, fcall::*, lib_utils::Result, serialize},
+    super::{codec::P9Codec, error, error::errno::*, fcall::*, lib_utils::Result},
     async_trait::async_trait,
-    bytes::buf::{Buf, BufMut},
-    futures::sink::SinkExt,
-    std::{collections::HashMap, sync::Arc},
+    futures::{
+        sink::{Sink, SinkExt},
+        Future,
+    },
+    std::{collections::HashMap, sync::Arc, time::Duration},
     tokio::{
         io::{AsyncRead, AsyncWrite},
         net::TcpListener,
-        sync::{Mutex, RwLock},
+        sync::{oneshot, watch, Mutex, OwnedSemaphorePermit, RwLock, Semaphore},
+        task::{AbortHandle, JoinError, JoinHandle, JoinSet},
     },
-    tokio_stream::StreamExt,
-    tokio_util::codec::length_delimited::LengthDelimitedCodec,
+    tokio_stream::{Stream, StreamExt},
+    tokio_util::codec::{FramedRead, FramedWrite},
 };
 
 /// Represents a fid of clients holding associated `Filesystem::Fid`.
@@ -45,7 +58,7 @@ impl<T> Fid<T> {
 /// Otherwise, they must return `Fcall` with the required fields filled.
 ///
 /// The default implementation, returning EOPNOTSUPP error, is provided to the all methods
-/// except Rversion.
+/// except Rversion and Rflush.
 /// The default implementation of Rversion returns a message accepting 9P2000.L.
 ///
 /// # NOTE
@@ -210,8 +223,9 @@ pub trait Filesystem: Send {
     /*
      * 9P2000 subset
      */
+    /// Called after the flushed request was cancelled, `old` is `None` when it already finished.
     async fn rflush(&self, _old: Option<&Fcall>) -> Result<Fcall> {
-        Err(error::Error::No(EOPNOTSUPP))
+        Ok(Fcall::Rflush)
     }
 
     async fn rwalk(
@@ -250,11 +264,36 @@ pub trait Filesystem: Send {
     }
 }
 
+/// Whether serving the request could change the export
+fn modifies_export(body: &Fcall) -> bool {
+    use super::fcall::Fcall::*;
+    match body {
+        Tlopen { flags, .. } => FileOpenMode::from_bits_truncate(*flags).intersects(
+            FileOpenMode::P9_DOTL_WRONLY | FileOpenMode::P9_DOTL_RDWR | FileOpenMode::P9_DOTL_TRUNC,
+        ),
+        Tlcreate { .. }
+        | Tsymlink { .. }
+        | Tmknod { .. }
+        | Trename { .. }
+        | Tsetattr { .. }
+        | Txattrcreate { .. }
+        | Tlink { .. }
+        | Tmkdir { .. }
+        | Trenameat { .. }
+        | Tunlinkat { .. }
+        | Twrite { .. }
+        | Tremove { .. } => true,
+        _ => false,
+    }
+}
+
 #[rustfmt::skip]
 async fn dispatch_once<Fs, FsFid>(
     msg: &Msg,
     fs: Arc<Fs>,
     fsfids: Arc<RwLock<HashMap<u32, Fid<FsFid>>>>,
+    iounit: u32,
+    read_only: bool,
 ) -> Result<Fcall>
 where
     Fs: Filesystem<Fid = FsFid> + Send + Sync,
@@ -266,7 +305,16 @@ where
     });
 
     use super::fcall::Fcall::*;
-    let response = {
+    if read_only && modifies_export(&msg.body) {
+        return Err(error::Error::No(EROFS));
+    }
+    if let Twrite { ref data, .. } = msg.body {
+        if data.0.len() > iounit as usize {
+            return Err(error::Error::No(EMSGSIZE));
+        }
+    }
+
+    let result = {
         let fids = fsfids.read().await;
         let get_fid = |fid: &u32| fids.get(fid).ok_or(error::Error::No(EBADF));
         let fut = match msg.body {
@@ -281,7 +329,7 @@ where
             Tsetattr { fid, ref valid, ref stat }                               => fs.rsetattr(get_fid(&fid)?, *valid, stat),
             Txattrwalk { fid, newfid: _, ref name }                             => fs.rxattrwalk(get_fid(&fid)?, newfid.as_ref().unwrap(), name),
             Txattrcreate { fid, ref name, ref attr_size, ref flags }            => fs.rxattrcreate(get_fid(&fid)?, name, *attr_size, *flags),
-            Treaddir { fid, ref offset, ref count }                             => fs.rreaddir(get_fid(&fid)?, *offset, *count),
+            Treaddir { fid, ref offset, ref count }                             => fs.rreaddir(get_fid(&fid)?, *offset, (*count).min(iounit)),
             Tfsync { fid }                                                      => fs.rfsync(get_fid(&fid)?),
             Tlock { fid, ref flock }                                            => fs.rlock(get_fid(&fid)?, flock),
             Tgetlock { fid, ref flock }                                         => fs.rgetlock(get_fid(&fid)?, flock),
@@ -292,23 +340,32 @@ where
             Tauth { afid: _, ref uname, ref aname, ref n_uname }                => fs.rauth(newfid.as_ref().unwrap(), uname, aname, *n_uname),
             Tattach { fid: _, afid: _, ref uname, ref aname, ref n_uname }      => fs.rattach(newfid.as_ref().unwrap(), None, uname, aname, *n_uname),
             Tversion { ref msize, ref version }                                 => fs.rversion(*msize, version),
-            Tflush { oldtag: _ }                                                => fs.rflush(None),
             Twalk { fid, newfid: _, ref wnames }                                => fs.rwalk(get_fid(&fid)?, newfid.as_ref().unwrap(), wnames),
-            Tread { fid, ref offset, ref count }                                => fs.rread(get_fid(&fid)?, *offset, *count),
+            Tread { fid, ref offset, ref count }                                => fs.rread(get_fid(&fid)?, *offset, (*count).min(iounit)),
             Twrite { fid, ref offset, ref data }                                => fs.rwrite(get_fid(&fid)?, *offset, data),
             Tclunk { fid }                                                      => fs.rclunk(get_fid(&fid)?),
             Tremove { fid }                                                     => fs.rremove(get_fid(&fid)?),
             _                                                                   => return Err(error::Error::No(EOPNOTSUPP)),
         };
 
-        fut.await?
+        fut.await
     };
-    /* Drop the fid which the Tclunk contains */
+
+    /* Drop the fid which the Tclunk contains, it is clunked even if that fails */
     if let Tclunk { fid } = msg.body {
         let mut fids = fsfids.write().await;
         fids.remove(&fid);
     }
 
+    let mut response = result?;
+
+    /* Clients may not transfer more than fits in a message at once */
+    if let Rlopen { iounit: ref mut unit, .. } | Rlcreate { iounit: ref mut unit, .. } = response {
+        if *unit == 0 || *unit > iounit {
+            *unit = iounit;
+        }
+    }
+
     if let Some(newfid) = newfid {
         let mut fids = fsfids.write().await;
         fids.insert(newfid.fid, newfid);
@@ -317,76 +374,508 @@ where
     Ok(response)
 }
 
-async fn dispatch<Fs, Reader, Writer>(filesystem: Fs, reader: Reader, writer: Writer) -> Result<()>
+/// Largest msize agreed to when the server is not configured otherwise
+pub const DEFAULT_MAX_MSIZE: u32 = 1024 * 1024;
+
+/// Smallest msize limits are enforced with, the least Linux clients work with
+pub const MIN_MSIZE: u32 = 4096;
+
+/// Requests served at once on one connection when the server is not configured otherwise
+pub const DEFAULT_MAX_REQUESTS_PER_CONNECTION: usize = 64;
+
+/// Limits applied to every connection of a server
+///
+/// Clones share the server-wide request slots, so all connections of a server
+/// have to be given clones of the same `Limits`.
+#[derive(Clone, Debug)]
+pub(crate) struct Limits {
+    /// Largest msize agreed to in Tversion, larger frames are never accepted
+    pub(crate) max_msize: u32,
+    /// Requests served at once on one connection, the connection is not read while at the limit
+    requests_per_connection: usize,
+    /// Request slots shared by all connections, unlimited if not set
+    requests: Option<Arc<Semaphore>>,
+    /// Connections served at once, further clients wait in the listen backlog
+    connections: usize,
+    /// Requests modifying the export are refused
+    read_only: bool,
+}
+
+impl Limits {
+    pub(crate) fn new(config: &ServerConfig) -> Self {
+        Self {
+            max_msize: config.max_msize.unwrap_or(DEFAULT_MAX_MSIZE).max(MIN_MSIZE),
+            requests_per_connection: config
+                .max_requests_per_connection
+                .unwrap_or(DEFAULT_MAX_REQUESTS_PER_CONNECTION)
+                .max(1),
+            requests: config
+                .max_requests
+                .map(|max| Arc::new(Semaphore::new(max.max(1)))),
+            connections: config.max_connections.unwrap_or(usize::MAX).max(1),
+            read_only: config.read_only,
+        }
+    }
+
+    /// Waits for a server-wide request slot, which is released when the permit is dropped
+    async fn acquire_request(&self) -> Option<OwnedSemaphorePermit> {
+        match &self.requests {
+            Some(requests) => requests.clone().acquire_owned().await.ok(),
+            None => None,
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self::new(&ServerConfig::default())
+    }
+}
+
+/// Message size of a session, lowered by Tversion negotiation
+#[derive(Clone, Debug)]
+pub(crate) struct Msize {
+    max: u32,
+    codec: P9Codec,
+}
+
+impl Msize {
+    pub(crate) fn new(max: u32) -> Self {
+        Self {
+            max,
+            codec: P9Codec::new(max),
+        }
+    }
+
+    /// Largest message accepted, the msize agreed in Rversion
+    pub(crate) fn get(&self) -> u32 {
+        self.codec.msize()
+    }
+
+    /// Codec of the session, refusing messages above `get`
+    fn codec(&self) -> P9Codec {
+        self.codec.clone()
+    }
+
+    /// Agrees on msize proposed by the client or the filesystem, never above the maximum
+    ///
+    /// Sizes below `MIN_MSIZE` are refused with `EINVAL` and leave the session as it was.
+    fn negotiate(&self, proposed: u32) -> Result<u32> {
+        if proposed < MIN_MSIZE {
+            return Err(error::Error::No(EINVAL));
+        }
+        let msize = proposed.min(self.max);
+        self.codec.set_msize(msize);
+        Ok(msize)
+    }
+
+    /// Largest data payload of Tread, Twrite and Treaddir
+    fn iounit(&self) -> u32 {
+        self.get().saturating_sub(IOHDRSZ)
+    }
+}
+
+/// Tells the server and its connections that shutdown was requested
+#[derive(Clone)]
+pub(crate) struct Shutdown(watch::Receiver<Option<Duration>>);
+
+impl Shutdown {
+    pub(crate) fn channel() -> (watch::Sender<Option<Duration>>, Shutdown) {
+        let (sender, receiver) = watch::channel(None);
+        (sender, Shutdown(receiver))
+    }
+
+    /// Signal which never fires, for servers running until the process exits
+    pub(crate) fn never() -> Shutdown {
+        Self::channel().1
+    }
+
+    /// Waits for shutdown, returns the time in-flight requests have to finish
+    pub(crate) async fn requested(&mut self) -> Duration {
+        loop {
+            if let Some(timeout) = *self.0.borrow() {
+                return timeout;
+            }
+            if self.0.changed().await.is_err() {
+                // Nobody can request the shutdown anymore
+                futures::future::pending::<()>().await;
+            }
+        }
+    }
+}
+
+/// Connections served by a listener, together with results of the finished ones
+pub(crate) struct Connections {
+    shutdown: Shutdown,
+    tasks: JoinSet<Result<()>>,
+    results: Vec<Result<()>>,
+}
+
+impl Connections {
+    pub(crate) fn new(shutdown: Shutdown) -> Self {
+        Self {
+            shutdown,
+            tasks: JoinSet::new(),
+            results: Vec::new(),
+        }
+    }
+
+    /// Serves connection in a separate task, `serve` gets the shutdown signal
+    pub(crate) fn spawn<F, Fut>(&mut self, serve: F)
+    where
+        F: FnOnce(Shutdown) -> Fut,
+        Fut: 'static + Future<Output = Result<()>> + Send,
+    {
+        self.reap_finished();
+
+        let connection = serve(self.shutdown.clone());
+        self.tasks.spawn(async move {
+            let res = connection.await;
+            if let Err(e) = &res {
+                log::error!("Error: {}: {:?}", e, e);
+            }
+            res
+        });
+    }
+
+    fn push(&mut self, joined: std::result::Result<Result<()>, JoinError>) {
+        self.results
+            .push(joined.unwrap_or_else(|e| res!(io_err!(Other, e))));
+    }
+
+    /// Collects result of connection which ended while serving, only failures are kept
+    fn ended(&mut self, joined: std::result::Result<Result<()>, JoinError>) {
+        match joined {
+            Ok(Ok(())) => {}
+            joined => self.push(joined),
+        }
+    }
+
+    /// Whether another connection can be accepted without exceeding `limits`
+    fn has_room(&mut self, limits: &Limits) -> bool {
+        self.reap_finished();
+        self.tasks.len() < limits.connections
+    }
+
+    /// Collects results of connections which already ended, never waits
+    fn reap_finished(&mut self) {
+        while let Some(joined) = self.tasks.try_join_next() {
+            self.ended(joined);
+        }
+    }
+
+    /// Collects result of the next finished connection, pending while there are none
+    async fn reap(&mut self) {
+        match self.tasks.join_next().await {
+            Some(joined) => self.ended(joined),
+            None => futures::future::pending().await,
+        }
+    }
+
+    /// Waits for all connections, returns their results in order of completion
+    ///
+    /// Connections which ended before are reported only if they failed.
+    pub(crate) async fn finish(mut self) -> Vec<Result<()>> {
+        while let Some(joined) = self.tasks.join_next().await {
+            self.push(joined);
+        }
+        std::mem::take(&mut self.results)
+    }
+}
+
+impl Drop for Connections {
+    fn drop(&mut self) {
+        // Connections outlive the listener unless shut down explicitly
+        self.tasks.detach_all();
+    }
+}
+
+/// Server running in the background, see `srv_spawn`
+pub struct ServerHandle {
+    shutdown: watch::Sender<Option<Duration>>,
+    server: JoinHandle<Result<Vec<Result<()>>>>,
+}
+
+impl ServerHandle {
+    /// Stops accepting new connections and waits for the served ones to close.
+    ///
+    /// In-flight requests get `timeout` to finish, after that all fids are clunked
+    /// and connections are closed. Returns result of every connection still served
+    /// and of those which failed before, or error which stopped the server before.
+    pub async fn shutdown(self, timeout: Duration) -> Result<Vec<Result<()>>> {
+        let _ = self.shutdown.send(Some(timeout));
+        self.server
+            .await
+            .unwrap_or_else(|e| res!(io_err!(Other, e)))
+    }
+}
+
+/// Clunks every fid left in `fsfids` through the filesystem
+async fn clunk_all<Fs>(filesystem: &Fs, fsfids: &RwLock<HashMap<u32, Fid<Fs::Fid>>>)
+where
+    Fs: Filesystem + Send + Sync,
+{
+    let fids: Vec<_> = fsfids.write().await.drain().map(|(_, fid)| fid).collect();
+    for fid in fids {
+        if let Err(e) = filesystem.rclunk(&fid).await {
+            log::debug!("Failed to clunk fid {}: {}", fid.fid, e);
+        }
+    }
+}
+
+/// Request being served, by its tag
+struct InflightRequest {
+    msg: Arc<Msg>,
+    abort: AbortHandle,
+    /// Resolves once the task serving the request is gone
+    done: oneshot::Receiver<()>,
+}
+
+type InflightTags = Arc<std::sync::Mutex<HashMap<u16, InflightRequest>>>;
+
+/// Cancels request with `oldtag`, its response is never sent after this returns
+async fn flush<Fs>(oldtag: u16, filesystem: &Fs, tags: &InflightTags) -> Result<Fcall>
+where
+    Fs: Filesystem + Send + Sync,
+{
+    let old = tags.lock().unwrap().remove(&oldtag);
+    let old = match old {
+        Some(InflightRequest { msg, abort, done }) => {
+            abort.abort();
+            let _ = done.await;
+            Some(msg)
+        }
+        None => None,
+    };
+
+    filesystem.rflush(old.as_ref().map(|msg| &msg.body)).await
+}
+
+/// Waits for all requests in flight, fails with the first response that could not be sent
+async fn join_requests(inflight: &mut JoinSet<Result<()>>) -> Result<()> {
+    let mut res = Ok(());
+    while let Some(joined) = inflight.join_next().await {
+        if let (Ok(Err(e)), Ok(())) = (joined, &res) {
+            res = Err(e);
+        }
+    }
+    res
+}
+
+pub(crate) async fn dispatch<Fs, Reader, Writer>(
+    filesystem: Fs,
+    reader: Reader,
+    writer: Writer,
+    limits: &Limits,
+    shutdown: Shutdown,
+) -> Result<()>
 where
     Fs: 'static + Filesystem + Send + Sync,
     Reader: 'static + AsyncRead + Send + std::marker::Unpin,
     Writer: 'static + AsyncWrite + Send + std::marker::Unpin,
+{
+    let msize = Msize::new(limits.max_msize);
+
+    let requests = FramedRead::new(reader, msize.codec());
+    let responses = FramedWrite::new(writer, msize.codec());
+
+    dispatch_msgs(
+        filesystem,
+        requests,
+        responses,
+        msize,
+        limits.clone(),
+        shutdown,
+    )
+    .await
+}
+
+/// Serves 9p messages which were already framed and parsed by the transport
+///
+/// Every response is sent to `responses` with the tag of its request.
+/// On shutdown no more requests are read and in-flight ones get the requested time
+/// to finish. Fids left by the client are clunked once the session ends.
+pub(crate) async fn dispatch_msgs<Fs, Requests, Responses>(
+    filesystem: Fs,
+    mut requests: Requests,
+    responses: Responses,
+    msize: Msize,
+    limits: Limits,
+    mut shutdown: Shutdown,
+) -> Result<()>
+where
+    Fs: 'static + Filesystem + Send + Sync,
+    Requests: Stream<Item = Result<Msg>> + std::marker::Unpin,
+    Responses: 'static + Sink<Msg, Error = error::Error> + Send + std::marker::Unpin,
 {
     let fsfids = Arc::new(RwLock::new(HashMap::new()));
     let filesystem = Arc::new(filesystem);
+    let responses = Arc::new(Mutex::new(responses));
+    let tags: InflightTags = Default::default();
+
+    // Requests being served, finished ones are reaped while waiting for the next request
+    let mut inflight = JoinSet::new();
 
-    let mut framedread = LengthDelimitedCodec::builder()
-        .length_field_offset(0)
-        .length_field_length(4)
-        .length_adjustment(-4)
-        .little_endian()
-        .new_read(reader);
-    let framedwrite = LengthDelimitedCodec::builder()
-        .length_field_offset(0)
-        .length_field_length(4)
-        .length_adjustment(-4)
-        .little_endian()
-        .new_write(writer);
-    let framedwrite = Arc::new(Mutex::new(framedwrite));
-
-    while let Some(bytes) = framedread.next().await {
-        let bytes = bytes?;
-
-        let msg = serialize::read_msg(&mut bytes.reader())?;
+    let (drain_timeout, res) = loop {
+        // At the limit the client is not read, so it has to wait for responses
+        let msg = tokio::select! {
+            msg = requests.next(), if inflight.len() < limits.requests_per_connection => match msg {
+                Some(Ok(mut msg)) => {
+                    if let Fcall::Tversion { msize: ref mut proposed, .. } = msg.body {
+                        // Refused sizes are passed on as they are, to be refused in Rversion
+                        *proposed = msize.negotiate(*proposed).unwrap_or(*proposed);
+                    }
+                    Arc::new(msg)
+                }
+                Some(Err(e)) => break (None, Err(e)),
+                None => break (None, Ok(())),
+            },
+            Some(joined) = inflight.join_next() => match joined {
+                // Connection is broken, nothing in flight can be answered anymore
+                Ok(Err(e)) => break (Some(Duration::ZERO), Err(e)),
+                _ => continue,
+            },
+            timeout = shutdown.requested() => break (Some(timeout), Ok(())),
+        };
+        let permit = tokio::select! {
+            permit = limits.acquire_request() => permit,
+            timeout = shutdown.requested() => break (Some(timeout), Ok(())),
+        };
 
         #[cfg(feature = "debug-msg")]
         log::debug!("\t← {:?}", msg);
 
+        // Tversion starts a new session, everything in flight is aborted and fids clunked
+        if let Fcall::Tversion { .. } = msg.body {
+            {
+                // No request is halfway through sending its response
+                let _responses_locked = responses.lock().await;
+                tags.lock().unwrap().clear();
+                inflight.abort_all();
+            }
+            let res = join_requests(&mut inflight).await;
+            clunk_all(filesystem.as_ref(), &fsfids).await;
+            if let Err(e) = res {
+                break (None, Err(e));
+            }
+        }
+
+        // Tflush could not tell which of two requests with the same tag to cancel
+        if tags.lock().unwrap().contains_key(&msg.tag) {
+            let responses = responses.clone();
+            inflight.spawn(async move {
+                let _permit = permit;
+                let response = Msg {
+                    tag: msg.tag,
+                    body: Fcall::Rlerror {
+                        ecode: EBUSY as u32,
+                    },
+                };
+                responses.lock().await.send(response).await
+            });
+            continue;
+        }
+
         let fids = fsfids.clone();
         let fs = filesystem.clone();
-        let framedwrite = framedwrite.clone();
+        let responses = responses.clone();
+        let request = msg.clone();
+        let msize = msize.clone();
+        let read_only = limits.read_only;
+        let (done_tx, done) = oneshot::channel::<()>();
 
-        tokio::spawn(async move {
-            let response_fcall = dispatch_once(&msg, fs, fids).await.unwrap_or_else(|e| {
+        // Task looks its tag up only after it is registered
+        let mut tags_locked = tags.lock().unwrap();
+        let task_tags = tags.clone();
+        let abort = inflight.spawn(async move {
+            let _done = done_tx;
+            let _permit = permit;
+            let tags = task_tags;
+
+            let response_fcall = match msg.body {
+                Fcall::Tflush { oldtag } => flush(oldtag, fs.as_ref(), &tags).await,
+                _ => dispatch_once(&msg, fs, fids, msize.iounit(), read_only).await,
+            };
+            let mut response_fcall = response_fcall.unwrap_or_else(|e| {
                 #[cfg(feature = "debug-msg")]
                 log::info!("{:?}: Error: \"{}\": {:?}", MsgType::from(&msg.body), e, e);
                 Fcall::Rlerror {
                     ecode: e.errno() as u32,
                 }
             });
+            if let Fcall::Rversion {
+                msize: ref mut agreed,
+                ..
+            } = response_fcall
+            {
+                match msize.negotiate(*agreed) {
+                    Ok(msize) => *agreed = msize,
+                    Err(e) => {
+                        response_fcall = Fcall::Rlerror {
+                            ecode: e.errno() as u32,
+                        }
+                    }
+                }
+            }
+
+            let mut responses_locked = responses.lock().await;
+            // Under the responses lock, so Rflush of this tag can only follow the response
+            let flushed = tags.lock().unwrap().remove(&msg.tag).is_none();
 
-            if MsgType::from(&response_fcall).is_r() {
+            if !flushed && MsgType::from(&response_fcall).is_r() {
                 let response = Msg {
                     tag: msg.tag,
                     body: response_fcall,
                 };
 
-                let mut writer = bytes::BytesMut::with_capacity(65535).writer();
-                serialize::write_msg(&mut writer, &response).unwrap();
-
-                {
-                    let mut framedwrite_locked = framedwrite.lock().await;
-                    framedwrite_locked
-                        .send(writer.into_inner().freeze())
-                        .await
-                        .unwrap();
-                }
                 #[cfg(feature = "debug-msg")]
                 log::debug!("\t→ {:?}", response);
+
+                responses_locked.send(response).await?;
             }
+            Ok(())
         });
-    }
+        tags_locked.insert(
+            request.tag,
+            InflightRequest {
+                msg: request,
+                abort,
+                done,
+            },
+        );
+    };
 
+    // Reply to everything received before the client went away
+    let drain = join_requests(&mut inflight);
+    let (drained, drain_res) = match drain_timeout {
+        Some(timeout) => match tokio::time::timeout(timeout, drain).await {
+            Ok(drain_res) => (true, drain_res),
+            Err(_) => (false, Ok(())),
+        },
+        None => (true, drain.await),
+    };
+    // Requests still running hold their fids, they have to go first
+    inflight.shutdown().await;
+    // Let the filesystem release whatever the client left open
+    clunk_all(filesystem.as_ref(), &fsfids).await;
+
+    res?;
+    drain_res?;
+    if !drained {
+        return res!(io_err!(
+            TimedOut,
+            "In-flight requests did not finish before shutdown"
+        ));
+    }
     Ok(())
 }
 
-async fn srv_async_tcp<Fs>(filesystem: Fs, addr: &str) -> Result<()>
+async fn srv_async_tcp<Fs>(
+    filesystem: Fs,
+    addr: &str,
+    limits: &Limits,
+    mut shutdown: Shutdown,
+) -> Result<Vec<Result<()>>>
 where
     Fs: 'static + Filesystem + Send + Sync + Clone,
 {
@@ -394,27 +883,441 @@ where
 
     log::info!("Server started, listening on: {}", listener.local_addr()?);
 
+    let mut connections = Connections::new(shutdown.clone());
     loop {
-        let (stream, peer) = listener.accept().await?;
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept(), if connections.has_room(limits) => accepted?,
+            _ = connections.reap() => continue,
+            _ = shutdown.requested() => break,
+        };
         log::info!("accepted: {:?}", peer);
 
         let fs = filesystem.clone();
-        tokio::spawn(async move {
+        let limits = limits.clone();
+        connections.spawn(|shutdown| async move {
             let (readhalf, writehalf) = stream.into_split();
-            let res = dispatch(fs, readhalf, writehalf).await;
-            if let Err(e) = res {
-                log::error!("Error: {}: {:?}", e, e);
-            }
+            dispatch(fs, readhalf, writehalf, &limits, shutdown).await
         });
     }
+
+    Ok(connections.finish().await)
+}
+
+/// Serves TCP connections wrapped in TLS.
+///
+/// Connections failing the handshake (including client certificate verification)
+/// are dropped without affecting the listener.
+#[cfg(feature = "tls")]
+async fn srv_async_tls<Fs>(
+    filesystem: Fs,
+    addr: &str,
+    tls: &TlsConfig,
+    limits: &Limits,
+    mut shutdown: Shutdown,
+) -> Result<Vec<Result<()>>>
+where
+    Fs: 'static + Filesystem + Send + Sync + Clone,
+{
+    let acceptor = tls.acceptor()?;
+    let handshake_timeout = tls
+        .handshake_timeout
+        .unwrap_or(DEFAULT_TLS_HANDSHAKE_TIMEOUT);
+    let listener = TcpListener::bind(addr).await?;
+
+    log::info!(
+        "Server started, listening on: {} (TLS)",
+        listener.local_addr()?
+    );
+
+    let mut connections = Connections::new(shutdown.clone());
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept(), if connections.has_room(limits) => accepted?,
+            _ = connections.reap() => continue,
+            _ = shutdown.requested() => break,
+        };
+        log::info!("accepted: {:?}", peer);
+
+        let fs = filesystem.clone();
+        let acceptor = acceptor.clone();
+        let limits = limits.clone();
+        connections.spawn(|shutdown| async move {
+            // Clients stalling the handshake would hold connection slots forever
+            let handshake = tokio::time::timeout(handshake_timeout, acceptor.accept(stream));
+            let stream = match handshake.await {
+                Ok(accepted) => accepted.map_err(|e| {
+                    io_err!(
+                        ConnectionAborted,
+                        format!("TLS handshake with {:?} failed: {}", peer, e)
+                    )
+                })?,
+                Err(_) => {
+                    return res!(io_err!(
+                        TimedOut,
+                        format!("TLS handshake with {:?} timed out", peer)
+                    ))
+                }
+            };
+
+            let (readhalf, writehalf) = tokio::io::split(stream);
+            dispatch(fs, readhalf, writehalf, &limits, shutdown).await
+        });
+    }
+
+    Ok(connections.finish().await)
+}
+
+/// Removes the socket file when the listener goes away
+#[cfg(unix)]
+struct UnixSocketGuard(PathBuf);
+
+#[cfg(unix)]
+impl Drop for UnixSocketGuard {
+    fn drop(&mut self) {
+        if let Err(e) = std::fs::remove_file(&self.0) {
+            log::warn!("Failed to remove socket {:?}: {}", self.0, e);
+        }
+    }
+}
+
+/// Removes a socket file left behind by a server which is no longer running.
+///
+/// Fails if the path is taken by something else than a socket,
+/// or if there is still a server accepting connections on it.
+#[cfg(unix)]
+fn remove_stale_socket(path: &Path) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return res!(e),
+    };
+
+    if !metadata.file_type().is_socket() {
+        return res!(io_err!(
+            AlreadyExists,
+            format!("{:?} exists and is not a socket", path)
+        ));
+    }
+
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => res!(io_err!(
+            AddrInUse,
+            format!("{:?} is used by a running server", path)
+        )),
+        Err(_) => {
+            log::info!("Removing stale socket {:?}", path);
+            std::fs::remove_file(path)?;
+            Ok(())
+        }
+    }
+}
+
+/// Binds unix socket at `path`, replacing stale socket file and applying `mode`
+///
+/// With `mode` the socket is bound in a directory only the server may enter and moved to
+/// `path` once it has the mode, so it is never reachable with looser permissions.
+#[cfg(unix)]
+fn bind_unix_socket(path: &str, mode: Option<u32>) -> Result<(UnixListener, UnixSocketGuard)> {
+    use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+
+    let path = Path::new(path);
+    remove_stale_socket(path)?;
+
+    let mode = match mode {
+        Some(mode) => mode,
+        None => {
+            let listener = UnixListener::bind(path)?;
+            return Ok((listener, UnixSocketGuard(path.to_path_buf())));
+        }
+    };
+
+    let name = path
+        .file_name()
+        .ok_or_else(|| io_err!(InvalidInput, "Socket path has no file name"))?;
+    let private_dir = path.with_file_name(format!(
+        ".{}.{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+    std::fs::DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)?;
+
+    let private_path = private_dir.join(name);
+    let bound = UnixListener::bind(&private_path).and_then(|listener| {
+        std::fs::set_permissions(&private_path, std::fs::Permissions::from_mode(mode))?;
+        std::fs::rename(&private_path, path)?;
+        Ok(listener)
+    });
+    if bound.is_err() {
+        let _ = std::fs::remove_file(&private_path);
+    }
+    let _ = std::fs::remove_dir(&private_dir);
+
+    Ok((bound?, UnixSocketGuard(path.to_path_buf())))
+}
+
+/// Main loop of 9p server listening on unix domain socket
+///
+/// Stale socket file left at `path` is replaced, and `mode` (if given)
+/// is applied to the socket file before accepting connections.
+#[cfg(unix)]
+async fn srv_async_unix<Fs>(
+    filesystem: Fs,
+    path: &str,
+    mode: Option<u32>,
+    limits: &Limits,
+    mut shutdown: Shutdown,
+) -> Result<Vec<Result<()>>>
+where
+    Fs: 'static + Filesystem + Send + Sync + Clone,
+{
+    let (listener, _guard) = bind_unix_socket(path, mode)?;
+
+    log::info!("Server started, listening on: {:?}", path);
+
+    let mut connections = Connections::new(shutdown.clone());
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept(), if connections.has_room(limits) => accepted?,
+            _ = connections.reap() => continue,
+            _ = shutdown.requested() => break,
+        };
+        log::info!("accepted: {:?}", peer);
+
+        let fs = filesystem.clone();
+        let limits = limits.clone();
+        connections.spawn(|shutdown| async move {
+            let (readhalf, writehalf) = stream.into_split();
+            dispatch(fs, readhalf, writehalf, &limits, shutdown).await
+        });
+    }
+
+    Ok(connections.finish().await)
+}
+
+/// Main loop of 9p server listening on AF_VSOCK socket
+///
+/// `address` has `cid:port` format, use `any` as cid to accept connections
+/// addressed to any CID of the host.
+#[cfg(target_os = "linux")]
+async fn srv_async_vsock<Fs>(
+    filesystem: Fs,
+    address: &str,
+    limits: &Limits,
+    mut shutdown: Shutdown,
+) -> Result<Vec<Result<()>>>
+where
+    Fs: 'static + Filesystem + Send + Sync + Clone,
+{
+    let (cid, port) = vsock::parse_vsock_address(address)?;
+    let listener = vsock::VsockListener::bind(cid, port)?;
+
+    log::info!("Server started, listening on: {}", listener.local_addr()?);
+
+    let mut connections = Connections::new(shutdown.clone());
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept(), if connections.has_room(limits) => accepted?,
+            _ = connections.reap() => continue,
+            _ = shutdown.requested() => break,
+        };
+        log::info!("accepted: {}", peer);
+
+        let fs = filesystem.clone();
+        let limits = limits.clone();
+        connections.spawn(|shutdown| async move {
+            let (readhalf, writehalf) = tokio::io::split(stream);
+            dispatch(fs, readhalf, writehalf, &limits, shutdown).await
+        });
+    }
+
+    Ok(connections.finish().await)
+}
+
+/// Main loop of vhost-user backend serving the export as virtio-9p device
+///
+/// Masters (hypervisors) connecting to the socket at `path` are served one at a time,
+/// so a restarted hypervisor can reconnect to the same socket.
+#[cfg(target_os = "linux")]
+async fn srv_async_vhost_user<Fs>(
+    filesystem: Fs,
+    path: &str,
+    mode: Option<u32>,
+    mount_tag: &str,
+    limits: &Limits,
+    mut shutdown: Shutdown,
+) -> Result<Vec<Result<()>>>
+where
+    Fs: 'static + Filesystem + Send + Sync + Clone,
+{
+    let (listener, _guard) = bind_unix_socket(path, mode)?;
+
+    log::info!(
+        "vhost-user backend started, listening on: {:?}, mount tag: {}",
+        path,
+        mount_tag
+    );
+
+    let mut results = Vec::new();
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            _ = shutdown.requested() => break,
+        };
+        log::info!("vhost-user master connected: {:?}", peer);
+
+        let res = vhost_user::serve_master(
+            filesystem.clone(),
+            stream,
+            mount_tag,
+            limits,
+            shutdown.clone(),
+        )
+        .await;
+        if let Err(e) = &res {
+            log::error!("Error: {}: {:?}", e, e);
+        }
+        results.push(res);
+        log::info!("vhost-user master disconnected");
+    }
+
+    Ok(results)
+}
+
+/// Transport settings for `srv_async_with_config`
+#[derive(Clone, Debug, Default)]
+pub struct ServerConfig {
+    /// Permissions of the socket file created by `unix` protocol, e.g. `0o660`
+    pub socket_mode: Option<u32>,
+    /// Mount tag of the virtio-9p device exposed by `vhost-user` protocol
+    pub mount_tag: Option<String>,
+    /// Largest msize agreed to in Tversion, `DEFAULT_MAX_MSIZE` if not set
+    pub max_msize: Option<u32>,
+    /// Requests served at once on one connection, `DEFAULT_MAX_REQUESTS_PER_CONNECTION` if not set
+    pub max_requests_per_connection: Option<usize>,
+    /// Requests served at once by the whole server, unlimited if not set
+    pub max_requests: Option<usize>,
+    /// Connections served at once, unlimited if not set
+    pub max_connections: Option<usize>,
+    /// Refuses requests which would modify the export with `EROFS`
+    pub read_only: bool,
+    /// Wraps `tcp` connections in TLS when set
+    #[cfg(feature = "tls")]
+    pub tls: Option<TlsConfig>,
 }
 
 pub async fn srv_async<Fs>(filesystem: Fs, protocol: &str, listen_address: &str) -> Result<()>
 where
     Fs: 'static + Filesystem + Send + Sync + Clone,
 {
+    srv_async_with_config(
+        filesystem,
+        protocol,
+        listen_address,
+        &ServerConfig::default(),
+    )
+    .await
+}
+
+pub async fn srv_async_with_config<Fs>(
+    filesystem: Fs,
+    protocol: &str,
+    listen_address: &str,
+    config: &ServerConfig,
+) -> Result<()>
+where
+    Fs: 'static + Filesystem + Send + Sync + Clone,
+{
+    serve(
+        filesystem,
+        protocol,
+        listen_address,
+        config,
+        Shutdown::never(),
+    )
+    .await
+    .map(drop)
+}
+
+/// Starts the server in the background, returns handle to shut it down
+pub fn srv_spawn<Fs>(
+    filesystem: Fs,
+    protocol: &str,
+    listen_address: &str,
+    config: &ServerConfig,
+) -> ServerHandle
+where
+    Fs: 'static + Filesystem + Send + Sync + Clone,
+{
+    let (sender, shutdown) = Shutdown::channel();
+    let protocol = protocol.to_owned();
+    let listen_address = listen_address.to_owned();
+    let config = config.clone();
+
+    ServerHandle {
+        shutdown: sender,
+        server: tokio::spawn(async move {
+            serve(filesystem, &protocol, &listen_address, &config, shutdown).await
+        }),
+    }
+}
+
+async fn serve<Fs>(
+    filesystem: Fs,
+    protocol: &str,
+    listen_address: &str,
+    config: &ServerConfig,
+    shutdown: Shutdown,
+) -> Result<Vec<Result<()>>>
+where
+    Fs: 'static + Filesystem + Send + Sync + Clone,
+{
+    let limits = &Limits::new(config);
     match protocol {
-        "tcp" => srv_async_tcp(filesystem, &listen_address).await,
+        "tcp" => {
+            #[cfg(feature = "tls")]
+            if let Some(tls) = &config.tls {
+                return srv_async_tls(filesystem, listen_address, tls, limits, shutdown).await;
+            }
+            srv_async_tcp(filesystem, listen_address, limits, shutdown).await
+        }
+        #[cfg(unix)]
+        "unix" => {
+            srv_async_unix(
+                filesystem,
+                listen_address,
+                config.socket_mode,
+                limits,
+                shutdown,
+            )
+            .await
+        }
+        "stdio" => {
+            let (stdin, stdout) = (tokio::io::stdin(), tokio::io::stdout());
+            serve_stdio(filesystem, stdin, stdout, limits, shutdown).await?;
+            Ok(vec![Ok(())])
+        }
+        #[cfg(target_os = "linux")]
+        "vsock" => srv_async_vsock(filesystem, listen_address, limits, shutdown).await,
+        #[cfg(target_os = "linux")]
+        "vhost-user" => {
+            let mount_tag = config
+                .mount_tag
+                .as_deref()
+                .unwrap_or(vhost_user::DEFAULT_MOUNT_TAG);
+            srv_async_vhost_user(
+                filesystem,
+                listen_address,
+                config.socket_mode,
+                mount_tag,
+                limits,
+                shutdown,
+            )
+            .await
+        }
         _ => Err(From::from(io_err!(InvalidInput, "Protocol not supported"))),
     }
 }
@@ -426,5 +1329,48 @@ where
 {
     let (server_rx, server_tx) = tokio::io::split(server);
 
-    dispatch(filesystem, server_rx, server_tx).await
+    dispatch(
+        filesystem,
+        server_rx,
+        server_tx,
+        &Limits::default(),
+        Shutdown::never(),
+    )
+    .await
+}
+
+/// Serves single 9p session over stdin/stdout
+///
+/// Returns when the client closes stdin. Nothing else may write to stdout
+/// while the session is running, logs have to go elsewhere.
+pub async fn srv_async_stdio<Fs>(filesystem: Fs) -> Result<()>
+where
+    Fs: 'static + Filesystem + Send + Sync + Clone,
+{
+    serve_stdio(
+        filesystem,
+        tokio::io::stdin(),
+        tokio::io::stdout(),
+        &Limits::default(),
+        Shutdown::never(),
+    )
+    .await
+}
+
+/// Session of `srv_async_stdio` over given standard input and output handles
+///
+/// Replies to requests read before `stdin` is closed are all written before returning.
+pub(crate) async fn serve_stdio<Fs, Stdin, Stdout>(
+    filesystem: Fs,
+    stdin: Stdin,
+    stdout: Stdout,
+    limits: &Limits,
+    shutdown: Shutdown,
+) -> Result<()>
+where
+    Fs: 'static + Filesystem + Send + Sync + Clone,
+    Stdin: 'static + AsyncRead + Send + std::marker::Unpin,
+    Stdout: 'static + AsyncWrite + Send + std::marker::Unpin,
+{
+    dispatch(filesystem, stdin, stdout, limits, shutdown).await
 }
@@ -0,0 +1,98 @@
+//! TLS for the TCP transport.
+//!
+//! Certificates and keys are read from PEM files. When a client CA is given,
+//! only clients presenting a certificate signed by it are accepted.
+
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio_rustls::rustls::server::AllowAnyAuthenticatedClient;
+use tokio_rustls::rustls::{self, Certificate, PrivateKey, RootCertStore};
+use tokio_rustls::TlsAcceptor;
+
+use super::lib_utils::Result;
+
+/// Time clients get to finish the TLS handshake if `TlsConfig` does not set it
+pub const DEFAULT_TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Paths to PEM files used by the TLS transport
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    /// Server certificate chain
+    pub cert_path: PathBuf,
+    /// Private key of the server certificate (PKCS#8, PKCS#1 or SEC1)
+    pub key_path: PathBuf,
+    /// CA certificates used to verify clients, client certificates are not required if not set
+    pub client_ca_path: Option<PathBuf>,
+    /// Time clients get to finish the handshake, `DEFAULT_TLS_HANDSHAKE_TIMEOUT` if not set
+    pub handshake_timeout: Option<Duration>,
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    let file = File::open(path).map_err(|e| {
+        io_err!(
+            NotFound,
+            format!("Failed to open {}: {}", path.display(), e)
+        )
+    })?;
+    Ok(BufReader::new(file))
+}
+
+fn invalid(path: &Path, what: &str) -> std::io::Error {
+    io_err!(
+        InvalidInput,
+        format!("No valid {} found in {}", what, path.display())
+    )
+}
+
+fn load_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut open(path)?)?;
+    if certs.is_empty() {
+        return res!(invalid(path, "certificates"));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_key(path: &Path) -> Result<PrivateKey> {
+    let mut reader = open(path)?;
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => {}
+        }
+    }
+    res!(invalid(path, "private key"))
+}
+
+fn tls_err(e: rustls::Error) -> std::io::Error {
+    io_err!(InvalidInput, format!("Invalid TLS configuration: {}", e))
+}
+
+impl TlsConfig {
+    /// Loads certificates and builds acceptor wrapping accepted TCP streams
+    pub fn acceptor(&self) -> Result<TlsAcceptor> {
+        let builder = rustls::ServerConfig::builder().with_safe_defaults();
+
+        let builder = match &self.client_ca_path {
+            Some(client_ca_path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in load_certs(client_ca_path)? {
+                    roots.add(&cert).map_err(tls_err)?;
+                }
+                builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let config = builder
+            .with_single_cert(load_certs(&self.cert_path)?, load_key(&self.key_path)?)
+            .map_err(tls_err)?;
+
+        Ok(TlsAcceptor::from(Arc::new(config)))
+    }
+}
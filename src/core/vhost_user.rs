@@ -0,0 +1,911 @@
+//! vhost-user backend exposing the export as a virtio-9p device.
+//!
+//! The hypervisor (vhost-user master) shares guest memory and the request virtqueue
+//! over a unix socket. Every descriptor chain carries one 9P request in its
+//! device-readable part and room for the response in its device-writable part,
+//! messages are parsed with `serialize::read_msg` and served by the same dispatcher
+//! as stream transports.
+//!
+//! Only the subset of the protocol needed by virtio-9p is implemented:
+//! single split virtqueue, no indirect descriptors, no event index, no dirty log.
+
+use std::collections::HashMap;
+use std::ffi::c_void;
+use std::io::{self, Write};
+use std::num::NonZeroUsize;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{fence, Ordering};
+use std::sync::{Arc, Mutex};
+
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use bytes::BytesMut;
+use enum_primitive::*;
+use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::sys::mman::{mmap, munmap, MapFlags, ProtFlags};
+use nix::sys::socket::{recvmsg, ControlMessageOwned, MsgFlags};
+use tokio::io::unix::AsyncFd;
+use tokio::io::Interest;
+use tokio::net::UnixStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_stream::wrappers::UnboundedReceiverStream;
+
+use super::codec::encode_msg;
+use super::error::{self, errno::*};
+use super::fcall::{Fcall, Msg};
+use super::lib_utils::Result;
+use super::serialize;
+use super::srv::{dispatch_msgs, Filesystem, Limits, Msize, Shutdown};
+
+/// Mount tag reported in the device config space when none is configured
+pub const DEFAULT_MOUNT_TAG: &str = "ya-vm-file-server";
+
+const VHOST_USER_VERSION: u32 = 0x1;
+const VHOST_USER_VERSION_MASK: u32 = 0x3;
+const VHOST_USER_REPLY_MASK: u32 = 0x1 << 2;
+const VHOST_USER_NEED_REPLY_MASK: u32 = 0x1 << 3;
+const VHOST_USER_HEADER_SIZE: usize = 12;
+const VHOST_USER_MAX_PAYLOAD: usize = 4096;
+const VHOST_USER_MAX_FDS: usize = 8;
+
+const VHOST_USER_VRING_IDX_MASK: u64 = 0xff;
+const VHOST_USER_VRING_NOFD_MASK: u64 = 0x1 << 8;
+
+const VIRTIO_9P_MOUNT_TAG: u64 = 0x1 << 0;
+const VHOST_USER_F_PROTOCOL_FEATURES: u64 = 0x1 << 30;
+const VIRTIO_F_VERSION_1: u64 = 0x1 << 32;
+
+const VHOST_USER_PROTOCOL_F_REPLY_ACK: u64 = 0x1 << 3;
+const VHOST_USER_PROTOCOL_F_CONFIG: u64 = 0x1 << 9;
+
+const FEATURES: u64 = VIRTIO_9P_MOUNT_TAG | VHOST_USER_F_PROTOCOL_FEATURES | VIRTIO_F_VERSION_1;
+const PROTOCOL_FEATURES: u64 = VHOST_USER_PROTOCOL_F_REPLY_ACK | VHOST_USER_PROTOCOL_F_CONFIG;
+
+const VRING_DESC_F_NEXT: u16 = 0x1;
+const VRING_DESC_F_WRITE: u16 = 0x2;
+const VRING_DESC_F_INDIRECT: u16 = 0x4;
+const VRING_AVAIL_F_NO_INTERRUPT: u16 = 0x1;
+const VRING_MAX_SIZE: u32 = 32768;
+
+enum_from_primitive! {
+    #[doc = "vhost-user master requests handled by the backend"]
+    #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+    enum Request {
+        GetFeatures         = 1,
+        SetFeatures         = 2,
+        SetOwner            = 3,
+        ResetOwner          = 4,
+        SetMemTable         = 5,
+        SetLogBase          = 6,
+        SetLogFd            = 7,
+        SetVringNum         = 8,
+        SetVringAddr        = 9,
+        SetVringBase        = 10,
+        GetVringBase        = 11,
+        SetVringKick        = 12,
+        SetVringCall        = 13,
+        SetVringErr         = 14,
+        GetProtocolFeatures = 15,
+        SetProtocolFeatures = 16,
+        GetQueueNum         = 17,
+        SetVringEnable      = 18,
+        GetConfig           = 24,
+        SetConfig           = 25,
+    }
+}
+
+fn invalid(msg: &str) -> error::Error {
+    From::from(io_err!(InvalidData, msg.to_owned()))
+}
+
+/// Single memory region mapped from the fd sent by the master
+struct MemoryRegion {
+    guest_phys_addr: u64,
+    size: u64,
+    userspace_addr: u64,
+    host_addr: usize,
+    mmap_addr: usize,
+    mmap_len: usize,
+}
+
+/// Guest memory shared by the master, mapped into the server process
+struct GuestMemory {
+    regions: Vec<MemoryRegion>,
+}
+
+impl GuestMemory {
+    fn map(payload: &[u8], fds: Vec<OwnedFd>) -> Result<GuestMemory> {
+        let mut payload = payload;
+        let nregions = payload.read_u32::<LittleEndian>()? as usize;
+        let _padding = payload.read_u32::<LittleEndian>()?;
+
+        if nregions != fds.len() || nregions > VHOST_USER_MAX_FDS {
+            return Err(invalid("Memory regions do not match passed fds"));
+        }
+
+        let mut memory = GuestMemory {
+            regions: Vec::with_capacity(nregions),
+        };
+
+        for fd in fds {
+            let guest_phys_addr = payload.read_u64::<LittleEndian>()?;
+            let size = payload.read_u64::<LittleEndian>()?;
+            let userspace_addr = payload.read_u64::<LittleEndian>()?;
+            let mmap_offset = payload.read_u64::<LittleEndian>()?;
+
+            let mmap_len = size
+                .checked_add(mmap_offset)
+                .and_then(|len| NonZeroUsize::new(len as usize))
+                .ok_or_else(|| invalid("Invalid memory region size"))?;
+            if guest_phys_addr.checked_add(size).is_none()
+                || userspace_addr.checked_add(size).is_none()
+            {
+                return Err(invalid("Memory region outside of address space"));
+            }
+
+            let mmap_addr = unsafe {
+                mmap(
+                    None,
+                    mmap_len,
+                    ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+                    MapFlags::MAP_SHARED,
+                    fd.as_raw_fd(),
+                    0,
+                )
+            }
+            .map_err(io::Error::from)? as usize;
+
+            memory.regions.push(MemoryRegion {
+                guest_phys_addr,
+                size,
+                userspace_addr,
+                host_addr: mmap_addr + mmap_offset as usize,
+                mmap_addr,
+                mmap_len: mmap_len.get(),
+            });
+        }
+
+        Ok(memory)
+    }
+
+    fn translate(
+        &self,
+        addr: u64,
+        len: usize,
+        start: impl Fn(&MemoryRegion) -> u64,
+    ) -> Result<usize> {
+        self.regions
+            .iter()
+            .find(|region| {
+                let region_start = start(region);
+                let region_end = region_start.checked_add(region.size);
+                addr >= region_start
+                    && addr
+                        .checked_add(len as u64)
+                        .zip(region_end)
+                        .is_some_and(|(end, region_end)| end <= region_end)
+            })
+            .map(|region| region.host_addr + (addr - start(region)) as usize)
+            .ok_or_else(|| invalid("Address outside of guest memory"))
+    }
+
+    /// Pointer to guest physical address range, as used in descriptors
+    fn gpa(&self, addr: u64, len: usize) -> Result<usize> {
+        self.translate(addr, len, |region| region.guest_phys_addr)
+    }
+
+    /// Pointer to master's virtual address range, as used in vring addresses
+    fn uva(&self, addr: u64, len: usize) -> Result<usize> {
+        self.translate(addr, len, |region| region.userspace_addr)
+    }
+
+    fn read(&self, addr: u64, buf: &mut [u8]) -> Result<()> {
+        let src = self.gpa(addr, buf.len())?;
+        unsafe { ptr::copy_nonoverlapping(src as *const u8, buf.as_mut_ptr(), buf.len()) };
+        Ok(())
+    }
+
+    fn write(&self, addr: u64, data: &[u8]) -> Result<()> {
+        let dst = self.gpa(addr, data.len())?;
+        unsafe { ptr::copy_nonoverlapping(data.as_ptr(), dst as *mut u8, data.len()) };
+        Ok(())
+    }
+}
+
+impl Drop for GuestMemory {
+    fn drop(&mut self) {
+        for region in &self.regions {
+            if let Err(e) = unsafe { munmap(region.mmap_addr as *mut c_void, region.mmap_len) } {
+                log::warn!("Failed to unmap guest memory: {}", e);
+            }
+        }
+    }
+}
+
+unsafe fn read_u16(addr: usize) -> u16 {
+    u16::from_le(ptr::read_volatile(addr as *const u16))
+}
+
+unsafe fn write_u16(addr: usize, value: u16) {
+    ptr::write_volatile(addr as *mut u16, value.to_le())
+}
+
+unsafe fn write_u32(addr: usize, value: u32) {
+    ptr::write_volatile(addr as *mut u32, value.to_le())
+}
+
+/// Descriptor chain holding single 9P request and room for its response
+struct DescChain {
+    head: u16,
+    readable: Vec<(u64, u32)>,
+    writable: Vec<(u64, u32)>,
+}
+
+/// Running split virtqueue
+struct Queue {
+    memory: Arc<GuestMemory>,
+    size: u16,
+    desc: usize,
+    avail: usize,
+    used: usize,
+    next_avail: u16,
+    next_used: u16,
+    call: Option<Arc<OwnedFd>>,
+    /// Chains waiting for the response, by the tag of the request
+    pending: HashMap<u16, DescChain>,
+    /// Tags of requests being flushed, by the tag of the Tflush
+    flushes: HashMap<u16, u16>,
+}
+
+impl Queue {
+    fn new(memory: Arc<GuestMemory>, vring: &VringConfig) -> Result<Queue> {
+        let (desc, avail, used) = Self::rings(&memory, vring, vring.size)?;
+        let next_used = unsafe { read_u16(used + 2) };
+
+        Ok(Queue {
+            memory,
+            size: vring.size,
+            desc,
+            avail,
+            used,
+            next_avail: vring.base,
+            next_used,
+            call: vring.call.clone(),
+            pending: HashMap::new(),
+            flushes: HashMap::new(),
+        })
+    }
+
+    /// Pointers to descriptor table, available and used ring of `size` entries
+    fn rings(
+        memory: &GuestMemory,
+        vring: &VringConfig,
+        size: u16,
+    ) -> Result<(usize, usize, usize)> {
+        let size = size as usize;
+        Ok((
+            memory.uva(vring.desc_addr, 16 * size)?,
+            memory.uva(vring.avail_addr, 6 + 2 * size)?,
+            memory.uva(vring.used_addr, 6 + 8 * size)?,
+        ))
+    }
+
+    /// Switches to guest memory mapped again by the master, requests in flight are kept
+    fn remap(&mut self, memory: Arc<GuestMemory>, vring: &VringConfig) -> Result<()> {
+        (self.desc, self.avail, self.used) = Self::rings(&memory, vring, self.size)?;
+        self.memory = memory;
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Option<Result<DescChain>> {
+        let avail_idx = unsafe { read_u16(self.avail + 2) };
+        if avail_idx == self.next_avail {
+            return None;
+        }
+        fence(Ordering::Acquire);
+
+        let slot = (self.next_avail % self.size) as usize;
+        let head = unsafe { read_u16(self.avail + 4 + 2 * slot) };
+        self.next_avail = self.next_avail.wrapping_add(1);
+
+        Some(self.read_chain(head))
+    }
+
+    fn read_chain(&self, head: u16) -> Result<DescChain> {
+        let mut chain = DescChain {
+            head,
+            readable: Vec::new(),
+            writable: Vec::new(),
+        };
+
+        let mut index = head;
+        for _ in 0..self.size {
+            if index >= self.size {
+                return Err(invalid("Descriptor index out of range"));
+            }
+
+            let desc = self.desc + 16 * index as usize;
+            let (addr, len, flags, next) = unsafe {
+                (
+                    u64::from_le(ptr::read_volatile(desc as *const u64)),
+                    u32::from_le(ptr::read_volatile((desc + 8) as *const u32)),
+                    read_u16(desc + 12),
+                    read_u16(desc + 14),
+                )
+            };
+
+            if flags & VRING_DESC_F_INDIRECT != 0 {
+                return Err(invalid("Indirect descriptors are not supported"));
+            }
+
+            if flags & VRING_DESC_F_WRITE != 0 {
+                chain.writable.push((addr, len));
+            } else if chain.writable.is_empty() {
+                chain.readable.push((addr, len));
+            } else {
+                return Err(invalid("Readable descriptor after writable one"));
+            }
+
+            if flags & VRING_DESC_F_NEXT == 0 {
+                return Ok(chain);
+            }
+            index = next;
+        }
+
+        Err(invalid("Descriptor chain is looped"))
+    }
+
+    fn read_request(&self, chain: &DescChain) -> Result<Msg> {
+        let len = chain.readable.iter().map(|(_, len)| *len as usize).sum();
+        let mut buf = vec![0; len];
+
+        let mut offset = 0;
+        for (addr, len) in &chain.readable {
+            let len = *len as usize;
+            self.memory.read(*addr, &mut buf[offset..offset + len])?;
+            offset += len;
+        }
+
+        let size = (&buf[..]).read_u32::<LittleEndian>()? as usize;
+        if size < 4 || size > buf.len() {
+            return Err(invalid("Invalid 9P message size"));
+        }
+
+        Ok(serialize::read_msg(&mut &buf[4..size])?)
+    }
+
+    fn write_response(&self, chain: &DescChain, response: &Msg) -> Result<u32> {
+        let capacity: usize = chain.writable.iter().map(|(_, len)| *len as usize).sum();
+
+        let mut buf = BytesMut::new();
+        encode_msg(response, &mut buf)?;
+        if buf.len() > capacity {
+            log::error!(
+                "Response for tag {} does not fit in {} bytes",
+                response.tag,
+                capacity
+            );
+            buf.clear();
+            let lerror = Msg {
+                tag: response.tag,
+                body: Fcall::Rlerror {
+                    ecode: EMSGSIZE as u32,
+                },
+            };
+            encode_msg(&lerror, &mut buf)?;
+        }
+        let len = buf.len();
+
+        let mut data = &buf[..];
+        for (addr, len) in &chain.writable {
+            if data.is_empty() {
+                break;
+            }
+            let chunk = data.len().min(*len as usize);
+            self.memory.write(*addr, &data[..chunk])?;
+            data = &data[chunk..];
+        }
+
+        Ok(len as u32)
+    }
+
+    fn push(&mut self, head: u16, len: u32) {
+        let slot = (self.next_used % self.size) as usize;
+        unsafe {
+            let elem = self.used + 4 + 8 * slot;
+            write_u32(elem, head as u32);
+            write_u32(elem + 4, len);
+        }
+        self.next_used = self.next_used.wrapping_add(1);
+
+        fence(Ordering::Release);
+        unsafe { write_u16(self.used + 2, self.next_used) };
+        fence(Ordering::SeqCst);
+
+        let flags = unsafe { read_u16(self.avail) };
+        if flags & VRING_AVAIL_F_NO_INTERRUPT == 0 {
+            self.signal();
+        }
+    }
+
+    fn signal(&self) {
+        if let Some(call) = &self.call {
+            if let Err(e) = nix::unistd::write(call.as_raw_fd(), &1u64.to_ne_bytes()) {
+                log::warn!("Failed to signal used buffers: {}", e);
+            }
+        }
+    }
+
+    /// Moves new requests from available ring to the dispatcher
+    fn process(&mut self, requests: &mpsc::UnboundedSender<Result<Msg>>) {
+        while let Some(chain) = self.pop() {
+            let request = chain.and_then(|chain| {
+                let request = self.read_request(&chain);
+                if request.is_err() {
+                    self.push(chain.head, 0);
+                }
+                request.map(|msg| (chain, msg))
+            });
+
+            match request {
+                Ok((chain, msg)) => {
+                    match msg.body {
+                        Fcall::Tflush { oldtag } => {
+                            self.flushes.insert(msg.tag, oldtag);
+                        }
+                        // Requests of the previous session are aborted without response
+                        Fcall::Tversion { .. } => {
+                            let aborted: Vec<_> = self.pending.drain().collect();
+                            for (_, old) in aborted {
+                                self.push(old.head, 0);
+                            }
+                            self.flushes.clear();
+                        }
+                        _ => {}
+                    }
+                    if let Some(previous) = self.pending.insert(msg.tag, chain) {
+                        log::warn!("Tag {} reused before its response was sent", msg.tag);
+                        self.push(previous.head, 0);
+                    }
+                    let _ = requests.send(Ok(msg));
+                }
+                Err(e) => log::error!("Dropping invalid request: {}", e),
+            }
+        }
+    }
+
+    fn complete(&mut self, response: Msg) {
+        // Flushed request gets no response, its buffers are returned before Rflush
+        if let Some(oldtag) = self.flushes.remove(&response.tag) {
+            if let Some(old) = self.pending.remove(&oldtag) {
+                self.push(old.head, 0);
+            }
+        }
+
+        let chain = match self.pending.remove(&response.tag) {
+            Some(chain) => chain,
+            None => {
+                log::warn!("No pending request for tag {}", response.tag);
+                return;
+            }
+        };
+
+        let len = self.write_response(&chain, &response).unwrap_or_else(|e| {
+            log::error!("Failed writing response for tag {}: {}", response.tag, e);
+            0
+        });
+        self.push(chain.head, len);
+    }
+}
+
+type SharedQueue = Arc<Mutex<Option<Queue>>>;
+
+/// Waits for guest notifications and forwards new requests
+async fn handle_kicks(
+    kick: OwnedFd,
+    queue: SharedQueue,
+    requests: mpsc::UnboundedSender<Result<Msg>>,
+) -> Result<()> {
+    // Master is free to pass blocking eventfd, draining it must never block the reactor
+    let flags = OFlag::from_bits_truncate(
+        fcntl(kick.as_raw_fd(), FcntlArg::F_GETFL).map_err(io::Error::from)?,
+    );
+    fcntl(
+        kick.as_raw_fd(),
+        FcntlArg::F_SETFL(flags | OFlag::O_NONBLOCK),
+    )
+    .map_err(io::Error::from)?;
+    let kick = AsyncFd::new(kick)?;
+
+    loop {
+        if let Some(queue) = queue.lock().unwrap().as_mut() {
+            queue.process(&requests);
+        }
+
+        let mut guard = kick.readable().await?;
+        let mut counter = [0u8; 8];
+        match guard.try_io(|inner| {
+            nix::unistd::read(inner.as_raw_fd(), &mut counter).map_err(io::Error::from)
+        }) {
+            Ok(read) => {
+                read?;
+            }
+            Err(_would_block) => continue,
+        }
+    }
+}
+
+/// Virtqueue setup received from the master
+#[derive(Default)]
+struct VringConfig {
+    size: u16,
+    desc_addr: u64,
+    avail_addr: u64,
+    used_addr: u64,
+    base: u16,
+    enabled: bool,
+    kick: Option<OwnedFd>,
+    call: Option<Arc<OwnedFd>>,
+}
+
+/// State of the connection with single vhost-user master
+struct Session {
+    acked_features: u64,
+    acked_protocol_features: u64,
+    memory: Option<Arc<GuestMemory>>,
+    vring: VringConfig,
+    queue: SharedQueue,
+    kick_task: Option<JoinHandle<Result<()>>>,
+    requests: mpsc::UnboundedSender<Result<Msg>>,
+    mount_tag: String,
+}
+
+impl Session {
+    fn start_queue(&mut self) -> Result<()> {
+        self.stop_queue();
+
+        let memory = match &self.memory {
+            Some(memory) => memory.clone(),
+            None => return Ok(()),
+        };
+        // Every kick task registers its own copy of the fd with the reactor
+        let kick = match &self.vring.kick {
+            Some(kick) => kick.try_clone()?,
+            None => return Ok(()),
+        };
+
+        // Ring is started once the master gives its size
+        if self.vring.size == 0 {
+            return Ok(());
+        }
+        // Without protocol features rings are enabled as soon as they get kick fd
+        if !self.vring.enabled && self.acked_features & VHOST_USER_F_PROTOCOL_FEATURES != 0 {
+            return Ok(());
+        }
+
+        *self.queue.lock().unwrap() = Some(Queue::new(memory, &self.vring)?);
+        self.kick_task = Some(tokio::spawn(handle_kicks(
+            kick,
+            self.queue.clone(),
+            self.requests.clone(),
+        )));
+
+        log::info!("Virtqueue started, size: {}", self.vring.size);
+        Ok(())
+    }
+
+    /// Stops processing and returns index of the next available descriptor
+    fn stop_queue(&mut self) -> u16 {
+        if let Some(kick_task) = self.kick_task.take() {
+            kick_task.abort();
+        }
+        if let Some(queue) = self.queue.lock().unwrap().take() {
+            self.vring.base = queue.next_avail;
+        }
+        self.vring.base
+    }
+
+    fn vring_index(&self, index: u64) -> Result<()> {
+        match index & VHOST_USER_VRING_IDX_MASK {
+            0 => Ok(()),
+            _ => Err(invalid("virtio-9p has single virtqueue")),
+        }
+    }
+
+    fn config_space(&self) -> Vec<u8> {
+        let mut config = Vec::with_capacity(2 + self.mount_tag.len());
+        config
+            .write_u16::<LittleEndian>(self.mount_tag.len() as u16)
+            .unwrap();
+        config.write_all(self.mount_tag.as_bytes()).unwrap();
+        config
+    }
+
+    /// Handles single request, returns payload of the reply if the request has one
+    fn handle(
+        &mut self,
+        request: Request,
+        payload: &[u8],
+        mut fds: Vec<OwnedFd>,
+    ) -> Result<Option<Vec<u8>>> {
+        let mut body = payload;
+        let mut reply = Vec::new();
+
+        match request {
+            Request::GetFeatures => reply.write_u64::<LittleEndian>(FEATURES)?,
+            Request::SetFeatures => {
+                self.acked_features = body.read_u64::<LittleEndian>()? & FEATURES;
+                return Ok(None);
+            }
+            Request::GetProtocolFeatures => reply.write_u64::<LittleEndian>(PROTOCOL_FEATURES)?,
+            Request::SetProtocolFeatures => {
+                self.acked_protocol_features = body.read_u64::<LittleEndian>()? & PROTOCOL_FEATURES;
+                return Ok(None);
+            }
+            Request::SetOwner => return Ok(None),
+            Request::ResetOwner => {
+                self.stop_queue();
+                self.vring = VringConfig::default();
+                self.acked_features = 0;
+                return Ok(None);
+            }
+            Request::GetQueueNum => reply.write_u64::<LittleEndian>(1)?,
+            Request::SetMemTable => {
+                let memory = Arc::new(GuestMemory::map(payload, fds)?);
+                // Running queue keeps chains of requests in flight, their responses still go there
+                if let Some(queue) = self.queue.lock().unwrap().as_mut() {
+                    queue.remap(memory.clone(), &self.vring)?;
+                }
+                self.memory = Some(memory);
+                return Ok(None);
+            }
+            Request::SetVringNum => {
+                self.vring_index(body.read_u32::<LittleEndian>()? as u64)?;
+                let size = body.read_u32::<LittleEndian>()?;
+                if size == 0 || size > VRING_MAX_SIZE || !size.is_power_of_two() {
+                    return Err(invalid("Invalid virtqueue size"));
+                }
+                self.vring.size = size as u16;
+                return Ok(None);
+            }
+            Request::SetVringAddr => {
+                self.vring_index(body.read_u32::<LittleEndian>()? as u64)?;
+                let _flags = body.read_u32::<LittleEndian>()?;
+                self.vring.desc_addr = body.read_u64::<LittleEndian>()?;
+                self.vring.used_addr = body.read_u64::<LittleEndian>()?;
+                self.vring.avail_addr = body.read_u64::<LittleEndian>()?;
+                return Ok(None);
+            }
+            Request::SetVringBase => {
+                self.vring_index(body.read_u32::<LittleEndian>()? as u64)?;
+                self.vring.base = body.read_u32::<LittleEndian>()? as u16;
+                return Ok(None);
+            }
+            Request::GetVringBase => {
+                let index = body.read_u32::<LittleEndian>()?;
+                self.vring_index(index as u64)?;
+                let base = self.stop_queue();
+                self.vring.kick = None;
+                reply.write_u32::<LittleEndian>(index)?;
+                reply.write_u32::<LittleEndian>(base as u32)?;
+            }
+            Request::SetVringKick | Request::SetVringCall | Request::SetVringErr => {
+                let index = body.read_u64::<LittleEndian>()?;
+                self.vring_index(index)?;
+                let fd = match index & VHOST_USER_VRING_NOFD_MASK {
+                    0 => Some(fds.pop().ok_or_else(|| invalid("Missing vring fd"))?),
+                    _ => None,
+                };
+
+                match request {
+                    Request::SetVringKick => {
+                        if fd.is_none() {
+                            return Err(invalid("Polling virtqueue is not supported"));
+                        }
+                        self.vring.kick = fd;
+                        self.start_queue()?;
+                    }
+                    Request::SetVringCall => {
+                        self.vring.call = fd.map(Arc::new);
+                        if let Some(queue) = self.queue.lock().unwrap().as_mut() {
+                            queue.call = self.vring.call.clone();
+                        }
+                    }
+                    _ => {}
+                }
+                return Ok(None);
+            }
+            Request::SetVringEnable => {
+                self.vring_index(body.read_u32::<LittleEndian>()? as u64)?;
+                self.vring.enabled = body.read_u32::<LittleEndian>()? != 0;
+                if self.vring.enabled {
+                    self.start_queue()?;
+                } else {
+                    self.stop_queue();
+                }
+                return Ok(None);
+            }
+            Request::GetConfig => {
+                let offset = body.read_u32::<LittleEndian>()? as usize;
+                let size = body.read_u32::<LittleEndian>()? as usize;
+                let flags = body.read_u32::<LittleEndian>()?;
+
+                let config = self.config_space();
+                let data = config.get(offset..offset + size);
+
+                reply.write_u32::<LittleEndian>(offset as u32)?;
+                reply.write_u32::<LittleEndian>(data.map_or(0, |data| data.len()) as u32)?;
+                reply.write_u32::<LittleEndian>(flags)?;
+                reply.write_all(data.unwrap_or_default())?;
+            }
+            Request::SetConfig => return Err(invalid("Config space is read only")),
+            Request::SetLogBase | Request::SetLogFd => {
+                return Err(invalid("Dirty page logging is not supported"))
+            }
+        }
+
+        Ok(Some(reply))
+    }
+}
+
+fn recv_with_fds(fd: RawFd, buf: &mut [u8], fds: &mut Vec<OwnedFd>) -> io::Result<usize> {
+    let mut cmsg = nix::cmsg_space!([RawFd; VHOST_USER_MAX_FDS]);
+    let mut iov = [io::IoSliceMut::new(buf)];
+
+    let msg = recvmsg::<()>(fd, &mut iov, Some(&mut cmsg), MsgFlags::MSG_CMSG_CLOEXEC)?;
+    for cmsg in msg.cmsgs() {
+        if let ControlMessageOwned::ScmRights(received) = cmsg {
+            fds.extend(
+                received
+                    .into_iter()
+                    .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+            );
+        }
+    }
+
+    Ok(msg.bytes)
+}
+
+/// Reads single master request with fds passed along with it
+async fn read_request(
+    stream: &mut UnixStream,
+) -> Result<Option<(u32, u32, Vec<u8>, Vec<OwnedFd>)>> {
+    let mut header = [0u8; VHOST_USER_HEADER_SIZE];
+    let mut fds = Vec::new();
+
+    let received = loop {
+        stream.readable().await?;
+        match stream.try_io(Interest::READABLE, || {
+            recv_with_fds(stream.as_raw_fd(), &mut header, &mut fds)
+        }) {
+            Ok(received) => break received,
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(e) => return Err(e.into()),
+        }
+    };
+
+    if received == 0 {
+        return Ok(None);
+    }
+    tokio::io::AsyncReadExt::read_exact(stream, &mut header[received..]).await?;
+
+    let mut header = &header[..];
+    let request = header.read_u32::<LittleEndian>()?;
+    let flags = header.read_u32::<LittleEndian>()?;
+    let size = header.read_u32::<LittleEndian>()? as usize;
+
+    if flags & VHOST_USER_VERSION_MASK != VHOST_USER_VERSION || size > VHOST_USER_MAX_PAYLOAD {
+        return Err(invalid("Invalid vhost-user message header"));
+    }
+
+    let mut payload = vec![0; size];
+    tokio::io::AsyncReadExt::read_exact(stream, &mut payload).await?;
+
+    Ok(Some((request, flags, payload, fds)))
+}
+
+async fn send_reply(stream: &mut UnixStream, request: u32, payload: &[u8]) -> Result<()> {
+    let mut message = Vec::with_capacity(VHOST_USER_HEADER_SIZE + payload.len());
+    message.write_u32::<LittleEndian>(request)?;
+    message.write_u32::<LittleEndian>(VHOST_USER_VERSION | VHOST_USER_REPLY_MASK)?;
+    message.write_u32::<LittleEndian>(payload.len() as u32)?;
+    message.write_all(payload)?;
+
+    tokio::io::AsyncWriteExt::write_all(stream, &message).await?;
+    Ok(())
+}
+
+/// Serves single vhost-user master until it disconnects or shutdown is requested
+pub(crate) async fn serve_master<Fs>(
+    filesystem: Fs,
+    mut stream: UnixStream,
+    mount_tag: &str,
+    limits: &Limits,
+    mut shutdown: Shutdown,
+) -> Result<()>
+where
+    Fs: 'static + Filesystem + Send + Sync,
+{
+    let (requests_tx, requests_rx) = mpsc::unbounded_channel();
+    let queue: SharedQueue = Arc::new(Mutex::new(None));
+
+    let responses = Box::pin(futures::sink::unfold(
+        queue.clone(),
+        |queue: SharedQueue, response: Msg| async move {
+            if let Some(queue) = queue.lock().unwrap().as_mut() {
+                queue.complete(response);
+            }
+            Ok::<_, error::Error>(queue)
+        },
+    ));
+    let dispatcher = tokio::spawn(dispatch_msgs(
+        filesystem,
+        UnboundedReceiverStream::new(requests_rx),
+        responses,
+        Msize::new(limits.max_msize),
+        limits.clone(),
+        shutdown.clone(),
+    ));
+
+    let mut session = Session {
+        acked_features: 0,
+        acked_protocol_features: 0,
+        memory: None,
+        vring: VringConfig::default(),
+        queue,
+        kick_task: None,
+        requests: requests_tx,
+        mount_tag: mount_tag.to_owned(),
+    };
+
+    let res = loop {
+        let request = tokio::select! {
+            request = read_request(&mut stream) => request,
+            _ = shutdown.requested() => break Ok(()),
+        };
+        let (code, flags, payload, fds) = match request {
+            Ok(Some(request)) => request,
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        };
+
+        let result = match Request::from_u32(code) {
+            Some(request) => {
+                log::debug!("vhost-user request: {:?}", request);
+                session.handle(request, &payload, fds)
+            }
+            None => Err(invalid("Unknown vhost-user request")),
+        };
+
+        let reply = match result {
+            Ok(Some(reply)) => Some(reply),
+            Ok(None) | Err(_) => {
+                if let Err(e) = &result {
+                    log::error!("vhost-user request {} failed: {}", code, e);
+                }
+                let ack = session.acked_protocol_features & VHOST_USER_PROTOCOL_F_REPLY_ACK != 0
+                    && flags & VHOST_USER_NEED_REPLY_MASK != 0;
+                ack.then(|| (result.is_err() as u64).to_le_bytes().to_vec())
+            }
+        };
+
+        if let Some(reply) = reply {
+            if let Err(e) = send_reply(&mut stream, code, &reply).await {
+                break Err(e);
+            }
+        }
+    };
+
+    // Stop taking new requests, in-flight ones still get their responses into the ring
+    if let Some(kick_task) = session.kick_task.take() {
+        kick_task.abort();
+    }
+    // Closing requests channel lets the dispatcher finish
+    drop(session);
+    if let Ok(Err(e)) = dispatcher.await {
+        log::error!("Dispatcher failed: {}", e);
+    }
+
+    res
+}
@@ -0,0 +1,193 @@
+//! AF_VSOCK stream sockets for tokio.
+//!
+//! Lets the host serve VMs directly over virtio-vsock.
+
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::ready;
+use nix::sys::socket::{self, AddressFamily, SockFlag, SockType, VsockAddr};
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+use super::lib_utils::Result;
+
+/// Wildcard CID, accepts connections addressed to any CID of the host
+pub const VMADDR_CID_ANY: u32 = u32::MAX;
+
+/// CID of the host as seen by the guests
+pub const VMADDR_CID_HOST: u32 = 2;
+
+/// Loopback CID, reachable only from the same machine (requires `vsock_loopback`)
+pub const VMADDR_CID_LOCAL: u32 = 1;
+
+/// Parses `cid:port` address, `cid` can be a number or `any`
+pub fn parse_vsock_address(address: &str) -> Result<(u32, u32)> {
+    let invalid = || io_err!(InvalidInput, format!("Invalid vsock address: {}", address));
+
+    let (cid, port) = address.split_once(':').ok_or_else(invalid)?;
+    let cid = match cid {
+        "any" => VMADDR_CID_ANY,
+        cid => cid.parse().map_err(|_| invalid())?,
+    };
+    let port = port.parse().map_err(|_| invalid())?;
+
+    Ok((cid, port))
+}
+
+fn new_socket() -> io::Result<OwnedFd> {
+    let fd = socket::socket(
+        AddressFamily::Vsock,
+        SockType::Stream,
+        SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Vsock socket listening for connections
+pub struct VsockListener {
+    inner: AsyncFd<OwnedFd>,
+}
+
+impl VsockListener {
+    pub fn bind(cid: u32, port: u32) -> io::Result<VsockListener> {
+        let fd = new_socket()?;
+        socket::bind(fd.as_raw_fd(), &VsockAddr::new(cid, port))?;
+        socket::listen(fd.as_raw_fd(), 128)?;
+
+        Ok(VsockListener {
+            inner: AsyncFd::new(fd)?,
+        })
+    }
+
+    pub async fn accept(&self) -> io::Result<(VsockStream, VsockAddr)> {
+        loop {
+            let mut guard = self.inner.readable().await?;
+
+            match guard.try_io(|inner| {
+                socket::accept4(
+                    inner.as_raw_fd(),
+                    SockFlag::SOCK_NONBLOCK | SockFlag::SOCK_CLOEXEC,
+                )
+                .map_err(io::Error::from)
+            }) {
+                Ok(fd) => {
+                    let fd = unsafe { OwnedFd::from_raw_fd(fd?) };
+                    let peer = socket::getpeername::<VsockAddr>(fd.as_raw_fd())?;
+                    return Ok((VsockStream::new(fd)?, peer));
+                }
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<VsockAddr> {
+        Ok(socket::getsockname::<VsockAddr>(self.inner.as_raw_fd())?)
+    }
+}
+
+/// Connected vsock stream
+pub struct VsockStream {
+    inner: AsyncFd<OwnedFd>,
+}
+
+impl VsockStream {
+    fn new(fd: OwnedFd) -> io::Result<VsockStream> {
+        Ok(VsockStream {
+            inner: AsyncFd::new(fd)?,
+        })
+    }
+
+    pub async fn connect(cid: u32, port: u32) -> io::Result<VsockStream> {
+        let fd = new_socket()?;
+
+        match socket::connect(fd.as_raw_fd(), &VsockAddr::new(cid, port)) {
+            Ok(()) | Err(nix::errno::Errno::EINPROGRESS) => {}
+            Err(e) => return Err(e.into()),
+        }
+
+        let stream = VsockStream::new(fd)?;
+        let _ = stream.inner.writable().await?;
+
+        match socket::getsockopt(stream.as_raw_fd(), socket::sockopt::SocketError)? {
+            0 => Ok(stream),
+            errno => Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+}
+
+impl AsRawFd for VsockStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.as_raw_fd()
+    }
+}
+
+impl AsyncRead for VsockStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.inner.poll_read_ready(cx))?;
+
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|inner| {
+                nix::unistd::read(inner.as_raw_fd(), unfilled).map_err(io::Error::from)
+            }) {
+                Ok(Ok(read)) => {
+                    buf.advance(read);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for VsockStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.inner.poll_write_ready(cx))?;
+
+            match guard
+                .try_io(|inner| nix::unistd::write(inner.as_raw_fd(), buf).map_err(io::Error::from))
+            {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(
+            socket::shutdown(self.inner.as_raw_fd(), socket::Shutdown::Write)
+                .map_err(io::Error::from),
+        )
+    }
+}
+
+#[test]
+fn parse_vsock_address_test() {
+    assert_eq!(parse_vsock_address("3:7878").unwrap(), (3, 7878));
+    assert_eq!(
+        parse_vsock_address("any:564").unwrap(),
+        (VMADDR_CID_ANY, 564)
+    );
+    assert!(parse_vsock_address("3").is_err());
+    assert!(parse_vsock_address("host:564").is_err());
+    assert!(parse_vsock_address("3:port").is_err());
+}
@@ -0,0 +1,176 @@
+//! POSIX byte-range locks held by clients of one export.
+//!
+//! Locks exist only in the server, they do not lock host files. All connections of a server
+//! share one `LockManager`, so clients see locks of each other.
+use std::collections::HashMap;
+use std::fs::Metadata;
+use std::path::Path;
+
+#[cfg(unix)]
+use std::os::unix::fs::MetadataExt;
+
+use crate::core::fcall::LockType;
+
+/// File the locks are taken on, same for all paths and hard links to it
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum FileKey {
+    #[cfg(unix)]
+    Inode { dev: u64, ino: u64 },
+    #[cfg(not(unix))]
+    Path(std::path::PathBuf),
+}
+
+impl FileKey {
+    #[cfg(unix)]
+    pub fn new(_path: &Path, metadata: &Metadata) -> Self {
+        FileKey::Inode {
+            dev: metadata.dev(),
+            ino: metadata.ino(),
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn new(path: &Path, _metadata: &Metadata) -> Self {
+        FileKey::Path(path.canonicalize().unwrap_or_else(|_| path.to_owned()))
+    }
+}
+
+/// Holder of locks, a process of a client as given in `Flock`
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct LockOwner {
+    pub client_id: String,
+    pub proc_id: u32,
+}
+
+/// Locked bytes from `start` to `end`, both inclusive
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LockRange {
+    pub owner: LockOwner,
+    pub exclusive: bool,
+    pub start: u64,
+    pub end: u64,
+}
+
+impl LockRange {
+    /// Range of `length` bytes from `start`, zero length reaches the end of any file
+    pub fn new(owner: LockOwner, exclusive: bool, start: u64, length: u64) -> Self {
+        let end = match length {
+            0 => u64::MAX,
+            length => start.saturating_add(length - 1),
+        };
+        Self {
+            owner,
+            exclusive,
+            start,
+            end,
+        }
+    }
+
+    /// Length as in `Flock`, zero if the range reaches the end of any file
+    pub fn length(&self) -> u64 {
+        match self.end {
+            u64::MAX => 0,
+            end => end - self.start + 1,
+        }
+    }
+
+    pub fn typ(&self) -> LockType {
+        if self.exclusive {
+            LockType::WRLOCK
+        } else {
+            LockType::RDLOCK
+        }
+    }
+
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        self.start <= end && start <= self.end
+    }
+
+    fn conflicts(&self, other: &LockRange) -> bool {
+        self.owner != other.owner
+            && (self.exclusive || other.exclusive)
+            && self.overlaps(other.start, other.end)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct LockManager {
+    locks: HashMap<FileKey, Vec<LockRange>>,
+}
+
+impl LockManager {
+    /// Lock of another owner preventing `range` from being taken
+    pub fn conflict(&self, file: &FileKey, range: &LockRange) -> Option<&LockRange> {
+        self.locks
+            .get(file)?
+            .iter()
+            .find(|lock| lock.conflicts(range))
+    }
+
+    /// Takes `range` unless it conflicts, replacing locks its owner had on the same bytes
+    ///
+    /// Returns whether the range was taken.
+    pub fn lock(&mut self, file: &FileKey, range: LockRange) -> bool {
+        if self.conflict(file, &range).is_some() {
+            return false;
+        }
+
+        self.unlock(file, &range.owner, range.start, range.end);
+        let locks = self.locks.entry(file.clone()).or_default();
+
+        // Joins the range with adjacent or overlapping locks of the same kind
+        let mut merged = range;
+        locks.retain(|lock| {
+            let touches = lock.start <= merged.end.saturating_add(1)
+                && merged.start <= lock.end.saturating_add(1);
+            if lock.owner == merged.owner && lock.exclusive == merged.exclusive && touches {
+                merged.start = merged.start.min(lock.start);
+                merged.end = merged.end.max(lock.end);
+                false
+            } else {
+                true
+            }
+        });
+        locks.push(merged);
+        true
+    }
+
+    /// Releases bytes from `start` to `end` held by `owner`, splitting locks covering more
+    pub fn unlock(&mut self, file: &FileKey, owner: &LockOwner, start: u64, end: u64) {
+        let locks = match self.locks.get_mut(file) {
+            Some(locks) => locks,
+            None => return,
+        };
+
+        let mut kept = Vec::with_capacity(locks.len());
+        for lock in locks.drain(..) {
+            if lock.owner != *owner || !lock.overlaps(start, end) {
+                kept.push(lock);
+                continue;
+            }
+            if lock.start < start {
+                kept.push(LockRange {
+                    end: start - 1,
+                    ..lock.clone()
+                });
+            }
+            if lock.end > end {
+                kept.push(LockRange {
+                    start: end + 1,
+                    ..lock
+                });
+            }
+        }
+
+        if kept.is_empty() {
+            self.locks.remove(file);
+        } else {
+            *locks = kept;
+        }
+    }
+
+    /// Releases every lock `owner` has on `file`
+    pub fn release(&mut self, file: &FileKey, owner: &LockOwner) {
+        self.unlock(file, owner, 0, u64::MAX);
+    }
+}
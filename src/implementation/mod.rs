@@ -1,2 +1,5 @@
+pub mod locks;
 pub mod unpfs;
 pub mod utils;
+#[cfg(target_os = "linux")]
+pub mod xattr;
@@ -1,7 +1,11 @@
+use super::locks::{FileKey, LockManager, LockOwner, LockRange};
 use super::utils::*;
+#[cfg(target_os = "linux")]
+use super::xattr;
 use crate::core::attributes_cache::*;
+use crate::core::error::{errno::*, Error};
 use crate::core::lib_utils::Result;
-use std::path::PathBuf;
+use std::path::{Component, Path, PathBuf};
 use std::sync::Arc;
 use {
     async_trait::async_trait,
@@ -19,16 +23,51 @@ use crate::core::srv::Fid;
 use crate::core::srv::Filesystem;
 use tokio::io::SeekFrom;
 
+/// Extended attribute a fid was turned into by `Txattrwalk` or `Txattrcreate`
+enum Xattr {
+    /// Value or list of names, read by `Tread`
+    Read(bytes::Bytes),
+    /// Value filled by `Twrite`, set when the fid is clunked
+    Write {
+        name: String,
+        size: usize,
+        flags: u32,
+        value: Vec<u8>,
+    },
+}
+
 #[derive(Default)]
 pub struct UnpfsFid {
     realpath: RwLock<PathBuf>,
+    /// Uid given in `Tattach`, owner of files created through the fid
+    uid: RwLock<Option<u32>>,
     file: Mutex<Option<fs::File>>,
+    xattr: Mutex<Option<Xattr>>,
+    /// Owners which took locks through the fid, released when it is clunked
+    lock_owners: Mutex<Vec<(FileKey, LockOwner)>>,
+}
+
+/// Optional behaviour of `Unpfs`
+#[derive(Clone, Debug, Default)]
+pub struct UnpfsOptions {
+    /// Device nodes and sockets made by `Tmknod` are empty files on the host,
+    /// their type and device number are kept only in virtual attributes
+    pub emulate_devices: bool,
+    /// Size in bytes reported by `Tstatfs` instead of the size of the host disk
+    pub virtual_capacity: Option<u64>,
+    /// Extended attributes are kept in virtual attributes rather than on the host
+    pub virtual_xattrs: bool,
 }
 
 #[derive(Clone)]
 pub struct Unpfs {
     pub realroot: PathBuf,
     pub vap: Arc<Mutex<VirtualAttributesProvider>>,
+    pub options: UnpfsOptions,
+    /// Byte-range locks, shared by all connections to the export
+    pub locks: Arc<Mutex<LockManager>>,
+    /// Space used by the export, reported by `Tstatfs` with a virtual capacity
+    pub usage: Arc<Mutex<DiskUsage>>,
 }
 
 //todo -add feature maybe?
@@ -36,22 +75,245 @@ const DEBUG_FLAGS: bool = false;
 
 impl Unpfs {
     async fn get_va_from_realpath(&self, realpath: &PathBuf) -> Result<VirtualAttributes> {
-        let my_str = realpath.clone().into_os_string().into_string().unwrap();
+        let my_path = realpath.clone();
 
         let mut vap = self.vap.lock().await;
-        let va = vap.get_or_create_virtual_attributes(my_str);
+        let va = vap.get_or_create_virtual_attributes(my_path);
         va
     }
+    /// Fresh attributes of `path`, just made on the host
+    async fn replace_va(&self, path: &Path) -> Result<VirtualAttributes> {
+        let mut vap = self.vap.lock().await;
+        vap.replace_virtual_attributes(path.to_path_buf())
+    }
+
+    /// Forgets attributes of `path`, removed from the host, `metadata` is of the file before
+    async fn unlink_va(&self, path: &Path, metadata: &std::fs::Metadata) {
+        let mut vap = self.vap.lock().await;
+        vap.unlink(path, metadata)
+    }
+
+    /// Moves attributes of `path`, renamed on the host, to `new_path`
+    async fn rename_va(&self, path: &Path, new_path: &Path) {
+        let mut vap = self.vap.lock().await;
+        vap.rename(path, new_path)
+    }
+
     /*async fn get_va_from_os_string(&self, os_str: OsString) -> Result<VirtualAttributes> {
         let my_str = os_str.into_string().unwrap();
         let mut vap = self.vap.lock().await;
         let va = vap.get_or_create_virtual_attributes(my_str);
         va
     }*/
+    /// Target stored on the host for symlink in `dir` pointing to `target`
+    ///
+    /// Absolute targets are taken relative to the export root and relative ones
+    /// may not lead above it, so the host never follows a link out of the export.
+    /// The host keeps absolute ones relative to `dir`, marked by a leading `./`,
+    /// so they resolve inside the export wherever it is.
+    async fn host_symlink_target(&self, dir: &Path, target: &str) -> Result<PathBuf> {
+        let dir = match dir.strip_prefix(&self.realroot) {
+            Ok(dir) => dir,
+            Err(_) => {
+                return res!(io_err!(
+                    PermissionDenied,
+                    "Symlink is outside of the export"
+                ))
+            }
+        };
+
+        let target = Path::new(target);
+        if target.is_absolute() {
+            let mut host_target = PathBuf::from(".");
+            for _ in dir.components() {
+                host_target.push("..");
+            }
+            return Ok(host_target.join(path_beneath(target)));
+        }
+
+        match escapes_root(&dir.join(target)) {
+            false => Ok(target.to_path_buf()),
+            true => res!(io_err!(
+                PermissionDenied,
+                "Symlink target is outside of the export"
+            )),
+        }
+    }
+
+    /// Target of symlink in `dir` as seen by clients, absolute targets are translated
+    /// back to the export root and ones outside of it are not revealed
+    async fn guest_symlink_target(&self, dir: &Path, target: PathBuf) -> Result<String> {
+        let outside = || {
+            res!(io_err!(
+                PermissionDenied,
+                "Symlink target is outside of the export"
+            ))
+        };
+        let dir = match dir.strip_prefix(&self.realroot) {
+            Ok(dir) => dir,
+            Err(_) => return outside(),
+        };
+
+        if target.is_absolute() {
+            // Made on the host, or by older versions which kept the host path
+            let root = fs::canonicalize(&self.realroot).await?;
+            return match target.strip_prefix(&root) {
+                Ok(target) => Ok(Path::new("/").join(target).to_string_lossy().into_owned()),
+                Err(_) => outside(),
+            };
+        }
+        if escapes_root(&dir.join(&target)) {
+            return outside();
+        }
+
+        // `./` followed by `..` up to the export root is an absolute target
+        let mut components = target.components();
+        if components.next() == Some(Component::CurDir)
+            && dir
+                .components()
+                .all(|_| components.next() == Some(Component::ParentDir))
+        {
+            let rest = components.as_path();
+            if rest
+                .components()
+                .all(|component| matches!(component, Component::Normal(_)))
+            {
+                return Ok(Path::new("/").join(rest).to_string_lossy().into_owned());
+            }
+        }
+        Ok(target.to_string_lossy().into_owned())
+    }
+
+    async fn link_va(&self, realpath: &Path, new_path: &Path) -> Result<()> {
+        let mut vap = self.vap.lock().await;
+        vap.get_or_create_virtual_attributes(realpath.to_path_buf())?;
+        vap.link(realpath, new_path.to_path_buf())
+    }
+
+    /// Records `path` as device node or socket with `mode` and `rdev`
+    async fn make_virtual_node_va(
+        &self,
+        path: &Path,
+        mode: u32,
+        rdev: u64,
+    ) -> Result<VirtualAttributes> {
+        let my_path = path.to_path_buf();
+        let mut vap = self.vap.lock().await;
+        vap.replace_virtual_attributes(my_path.clone())?;
+        vap.update_virtual_attributes(my_path.clone(), |va| {
+            va.mode = mode;
+            va.rdev = rdev;
+        })?;
+        vap.get_or_create_virtual_attributes(my_path)
+    }
+
+    /// Gives `path`, just made by a client, permission bits of `mode` and owner `uid`:`gid`
+    ///
+    /// Files of clients attached without uid are owned by the default user.
+    async fn make_created_va(
+        &self,
+        path: &Path,
+        mode: u32,
+        uid: Option<u32>,
+        gid: u32,
+    ) -> Result<VirtualAttributes> {
+        // Only directories and regular files are made here, their type is the host one
+        let file_type = match fs::symlink_metadata(path).await?.is_dir() {
+            true => S_IFDIR,
+            false => S_IFREG,
+        };
+        let my_path = path.to_path_buf();
+        let mut vap = self.vap.lock().await;
+        vap.replace_virtual_attributes(my_path.clone())?;
+        let uid = uid.unwrap_or(vap.defaults.uid);
+        vap.update_virtual_attributes(my_path.clone(), |va| {
+            va.mode = file_type | (mode & !S_IFMT);
+            va.uid = uid;
+            va.gid = gid;
+        })?;
+        vap.get_or_create_virtual_attributes(my_path)
+    }
+
+    /// Changes owner of `path` to those of `uid` and `gid` which are given
+    async fn update_owner_va(&self, path: &Path, uid: Option<u32>, gid: Option<u32>) -> Result<()> {
+        let my_path = path.to_path_buf();
+        let mut vap = self.vap.lock().await;
+        vap.get_or_create_virtual_attributes(my_path.clone())?;
+        vap.update_virtual_attributes(my_path, |va| {
+            log::debug!(
+                "Changing owner from: {}:{} to {:?}:{:?}",
+                va.uid,
+                va.gid,
+                uid,
+                gid
+            );
+            va.uid = uid.unwrap_or(va.uid);
+            va.gid = gid.unwrap_or(va.gid);
+        })
+    }
+
+    /// Value of extended attribute `name` of `path`, or list of names if `name` is empty
+    async fn get_xattr(&self, path: &Path, name: &str) -> Result<Vec<u8>> {
+        if self.options.virtual_xattrs {
+            let my_path = path.to_path_buf();
+            let mut vap = self.vap.lock().await;
+            vap.get_or_create_virtual_attributes(my_path.clone())?;
+            return match name {
+                "" => vap.list_xattrs(&my_path),
+                name => vap.get_xattr(&my_path, name),
+            };
+        }
+
+        #[cfg(target_os = "linux")]
+        return match name {
+            "" => xattr::list(path),
+            name => xattr::get(path, name),
+        };
+        #[cfg(not(target_os = "linux"))]
+        Err(Error::No(EOPNOTSUPP))
+    }
+
+    /// Sets extended attribute `name` of `path`, empty `value` removes it
+    async fn set_xattr(&self, path: &Path, name: &str, value: Vec<u8>, flags: u32) -> Result<()> {
+        if self.options.virtual_xattrs {
+            let my_path = path.to_path_buf();
+            let mut vap = self.vap.lock().await;
+            vap.get_or_create_virtual_attributes(my_path.clone())?;
+            return match value.is_empty() {
+                true => vap.remove_xattr(&my_path, name),
+                false => vap.set_xattr(&my_path, name, value, flags),
+            };
+        }
+
+        #[cfg(target_os = "linux")]
+        return match value.is_empty() {
+            true => xattr::remove(path, name),
+            false => xattr::set(path, name, &value, flags),
+        };
+        #[cfg(not(target_os = "linux"))]
+        Err(Error::No(EOPNOTSUPP))
+    }
+
+    /// File `fid` refers to and owner of a lock taken through it
+    async fn lock_target(
+        &self,
+        fid: &Fid<UnpfsFid>,
+        client_id: &str,
+        proc_id: u32,
+    ) -> Result<(FileKey, LockOwner)> {
+        let path = { fid.aux.realpath.read().await.clone() };
+        let metadata = fs::metadata(&path).await?;
+        let owner = LockOwner {
+            client_id: client_id.to_owned(),
+            proc_id,
+        };
+        Ok((FileKey::new(&path, &metadata), owner))
+    }
+
     async fn update_permission_mode_va(&self, realpath: &PathBuf, mode: u32) -> Result<()> {
-        let my_str = realpath.clone().into_os_string().into_string().unwrap();
+        let my_path = realpath.clone();
         let mut vap = self.vap.lock().await;
-        vap.update_virtual_attributes(my_str, |va| {
+        vap.update_virtual_attributes(my_path, |va| {
             log::debug!("Changing attributes from: {} to {}", va.mode, mode);
             va.mode = mode;
         })
@@ -68,13 +330,17 @@ impl Filesystem for Unpfs {
         _afid: Option<&Fid<Self::Fid>>,
         _uname: &str,
         _aname: &str,
-        _n_uname: u32,
+        n_uname: u32,
     ) -> Result<Fcall> {
         let realpath = {
             let mut realpath = fid.aux.realpath.write().await;
             *realpath = PathBuf::from(&self.realroot);
             realpath.clone()
         };
+        {
+            let mut uid = fid.aux.uid.write().await;
+            *uid = Some(n_uname).filter(|&n_uname| n_uname != NONUNAME);
+        }
 
         let va = self.get_va_from_realpath(&realpath).await?;
 
@@ -113,10 +379,15 @@ impl Filesystem for Unpfs {
             wqids.push(qid);
         }
 
+        let uid = { *fid.aux.uid.read().await };
         {
             let mut new_realpath = newfid.aux.realpath.write().await;
             *new_realpath = path;
         }
+        {
+            let mut new_uid = newfid.aux.uid.write().await;
+            *new_uid = uid;
+        }
 
         Ok(Fcall::Rwalk { wqids: wqids })
     }
@@ -151,18 +422,11 @@ impl Filesystem for Unpfs {
             // fs::set_permissions(&filepath, PermissionsExt::from_mode(stat.mode)).await?;
         }
 
+        // Owners are emulated, the host files stay owned by the server
         if valid.intersects(SetattrMask::UID | SetattrMask::GID) {
-            /*let uid = if valid.contains(SetattrMask::UID) {
-                None//Some(nix::unistd::Uid::from_raw(stat.uid))
-            } else {
-                None
-            };
-            let gid = if valid.contains(SetattrMask::GID) {
-            None //   Some(nix::unistd::Gid::from_raw(stat.gid))
-            } else {
-                None
-            };*/
-            //  nix::unistd::chown(&filepath, uid, gid)?;
+            let uid = Some(stat.uid).filter(|_| valid.contains(SetattrMask::UID));
+            let gid = Some(stat.gid).filter(|_| valid.contains(SetattrMask::GID));
+            self.update_owner_va(&filepath, uid, gid).await?;
         }
 
         if valid.contains(SetattrMask::SIZE) {
@@ -198,14 +462,146 @@ impl Filesystem for Unpfs {
         Ok(Fcall::Rsetattr)
     }
 
+    #[cfg(unix)]
+    async fn rsymlink(
+        &self,
+        dfid: &Fid<Self::Fid>,
+        name: &str,
+        sym: &str,
+        _gid: u32,
+    ) -> Result<Fcall> {
+        let dir = { dfid.aux.realpath.read().await.clone() };
+        let path = dir.join(name);
+
+        let target = self.host_symlink_target(&dir, sym).await?;
+        fs::symlink(&target, &path).await?;
+
+        let va = self.replace_va(&path).await?;
+        Ok(Fcall::Rsymlink {
+            qid: get_qid(&path, &va).await?,
+        })
+    }
+
+    async fn rmknod(
+        &self,
+        dfid: &Fid<Self::Fid>,
+        name: &str,
+        mode: u32,
+        major: u32,
+        minor: u32,
+        _gid: u32,
+    ) -> Result<Fcall> {
+        let path = {
+            let realpath = dfid.aux.realpath.read().await;
+            realpath.join(name)
+        };
+
+        // Device number kept in virtual attributes of emulated nodes
+        let emulated = match mode & S_IFMT {
+            0 | S_IFREG => {
+                fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&path)
+                    .await?;
+                None
+            }
+            S_IFCHR | S_IFBLK | S_IFSOCK if self.options.emulate_devices => {
+                fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&path)
+                    .await?;
+                Some(makedev(major, minor))
+            }
+            #[cfg(target_os = "linux")]
+            S_IFIFO | S_IFCHR | S_IFBLK | S_IFSOCK => {
+                use nix::sys::stat::{mknod, Mode, SFlag};
+
+                let kind = SFlag::from_bits_truncate(mode & S_IFMT);
+                let perm = Mode::from_bits_truncate(mode & !S_IFMT);
+                let dev = makedev(major, minor);
+                let node = path.clone();
+                tokio::task::spawn_blocking(move || {
+                    mknod(&node, kind, perm, dev).map_err(std::io::Error::from)
+                })
+                .await
+                .map_err(|e| io_err!(Other, e))??;
+                None
+            }
+            #[cfg(not(target_os = "linux"))]
+            S_IFIFO | S_IFCHR | S_IFBLK | S_IFSOCK => {
+                return Err(crate::core::error::Error::No(
+                    crate::core::error::errno::EOPNOTSUPP,
+                ))
+            }
+            _ => return res!(io_err!(InvalidInput, "Invalid file type")),
+        };
+
+        let va = match emulated {
+            Some(rdev) => self.make_virtual_node_va(&path, mode, rdev).await?,
+            None => self.replace_va(&path).await?,
+        };
+        Ok(Fcall::Rmknod {
+            qid: get_qid(&path, &va).await?,
+        })
+    }
+
+    async fn rxattrwalk(
+        &self,
+        fid: &Fid<Self::Fid>,
+        newfid: &Fid<Self::Fid>,
+        name: &str,
+    ) -> Result<Fcall> {
+        let path = { fid.aux.realpath.read().await.clone() };
+        let value = self.get_xattr(&path, name).await?;
+        let size = value.len() as u64;
+
+        {
+            let mut new_realpath = newfid.aux.realpath.write().await;
+            *new_realpath = path;
+        }
+        {
+            let mut xattr = newfid.aux.xattr.lock().await;
+            *xattr = Some(Xattr::Read(value.into()));
+        }
+
+        Ok(Fcall::Rxattrwalk { size })
+    }
+
+    async fn rxattrcreate(
+        &self,
+        fid: &Fid<Self::Fid>,
+        name: &str,
+        attr_size: u64,
+        flags: u32,
+    ) -> Result<Fcall> {
+        if attr_size > XATTR_SIZE_MAX {
+            return Err(Error::No(E2BIG));
+        }
+
+        {
+            let mut xattr = fid.aux.xattr.lock().await;
+            *xattr = Some(Xattr::Write {
+                name: name.to_owned(),
+                size: attr_size as usize,
+                flags,
+                value: Vec::with_capacity(attr_size as usize),
+            });
+        }
+
+        Ok(Fcall::Rxattrcreate)
+    }
+
     async fn rreadlink(&self, fid: &Fid<Self::Fid>) -> Result<Fcall> {
-        let link = {
+        let (dir, link) = {
             let realpath = fid.aux.realpath.read().await;
-            fs::read_link(&*realpath).await?
+            let dir = realpath.parent().unwrap_or(&realpath).to_path_buf();
+            (dir, fs::read_link(&*realpath).await?)
         };
 
         Ok(Fcall::Rreadlink {
-            target: link.to_string_lossy().into_owned(),
+            target: self.guest_symlink_target(&dir, link).await?,
         })
     }
 
@@ -255,7 +651,7 @@ impl Filesystem for Unpfs {
         if DEBUG_FLAGS {
             print!(
                 "Rlopen {} flags: {} (0x{:x}) bits:(",
-                realpath.clone().into_os_string().into_string().unwrap(),
+                realpath.display(),
                 flags,
                 flags,
             );
@@ -276,21 +672,26 @@ impl Filesystem for Unpfs {
         }
 
         let qid = get_qid(&realpath, &va).await?;
-        if !qid.typ.contains(QidType::DIR) {
-            let fd = if fmode.intersects(FileOpenMode::P9_DOTL_WRONLY) {
-                tokio::fs::OpenOptions::new()
-                    .write(true)
-                    .open(&realpath)
-                    .await?
-            } else if fmode.intersects(FileOpenMode::P9_DOTL_RDWR) {
-                tokio::fs::OpenOptions::new()
-                    .read(true)
-                    .write(true)
-                    .open(&realpath)
-                    .await?
-            } else {
-                fs::File::open(&realpath).await?
-            };
+        if fmode.contains(FileOpenMode::P9_DOTL_CREATE | FileOpenMode::P9_DOTL_EXCL) {
+            return Err(Error::No(EEXIST));
+        }
+        if fmode.contains(FileOpenMode::P9_DOTL_NOFOLLOW) && qid.typ.contains(QidType::SYMLINK) {
+            return Err(Error::No(ELOOP));
+        }
+
+        if qid.typ.contains(QidType::DIR) {
+            // Directories are read by Treaddir only, nothing is opened on the host
+            let modifying = FileOpenMode::P9_DOTL_NOACCESS
+                | FileOpenMode::P9_DOTL_CREATE
+                | FileOpenMode::P9_DOTL_TRUNC;
+            if fmode.intersects(modifying) {
+                return Err(Error::No(EISDIR));
+            }
+        } else {
+            if fmode.contains(FileOpenMode::P9_DOTL_DIRECTORY) {
+                return Err(Error::No(ENOTDIR));
+            }
+            let fd = open_file(&realpath, fmode).await?;
 
             {
                 let mut file = fid.aux.file.lock().await;
@@ -310,7 +711,7 @@ impl Filesystem for Unpfs {
         name: &str,
         flags: u32,
         mode: u32,
-        _gid: u32,
+        gid: u32,
     ) -> Result<Fcall> {
         let path = {
             let realpath = fid.aux.realpath.read().await;
@@ -323,7 +724,7 @@ impl Filesystem for Unpfs {
         if DEBUG_FLAGS {
             print!(
                 "Create {} flags: {} (0x{:x}) mode: {} (0x{:x}) bits:(",
-                path.clone().into_os_string().into_string().unwrap(),
+                path.display(),
                 flags,
                 flags,
                 mode,
@@ -345,28 +746,21 @@ impl Filesystem for Unpfs {
             println!("{:?}", fmode);
         }
 
-        let fd = if fmode.intersects(FileOpenMode::P9_DOTL_WRONLY) {
-            tokio::fs::OpenOptions::new()
-                .create(true)
-                .write(true)
-                .open(&path)
-                .await?
-        } else if fmode.intersects(FileOpenMode::P9_DOTL_RDWR) {
-            tokio::fs::OpenOptions::new()
-                .create(true)
-                .read(true)
-                .write(true)
-                .open(&path)
-                .await?
-        } else {
-            tokio::fs::OpenOptions::new()
-                .create(true)
-                .read(true)
-                .open(&path)
-                .await?
+        // Mode and group apply only if the file did not exist
+        let create = fmode | FileOpenMode::P9_DOTL_CREATE | FileOpenMode::P9_DOTL_EXCL;
+        let (fd, va) = match open_file(&path, create).await {
+            Ok(fd) => {
+                let uid = { *fid.aux.uid.read().await };
+                (fd, self.make_created_va(&path, mode, uid, gid).await?)
+            }
+            Err(e)
+                if !fmode.contains(FileOpenMode::P9_DOTL_EXCL) && matches!(e.errno(), EEXIST) =>
+            {
+                let fd = open_file(&path, fmode - FileOpenMode::P9_DOTL_CREATE).await?;
+                (fd, self.get_va_from_realpath(&path).await?)
+            }
+            Err(e) => return Err(e),
         };
-
-        let va = self.get_va_from_realpath(&path).await?;
         let qid = get_qid(&path, &va).await?;
         {
             let mut realpath = fid.aux.realpath.write().await;
@@ -381,6 +775,14 @@ impl Filesystem for Unpfs {
     }
 
     async fn rread(&self, fid: &Fid<Self::Fid>, offset: u64, count: u32) -> Result<Fcall> {
+        if let Some(Xattr::Read(value)) = &*fid.aux.xattr.lock().await {
+            let start = (offset as usize).min(value.len());
+            let end = (start + count as usize).min(value.len());
+            return Ok(Fcall::Rread {
+                data: Data(value.slice(start..end)),
+            });
+        }
+
         let buf = {
             let mut file = fid.aux.file.lock().await;
             let file = file.as_mut().ok_or(io_err!(InvalidInput, "Invalid fid"))?;
@@ -392,26 +794,64 @@ impl Filesystem for Unpfs {
             buf
         };
 
-        Ok(Fcall::Rread { data: Data(buf) })
+        Ok(Fcall::Rread {
+            data: Data(buf.into()),
+        })
     }
 
     async fn rwrite(&self, fid: &Fid<Self::Fid>, offset: u64, data: &Data) -> Result<Fcall> {
+        if let Some(Xattr::Write { size, value, .. }) = &mut *fid.aux.xattr.lock().await {
+            let offset = offset as usize;
+            if offset > *size {
+                return Err(Error::No(ENOSPC));
+            }
+            let count = data.0.len().min(*size - offset);
+            if value.len() < offset + count {
+                value.resize(offset + count, 0);
+            }
+            value[offset..offset + count].copy_from_slice(&data.0[..count]);
+            return Ok(Fcall::Rwrite {
+                count: count as u32,
+            });
+        }
+
         let count = {
             let mut file = fid.aux.file.lock().await;
             let file = file.as_mut().ok_or(io_err!(InvalidInput, "Invalid fid"))?;
             file.seek(SeekFrom::Start(offset)).await?;
-            file.write(&data.0).await? as u32
+            let count = file.write(&data.0).await? as u32;
+            // tokio finishes writes in the background, Rwrite must mean the data is written
+            file.flush().await?;
+            count
         };
 
         Ok(Fcall::Rwrite { count })
     }
 
+    async fn rlink(
+        &self,
+        dfid: &Fid<Self::Fid>,
+        fid: &Fid<Self::Fid>,
+        name: &str,
+    ) -> Result<Fcall> {
+        let path = {
+            let realpath = dfid.aux.realpath.read().await;
+            realpath.join(name)
+        };
+        let target = { fid.aux.realpath.read().await.clone() };
+
+        fs::hard_link(&target, &path).await?;
+        self.link_va(&target, &path).await?;
+
+        Ok(Fcall::Rlink)
+    }
+
     async fn rmkdir(
         &self,
         dfid: &Fid<Self::Fid>,
         name: &str,
-        _mode: u32,
-        _gid: u32,
+        mode: u32,
+        gid: u32,
     ) -> Result<Fcall> {
         let path = {
             let realpath = dfid.aux.realpath.read().await;
@@ -420,7 +860,8 @@ impl Filesystem for Unpfs {
 
         fs::create_dir(&path).await?;
 
-        let va = self.get_va_from_realpath(&path).await?;
+        let uid = { *dfid.aux.uid.read().await };
+        let va = self.make_created_va(&path, mode, uid, gid).await?;
         Ok(Fcall::Rmkdir {
             qid: get_qid(&path, &va).await?,
         })
@@ -443,12 +884,23 @@ impl Filesystem for Unpfs {
             realpath.join(newname)
         };
 
+        // A file replaced by the rename loses a link like an unlinked one
+        let replaced = match oldpath == newpath {
+            true => None,
+            false => fs::symlink_metadata(&newpath).await.ok(),
+        };
         fs::rename(&oldpath, &newpath).await?;
+        if let Some(replaced) = replaced {
+            self.unlink_va(&newpath, &replaced).await;
+        }
+        if oldpath != newpath {
+            self.rename_va(&oldpath, &newpath).await;
+        }
 
         Ok(Fcall::Rrenameat)
     }
 
-    async fn runlinkat(&self, dirfid: &Fid<Self::Fid>, name: &str, _flags: u32) -> Result<Fcall> {
+    async fn runlinkat(&self, dirfid: &Fid<Self::Fid>, name: &str, flags: u32) -> Result<Fcall> {
         let path = {
             let realpath = dirfid.aux.realpath.read().await;
             realpath.join(name)
@@ -456,11 +908,13 @@ impl Filesystem for Unpfs {
 
         let attr = fs::symlink_metadata(&path).await?;
 
-        if attr.is_dir() {
-            fs::remove_dir(&path).await?;
-        } else {
-            fs::remove_file(&path).await?;
+        match (attr.is_dir(), flags & AT_REMOVEDIR != 0) {
+            (true, true) => fs::remove_dir(&path).await?,
+            (false, false) => fs::remove_file(&path).await?,
+            (true, false) => return res!(io_err!(IsADirectory, "Is a directory")),
+            (false, true) => return res!(io_err!(NotADirectory, "Not a directory")),
         }
+        self.unlink_va(&path, &attr).await;
 
         Ok(Fcall::Runlinkat)
     }
@@ -477,25 +931,101 @@ impl Filesystem for Unpfs {
         Ok(Fcall::Rfsync)
     }
 
-    async fn rclunk(&self, _: &Fid<Self::Fid>) -> Result<Fcall> {
+    async fn rlock(&self, fid: &Fid<Self::Fid>, lock: &Flock) -> Result<Fcall> {
+        let (file, owner) = self.lock_target(fid, &lock.client_id, lock.proc_id).await?;
+        let range = LockRange::new(
+            owner.clone(),
+            lock.typ == LockType::WRLOCK,
+            lock.start,
+            lock.length,
+        );
+
+        // Conflicting requests are not queued, clients retry blocking ones
+        let status = if lock.typ == LockType::UNLOCK {
+            let mut locks = self.locks.lock().await;
+            locks.unlock(&file, &owner, range.start, range.end);
+            LockStatus::SUCCESS
+        } else if lock.typ == LockType::RDLOCK || lock.typ == LockType::WRLOCK {
+            if !self.locks.lock().await.lock(&file, range) {
+                return Ok(Fcall::Rlock {
+                    status: LockStatus::BLOCKED,
+                });
+            }
+            let mut lock_owners = fid.aux.lock_owners.lock().await;
+            let target = (file, owner);
+            if !lock_owners.contains(&target) {
+                lock_owners.push(target);
+            }
+            LockStatus::SUCCESS
+        } else {
+            return Err(Error::No(EINVAL));
+        };
+
+        Ok(Fcall::Rlock { status })
+    }
+
+    async fn rgetlock(&self, fid: &Fid<Self::Fid>, lock: &Getlock) -> Result<Fcall> {
+        let (file, owner) = self.lock_target(fid, &lock.client_id, lock.proc_id).await?;
+        let range = LockRange::new(owner, lock.typ == LockType::WRLOCK, lock.start, lock.length);
+
+        let locks = self.locks.lock().await;
+        let flock = match locks.conflict(&file, &range) {
+            Some(conflict) => Getlock {
+                typ: conflict.typ(),
+                start: conflict.start,
+                length: conflict.length(),
+                proc_id: conflict.owner.proc_id,
+                client_id: conflict.owner.client_id.clone(),
+            },
+            None => Getlock {
+                typ: LockType::UNLOCK,
+                ..lock.clone()
+            },
+        };
+
+        Ok(Fcall::Rgetlock { flock })
+    }
+
+    async fn rclunk(&self, fid: &Fid<Self::Fid>) -> Result<Fcall> {
+        let lock_owners = std::mem::take(&mut *fid.aux.lock_owners.lock().await);
+        if !lock_owners.is_empty() {
+            let mut locks = self.locks.lock().await;
+            for (file, owner) in lock_owners {
+                locks.release(&file, &owner);
+            }
+        }
+
+        let xattr = { fid.aux.xattr.lock().await.take() };
+        if let Some(Xattr::Write {
+            name,
+            size,
+            flags,
+            value,
+        }) = xattr
+        {
+            // Attribute is set only once its whole value was written
+            if value.len() != size {
+                return Err(Error::No(EINVAL));
+            }
+            let path = { fid.aux.realpath.read().await.clone() };
+            self.set_xattr(&path, &name, value, flags).await?;
+        }
+
         Ok(Fcall::Rclunk)
     }
 
     async fn rstatfs(&self, _fid: &Fid<Self::Fid>) -> Result<Fcall> {
-        log::error!("rstatfs not implemented");
-        /*let path = {
-            let realpath = fid.aux.realpath.read().await;
-            realpath.clone()
-        };*/
-
-        //let fs = nix::sys::statvfs::statvfs(&path)?;
-        /* let fs = tokio::task::spawn_blocking(move || nix::sys::statvfs::statvfs(&path))
-                    .await
-                    .unwrap()?;
-        */
-        /*Ok(Fcall::Rstatfs {
-            statfs: From::from(fs),
-        })*/
-        return res!(io_err!(Other, std::format!("rstatfs not implemented")));
+        let root = self.realroot.clone();
+        let capacity = self.options.virtual_capacity;
+        let usage = self.usage.clone();
+
+        let statfs = tokio::task::spawn_blocking(move || match capacity {
+            Some(capacity) => virtual_statfs(&root, capacity, &mut usage.blocking_lock()),
+            None => host_statfs(&root),
+        })
+        .await
+        .map_err(|e| io_err!(Other, e))??;
+
+        Ok(Fcall::Rstatfs { statfs })
     }
 }
@@ -1,8 +1,14 @@
 use {
     crate::core::attributes_cache::VirtualAttributes,
+    crate::core::error::{errno::*, Error},
     crate::core::fcall::*,
     crate::core::lib_utils::Result,
-    std::{fs::Metadata, path::Path},
+    std::{
+        collections::HashSet,
+        fs::Metadata,
+        path::{Component, Path, PathBuf},
+        time::{Duration, Instant},
+    },
     tokio::fs,
 };
 
@@ -14,6 +20,244 @@ pub fn create_buffer(size: usize) -> Vec<u8> {
     buffer
 }
 
+/// Relative path `path` names below a root, `..` never goes above it like in a chroot
+pub fn path_beneath<P: AsRef<Path> + ?Sized>(path: &P) -> PathBuf {
+    let mut beneath = PathBuf::new();
+    for component in path.as_ref().components() {
+        match component {
+            Component::Normal(name) => beneath.push(name),
+            Component::ParentDir => {
+                beneath.pop();
+            }
+            Component::CurDir | Component::RootDir | Component::Prefix(_) => {}
+        }
+    }
+    beneath
+}
+
+/// Whether relative `path` resolved from a root goes above it, symlinks along it are not followed
+pub fn escapes_root<P: AsRef<Path> + ?Sized>(path: &P) -> bool {
+    let mut depth = 0usize;
+    for component in path.as_ref().components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::ParentDir => match depth.checked_sub(1) {
+                Some(parent) => depth = parent,
+                None => return true,
+            },
+            Component::CurDir => {}
+            Component::RootDir | Component::Prefix(_) => return true,
+        }
+    }
+    false
+}
+
+/// Device number of `major` and `minor` as encoded by Linux
+pub fn makedev(major: u32, minor: u32) -> u64 {
+    let (major, minor) = (major as u64, minor as u64);
+    ((major & 0xfffff000) << 32)
+        | ((major & 0x00000fff) << 8)
+        | ((minor & 0xffffff00) << 12)
+        | (minor & 0x000000ff)
+}
+
+/// Block size of capacity reported by `virtual_statfs`
+const VIRTUAL_BLOCK_SIZE: u32 = 4096;
+
+/// Time for which space used by an export is reported without scanning it again
+const DISK_USAGE_TTL: Duration = Duration::from_secs(5);
+
+/// Figures of the host filesystem holding `path`
+#[cfg(target_os = "linux")]
+pub fn host_statfs(path: &Path) -> Result<Statfs> {
+    Ok(nix::sys::statvfs::statvfs(path)
+        .map_err(std::io::Error::from)?
+        .into())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn host_statfs(_path: &Path) -> Result<Statfs> {
+    Err(crate::core::error::Error::No(
+        crate::core::error::errno::EOPNOTSUPP,
+    ))
+}
+
+/// Figures of export `root` sized `capacity` bytes, whatever the size of the host disk
+///
+/// Space used is what files of the export take, as last scanned into `usage`, space left
+/// never exceeds what is left on the host.
+pub fn virtual_statfs(root: &Path, capacity: u64, usage: &mut DiskUsage) -> Result<Statfs> {
+    let host = host_statfs(root).unwrap_or(Statfs {
+        typ: V9FS_MAGIC,
+        bsize: VIRTUAL_BLOCK_SIZE,
+        blocks: u64::MAX,
+        bfree: u64::MAX,
+        bavail: u64::MAX,
+        files: 0,
+        ffree: 0,
+        fsid: 0,
+        namelen: 255,
+    });
+
+    let host_free = host.bavail.saturating_mul(host.bsize as u64);
+    let free = capacity.saturating_sub(usage.get(root)).min(host_free);
+    let bsize = VIRTUAL_BLOCK_SIZE as u64;
+
+    Ok(Statfs {
+        bsize: VIRTUAL_BLOCK_SIZE,
+        blocks: capacity / bsize,
+        bfree: free / bsize,
+        bavail: free / bsize,
+        ..host
+    })
+}
+
+/// Space taken by files of an export, kept between requests so the export is not scanned for each
+#[derive(Debug, Default)]
+pub struct DiskUsage {
+    /// Bytes used and when they were counted
+    scanned: Option<(Instant, u64)>,
+}
+
+impl DiskUsage {
+    /// Bytes taken by files below `root`, scanned again once the last count is `DISK_USAGE_TTL` old
+    pub fn get(&mut self, root: &Path) -> u64 {
+        match self.scanned {
+            Some((at, used)) if at.elapsed() < DISK_USAGE_TTL => used,
+            _ => {
+                let used = disk_usage(root);
+                self.scanned = Some((Instant::now(), used));
+                used
+            }
+        }
+    }
+}
+
+/// Bytes taken by files below `root`, entries which can't be read are skipped
+///
+/// Files with several hard links are counted once.
+pub fn disk_usage(root: &Path) -> u64 {
+    let mut used = 0;
+    let mut linked = HashSet::new();
+    let mut dirs = vec![root.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let entries = match std::fs::read_dir(&dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            if metadata.is_dir() {
+                dirs.push(entry.path());
+            }
+            if let Some(id) = linked_file(&metadata) {
+                if !linked.insert(id) {
+                    continue;
+                }
+            }
+            used += allocated_size(&metadata);
+        }
+    }
+    used
+}
+
+#[cfg(target_os = "linux")]
+fn allocated_size(metadata: &Metadata) -> u64 {
+    use std::os::unix::fs::MetadataExt;
+
+    metadata.blocks() * 512
+}
+
+#[cfg(not(target_os = "linux"))]
+fn allocated_size(metadata: &Metadata) -> u64 {
+    metadata.len()
+}
+
+/// Device and inode of a file with more than one hard link
+#[cfg(target_os = "linux")]
+fn linked_file(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+
+    (!metadata.is_dir() && metadata.nlink() > 1).then(|| (metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn linked_file(_metadata: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Opens file `path` on the host as asked by 9P2000.L `flags`
+///
+/// Flags only meaningful to the client, as CLOEXEC, DIRECT, FASYNC and LARGEFILE, are ignored.
+/// NOATIME is dropped for files the host lets only their owner open with it.
+pub async fn open_file(path: &Path, flags: FileOpenMode) -> Result<fs::File> {
+    let mut options = fs::OpenOptions::new();
+    let access = flags & FileOpenMode::P9_DOTL_NOACCESS;
+    if access.is_empty() {
+        options.read(true);
+    } else if access == FileOpenMode::P9_DOTL_WRONLY {
+        options.write(true);
+    } else if access == FileOpenMode::P9_DOTL_RDWR {
+        options.read(true).write(true);
+    } else {
+        return Err(Error::No(EINVAL));
+    }
+
+    #[cfg(target_os = "linux")]
+    let host_flags = {
+        const HOST_FLAGS: [(FileOpenMode, libc::c_int); 11] = [
+            (FileOpenMode::P9_DOTL_CREATE, libc::O_CREAT),
+            (FileOpenMode::P9_DOTL_EXCL, libc::O_EXCL),
+            (FileOpenMode::P9_DOTL_NOCTTY, libc::O_NOCTTY),
+            (FileOpenMode::P9_DOTL_TRUNC, libc::O_TRUNC),
+            (FileOpenMode::P9_DOTL_APPEND, libc::O_APPEND),
+            (FileOpenMode::P9_DOTL_NONBLOCK, libc::O_NONBLOCK),
+            (FileOpenMode::P9_DOTL_DSYNC, libc::O_DSYNC),
+            (FileOpenMode::P9_DOTL_DIRECTORY, libc::O_DIRECTORY),
+            (FileOpenMode::P9_DOTL_NOFOLLOW, libc::O_NOFOLLOW),
+            (FileOpenMode::P9_DOTL_NOATIME, libc::O_NOATIME),
+            (FileOpenMode::P9_DOTL_SYNC, libc::O_SYNC),
+        ];
+        let host_flags = HOST_FLAGS
+            .iter()
+            .filter(|(flag, _)| flags.contains(*flag))
+            .fold(0, |host_flags, (_, host_flag)| host_flags | host_flag);
+        options.custom_flags(host_flags);
+        host_flags
+    };
+    #[cfg(not(target_os = "linux"))]
+    options
+        .create(flags.contains(FileOpenMode::P9_DOTL_CREATE))
+        .create_new(flags.contains(FileOpenMode::P9_DOTL_CREATE | FileOpenMode::P9_DOTL_EXCL))
+        .truncate(flags.contains(FileOpenMode::P9_DOTL_TRUNC))
+        .append(flags.contains(FileOpenMode::P9_DOTL_APPEND));
+
+    let opened = options.open(path).await;
+    // Only owners of files may keep their access time, files of others are opened as usual
+    #[cfg(target_os = "linux")]
+    let opened = match opened {
+        Err(e) if e.raw_os_error() == Some(libc::EPERM) && host_flags & libc::O_NOATIME != 0 => {
+            options.custom_flags(host_flags & !libc::O_NOATIME);
+            options.open(path).await
+        }
+        opened => opened,
+    };
+
+    opened.map_err(|e| {
+        // Errnos of open flags without `io::ErrorKind`
+        #[cfg(target_os = "linux")]
+        match e.raw_os_error() {
+            Some(libc::ELOOP) => return Error::No(ELOOP),
+            Some(libc::ENXIO) => return Error::No(ENXIO),
+            _ => {}
+        }
+        Error::Io(e)
+    })
+}
+
 pub async fn get_qid<T: AsRef<Path> + ?Sized>(path: &T, va: &VirtualAttributes) -> Result<Qid> {
     Ok(qid_from_attr(
         &fs::symlink_metadata(path.as_ref()).await?,
@@ -0,0 +1,94 @@
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+
+use crate::core::error::{errno::*, Error};
+use crate::core::lib_utils::Result;
+
+/// Error of the last xattr call, errnos without `io::ErrorKind` are kept as they are
+fn last_error() -> Error {
+    let e = io::Error::last_os_error();
+    match e.raw_os_error() {
+        Some(libc::ENODATA) => Error::No(ENODATA),
+        Some(libc::EOPNOTSUPP) => Error::No(EOPNOTSUPP),
+        Some(libc::E2BIG) => Error::No(E2BIG),
+        Some(libc::ERANGE) => Error::No(ERANGE),
+        _ => Error::Io(e),
+    }
+}
+
+fn c_path(path: &Path) -> Result<CString> {
+    CString::new(path.as_os_str().as_bytes())
+        .map_err(|_| io_err!(InvalidInput, "Path contains nul byte").into())
+}
+
+fn c_name(name: &str) -> Result<CString> {
+    CString::new(name).map_err(|_| io_err!(InvalidInput, "Name contains nul byte").into())
+}
+
+/// Calls `fetch` with a buffer large enough for what `fetch` returns when given none,
+/// again if it grew in between
+fn fetch_sized<F>(fetch: F) -> Result<Vec<u8>>
+where
+    F: Fn(*mut libc::c_void, usize) -> libc::ssize_t,
+{
+    loop {
+        let size = fetch(ptr::null_mut(), 0);
+        if size < 0 {
+            return Err(last_error());
+        }
+
+        let mut buf = vec![0u8; size as usize];
+        let read = fetch(buf.as_mut_ptr() as *mut libc::c_void, buf.len());
+        if read >= 0 {
+            buf.truncate(read as usize);
+            return Ok(buf);
+        }
+        if io::Error::last_os_error().raw_os_error() != Some(libc::ERANGE) {
+            return Err(last_error());
+        }
+    }
+}
+
+/// Value of attribute `name` of `path`, symlinks are not followed
+pub fn get(path: &Path, name: &str) -> Result<Vec<u8>> {
+    let (path, name) = (c_path(path)?, c_name(name)?);
+    fetch_sized(|buf, size| unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), buf, size) })
+}
+
+/// Names of attributes of `path`, each one terminated by nul
+pub fn list(path: &Path) -> Result<Vec<u8>> {
+    let path = c_path(path)?;
+    fetch_sized(|buf, size| unsafe {
+        libc::llistxattr(path.as_ptr(), buf as *mut libc::c_char, size)
+    })
+}
+
+/// Sets attribute `name` of `path`, `flags` are `XATTR_CREATE` or `XATTR_REPLACE`
+pub fn set(path: &Path, name: &str, value: &[u8], flags: u32) -> Result<()> {
+    let (path, name) = (c_path(path)?, c_name(name)?);
+    let res = unsafe {
+        libc::lsetxattr(
+            path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const libc::c_void,
+            value.len(),
+            flags as libc::c_int,
+        )
+    };
+    match res {
+        0 => Ok(()),
+        _ => Err(last_error()),
+    }
+}
+
+/// Removes attribute `name` of `path`
+pub fn remove(path: &Path, name: &str) -> Result<()> {
+    let (path, name) = (c_path(path)?, c_name(name)?);
+    match unsafe { libc::lremovexattr(path.as_ptr(), name.as_ptr()) } {
+        0 => Ok(()),
+        _ => Err(last_error()),
+    }
+}
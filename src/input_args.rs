@@ -1,5 +1,11 @@
+use std::num::ParseIntError;
+use std::path::PathBuf;
 use structopt::StructOpt;
 
+fn parse_octal(mode: &str) -> Result<u32, ParseIntError> {
+    u32::from_str_radix(mode.trim_start_matches("0o"), 8)
+}
+
 #[derive(StructOpt)]
 #[structopt(about = "Rust crossplatform 9p server")]
 pub struct ServerOptions {
@@ -7,18 +13,96 @@ pub struct ServerOptions {
         short = "a",
         long = "network-address",
         default_value = "127.0.0.1:7878",
-        help = "Address to listen on: 127.0.0.1:7878"
+        help = "Address to listen on: 127.0.0.1:7878 for tcp, socket path for unix and vhost-user, cid:port for vsock, unused for stdio"
     )]
     pub network_address: String,
 
     #[structopt(
         short = "p",
         long = "network-protocol",
-        help = "For now only tcp/ip server is supported",
+        help = "Transport to serve on: tcp, unix, vsock, vhost-user or stdio",
         default_value = "tcp"
     )]
     pub network_protocol: String,
 
+    #[structopt(
+        long = "socket-mode",
+        parse(try_from_str = parse_octal),
+        help = "Permissions of the unix socket file in octal: 660"
+    )]
+    pub socket_mode: Option<u32>,
+
+    #[structopt(
+        long = "mount-tag",
+        help = "Mount tag of the vhost-user virtio-9p device"
+    )]
+    pub mount_tag: Option<String>,
+
+    #[structopt(
+        long = "max-msize",
+        help = "Largest message size agreed to with clients in bytes [default: 1048576]"
+    )]
+    pub max_msize: Option<u32>,
+
+    #[structopt(
+        long = "max-requests-per-connection",
+        help = "Requests served at once on one connection, further messages are not read until one finishes [default: 64]"
+    )]
+    pub max_requests_per_connection: Option<usize>,
+
+    #[structopt(
+        long = "max-requests",
+        help = "Requests served at once across all connections, unlimited by default"
+    )]
+    pub max_requests: Option<usize>,
+
+    #[structopt(
+        long = "max-connections",
+        help = "Connections served at once, further clients wait until one disconnects; unlimited by default"
+    )]
+    pub max_connections: Option<usize>,
+
+    #[structopt(
+        long = "read-only",
+        help = "Refuse requests which would modify the exported directory"
+    )]
+    pub read_only: bool,
+
+    #[structopt(
+        long = "emulate-devices",
+        help = "Keep device nodes and sockets created by clients as empty files with virtual attributes"
+    )]
+    pub emulate_devices: bool,
+
+    #[structopt(
+        long = "virtual-capacity",
+        help = "Size of the export in bytes reported to clients instead of the host disk size"
+    )]
+    pub virtual_capacity: Option<u64>,
+
+    #[structopt(
+        long = "virtual-xattrs",
+        help = "Keep extended attributes in memory of the server instead of the exported files"
+    )]
+    pub virtual_xattrs: bool,
+
+    #[structopt(
+        long = "tls-cert",
+        requires = "tls-key",
+        help = "PEM certificate chain, serves tcp over TLS when set"
+    )]
+    pub tls_cert: Option<PathBuf>,
+
+    #[structopt(long = "tls-key", help = "PEM private key of the TLS certificate")]
+    pub tls_key: Option<PathBuf>,
+
+    #[structopt(
+        long = "tls-client-ca",
+        requires = "tls-cert",
+        help = "PEM CA certificates, TLS clients must present certificate signed by one of them"
+    )]
+    pub tls_client_ca: Option<PathBuf>,
+
     #[structopt(
         short = "m",
         long = "mount-point",
@@ -11,40 +11,382 @@
 //! rs9p is a core to develop 9P2000.L virtual filesystems in Rust.
 //! All you have to do is to implement `Filesystem` trait.
 
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
 
-use crate::core::srv::srv_async_inproc;
-use crate::{core::attributes_cache::VirtualAttributesProvider, implementation::unpfs::Unpfs};
-use tokio::io::DuplexStream;
-use tokio::sync::Mutex;
+use crate::core::attributes_cache::{AttributeDefaults, VirtualAttributesProvider};
+use crate::core::error;
+use crate::core::fcall::Msg;
+use crate::core::lib_utils::Result;
+use crate::core::srv::{
+    dispatch, dispatch_msgs, Connections, Filesystem, Limits, Msize, ServerConfig, Shutdown,
+};
+use crate::implementation::unpfs::{Unpfs, UnpfsOptions};
+use futures::channel::mpsc;
+use futures::{Future, Sink, SinkExt, Stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::sync::{watch, Mutex};
 
 #[macro_use]
 pub mod core;
 pub mod implementation;
 
-pub struct InprocServer {
-    filesystem: Unpfs,
+/// 9P server living in the same process as its clients, which talk to it over in-memory
+/// pipes or pass messages over channels
+pub struct InprocServer<Fs = Unpfs> {
+    filesystem: Fs,
+    limits: Limits,
+    shutdown: watch::Sender<Option<Duration>>,
+    connections: std::sync::Mutex<Connections>,
+    next_client: AtomicU64,
+    attached: Arc<AtomicUsize>,
 }
 
 impl InprocServer {
+    /// Exports `mount_point` with `Unpfs` and default settings
     pub fn new(mount_point: &str) -> Self {
+        Self::builder(mount_point).build()
+    }
+
+    /// Configures server exporting `root` with `Unpfs`
+    pub fn builder(root: impl Into<PathBuf>) -> InprocServerBuilder {
+        InprocServerBuilder {
+            root: root.into(),
+            config: ServerConfig::default(),
+            attributes: AttributeDefaults::default(),
+            options: UnpfsOptions::default(),
+        }
+    }
+}
+
+impl<Fs> InprocServer<Fs>
+where
+    Fs: 'static + Filesystem + Send + Sync + Clone,
+{
+    /// Serves `filesystem`, every client gets a clone of it
+    ///
+    /// Message size, request limits and read-only mode of `config` apply,
+    /// transport settings are ignored.
+    pub fn with_config(filesystem: Fs, config: &ServerConfig) -> Self {
+        let (shutdown, signal) = Shutdown::channel();
+
         Self {
-            filesystem: Unpfs {
-                realroot: mount_point.into(),
-                vap: Arc::new(Mutex::new(VirtualAttributesProvider::new())),
-            },
+            filesystem,
+            limits: Limits::new(config),
+            shutdown,
+            connections: std::sync::Mutex::new(Connections::new(signal)),
+            next_client: AtomicU64::new(0),
+            attached: Default::default(),
         }
     }
 
     /// Attaches to the 9p server,
-    /// Returns client stream to write requests, and read responses
-    /// To detach simply drop the stream
-    pub fn attach_client(&self, max_packet_size: usize) -> DuplexStream {
+    /// Returns client end of the connection to write requests, and read responses
+    pub fn attach_client(&self, max_packet_size: usize) -> InprocClient {
         let (client, server) = tokio::io::duplex(max_packet_size);
+        let (id, served) = self.serve(|filesystem, limits, shutdown| async move {
+            let (server_rx, server_tx) = tokio::io::split(server);
+            dispatch(filesystem, server_rx, server_tx, &limits, shutdown).await
+        });
+
+        InprocClient {
+            stream: client,
+            id,
+            served,
+        }
+    }
+
+    /// Attaches to the 9p server without encoding messages,
+    /// Returns client end of the channels to send requests, and receive responses
+    ///
+    /// Up to `capacity` messages are buffered each way. Payloads are shared with
+    /// the server, not copied.
+    pub fn attach_channel(&self, capacity: usize) -> InprocChannel {
+        let (requests, server_rx) = mpsc::channel(capacity);
+        let (server_tx, responses) = mpsc::channel(capacity);
+        let (id, served) = self.serve(|filesystem, limits, shutdown| {
+            let msize = Msize::new(limits.max_msize);
+            dispatch_msgs(
+                filesystem,
+                server_rx.map(Ok),
+                server_tx.sink_map_err(|_| disconnected()),
+                msize,
+                limits,
+                shutdown,
+            )
+        });
+
+        InprocChannel {
+            requests,
+            responses,
+            id,
+            served,
+        }
+    }
+
+    /// Spawns task serving a new client, returns its id and the receiver telling when it ends
+    fn serve<F, Fut>(&self, serve: F) -> (u64, watch::Receiver<bool>)
+    where
+        F: FnOnce(Fs, Limits, Shutdown) -> Fut,
+        Fut: 'static + Future<Output = Result<()>> + Send,
+    {
+        let (served, receiver) = watch::channel(false);
+        let served = Served {
+            sender: served,
+            attached: self.attached.clone(),
+        };
+        self.attached.fetch_add(1, Ordering::SeqCst);
+
+        let filesystem = self.filesystem.clone();
+        let limits = self.limits.clone();
+        self.connections.lock().unwrap().spawn(|shutdown| {
+            let connection = serve(filesystem, limits, shutdown);
+            async move {
+                let _served = served;
+                connection.await
+            }
+        });
+
+        (self.next_client.fetch_add(1, Ordering::SeqCst), receiver)
+    }
+
+    /// Number of clients the server is still serving
+    pub fn attached_clients(&self) -> usize {
+        self.attached.load(Ordering::SeqCst)
+    }
+
+    /// Detaches all clients, see `ServerHandle::shutdown`
+    pub async fn shutdown(self, timeout: Duration) -> Vec<Result<()>> {
+        let _ = self.shutdown.send(Some(timeout));
+        self.connections.into_inner().unwrap().finish().await
+    }
+}
+
+/// Configures `InprocServer` exporting a directory with `Unpfs`, see `InprocServer::builder`
+#[derive(Clone, Debug)]
+pub struct InprocServerBuilder {
+    root: PathBuf,
+    config: ServerConfig,
+    attributes: AttributeDefaults,
+    options: UnpfsOptions,
+}
+
+impl InprocServerBuilder {
+    /// Largest msize agreed to in Tversion, `DEFAULT_MAX_MSIZE` if not set
+    pub fn max_msize(mut self, max_msize: u32) -> Self {
+        self.config.max_msize = Some(max_msize);
+        self
+    }
+
+    /// Refuses requests which would modify the export with `EROFS`
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.config.read_only = read_only;
+        self
+    }
+
+    /// Owner and permission bits given to files the first time they are seen
+    pub fn attribute_defaults(mut self, attributes: AttributeDefaults) -> Self {
+        self.attributes = attributes;
+        self
+    }
+
+    /// Keeps device nodes and sockets made by clients only in virtual attributes
+    pub fn emulate_devices(mut self, emulate_devices: bool) -> Self {
+        self.options.emulate_devices = emulate_devices;
+        self
+    }
+
+    /// Reports export sized `capacity` bytes to `Tstatfs` rather than the host disk
+    pub fn virtual_capacity(mut self, capacity: u64) -> Self {
+        self.options.virtual_capacity = Some(capacity);
+        self
+    }
+
+    /// Keeps extended attributes only in virtual attributes, not on the host
+    pub fn virtual_xattrs(mut self, virtual_xattrs: bool) -> Self {
+        self.options.virtual_xattrs = virtual_xattrs;
+        self
+    }
+
+    pub fn build(self) -> InprocServer {
+        let filesystem = Unpfs {
+            realroot: self.root,
+            vap: Arc::new(Mutex::new(VirtualAttributesProvider::with_defaults(
+                self.attributes,
+            ))),
+            options: self.options,
+            locks: Default::default(),
+            usage: Default::default(),
+        };
+        InprocServer::with_config(filesystem, &self.config)
+    }
+}
+
+/// Marks the connection as no longer served once its task ends, however it ends
+struct Served {
+    sender: watch::Sender<bool>,
+    attached: Arc<AtomicUsize>,
+}
+
+impl Drop for Served {
+    fn drop(&mut self) {
+        self.attached.fetch_sub(1, Ordering::SeqCst);
+        let _ = self.sender.send(true);
+    }
+}
+
+/// Waits until the server stops serving the connection
+async fn wait_served(mut served: watch::Receiver<bool>) {
+    while !*served.borrow_and_update() {
+        if served.changed().await.is_err() {
+            break;
+        }
+    }
+}
+
+fn disconnected() -> error::Error {
+    io_err!(BrokenPipe, "Inproc connection is closed").into()
+}
+
+/// Client end of a connection to `InprocServer`
+///
+/// Requests are written to it and responses read from it. Dropping it detaches
+/// the client without waiting for the server.
+pub struct InprocClient {
+    stream: DuplexStream,
+    id: u64,
+    served: watch::Receiver<bool>,
+}
+
+impl InprocClient {
+    /// Number of the connection, unique within its server
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Whether the server is still serving the connection
+    pub fn is_attached(&self) -> bool {
+        !*self.served.borrow()
+    }
+
+    /// Closes the connection and waits until the server stops serving it
+    ///
+    /// Requests in flight are finished and fids left open are clunked by then.
+    pub async fn detach(self) {
+        let Self { stream, served, .. } = self;
+        drop(stream);
+        wait_served(served).await
+    }
+}
+
+impl AsyncRead for InprocClient {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for InprocClient {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().stream).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().stream).poll_shutdown(cx)
+    }
+}
+
+/// Client end of a message channel to `InprocServer`, see `InprocServer::attach_channel`
+///
+/// Requests are sent to it as `Msg` values and responses received from it,
+/// e.g. by `P9Client::connect_msgs`. Dropping it detaches the client without
+/// waiting for the server.
+pub struct InprocChannel {
+    requests: mpsc::Sender<Msg>,
+    responses: mpsc::Receiver<Msg>,
+    id: u64,
+    served: watch::Receiver<bool>,
+}
+
+impl InprocChannel {
+    /// Number of the connection, unique within its server
+    pub fn id(&self) -> u64 {
+        self.id
+    }
 
-        tokio::spawn(srv_async_inproc(self.filesystem.clone(), server));
+    /// Whether the server is still serving the connection
+    pub fn is_attached(&self) -> bool {
+        !*self.served.borrow()
+    }
+
+    /// Closes the channels and waits until the server stops serving them, see `InprocClient::detach`
+    pub async fn detach(self) {
+        let Self {
+            requests,
+            responses,
+            served,
+            ..
+        } = self;
+        drop(requests);
+        drop(responses);
+        wait_served(served).await
+    }
+}
+
+impl Sink<Msg> for InprocChannel {
+    type Error = error::Error;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.get_mut()
+            .requests
+            .poll_ready(cx)
+            .map_err(|_| disconnected())
+    }
+
+    fn start_send(self: Pin<&mut Self>, msg: Msg) -> Result<()> {
+        self.get_mut()
+            .requests
+            .start_send(msg)
+            .map_err(|_| disconnected())
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().requests)
+            .poll_flush(cx)
+            .map_err(|_| disconnected())
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().requests)
+            .poll_close(cx)
+            .map_err(|_| disconnected())
+    }
+}
 
-        client
+impl Stream for InprocChannel {
+    type Item = Result<Msg>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Result<Msg>>> {
+        self.get_mut()
+            .responses
+            .poll_next_unpin(cx)
+            .map(|msg| msg.map(Ok))
     }
 }
 
@@ -54,7 +396,7 @@ mod tests {
     use ::core::panic;
     use std::sync::Once;
 
-    use bytes::{Buf, BufMut};
+    use bytes::BufMut;
     use futures::{Future, SinkExt};
     use log::LevelFilter;
     use log4rs::{
@@ -62,70 +404,58 @@ mod tests {
         config::{Appender, Root},
         Config,
     };
-    use tokio::io::{ReadHalf, WriteHalf};
+    use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf};
     use tokio_stream::StreamExt;
-    use tokio_util::codec::{FramedRead, FramedWrite, LengthDelimitedCodec};
+    use tokio_util::codec::{Encoder, FramedRead, FramedWrite};
 
     use crate::core::{
-        fcall::{Fcall, Msg, QidType, NOTAG},
+        codec::P9Codec,
+        fcall::{Data, Fcall, Msg, QidType, NOFID, NOTAG},
         serialize,
+        srv::{srv_spawn, Fid, Filesystem, ServerConfig, DEFAULT_MAX_MSIZE},
     };
 
     use super::*;
 
     /// Creates high level communication adapter for the 9P FS
-    struct FSAdapter {
-        msg_reader: FramedRead<ReadHalf<DuplexStream>, LengthDelimitedCodec>,
-        msg_writer: FramedWrite<WriteHalf<DuplexStream>, LengthDelimitedCodec>,
+    struct FSAdapter<S = InprocClient> {
+        msg_reader: FramedRead<ReadHalf<S>, P9Codec>,
+        msg_writer: FramedWrite<WriteHalf<S>, P9Codec>,
     }
 
-    impl FSAdapter {
+    impl<S: AsyncRead + AsyncWrite> FSAdapter<S> {
         async fn send(&mut self, msg: &Msg) -> anyhow::Result<()> {
-            let mut writer = bytes::BytesMut::with_capacity(65535).writer();
-            serialize::write_msg(&mut writer, &msg).unwrap();
-
             self.msg_writer
-                .send(writer.into_inner().freeze())
+                .send(msg.clone())
                 .await
                 .map_err(|e| anyhow::anyhow!("Failed sending the request {e}"))
         }
 
         async fn receive(&mut self) -> anyhow::Result<Msg> {
-            if let Some(bytes) = self.msg_reader.next().await {
-                let bytes = bytes?;
-                return serialize::read_msg(&mut bytes.reader())
-                    .map_err(|e| anyhow::anyhow!("Failed parsing the message: {e}"));
+            if let Some(msg) = self.msg_reader.next().await {
+                return msg.map_err(|e| anyhow::anyhow!("Failed parsing the message: {e}"));
             }
 
             Err(anyhow::anyhow!("Reader stream is broken"))
         }
 
-        fn new(server: &InprocServer) -> Self {
-            let client = server.attach_client(16384);
-
-            let (reader, writer) = tokio::io::split(client);
-
-            let framedread = LengthDelimitedCodec::builder()
-                .length_field_offset(0)
-                .length_field_length(4)
-                .length_adjustment(-4)
-                .little_endian()
-                .new_read(reader);
-
-            let framedwrite = LengthDelimitedCodec::builder()
-                .length_field_offset(0)
-                .length_field_length(4)
-                .length_adjustment(-4)
-                .little_endian()
-                .new_write(writer);
+        fn from_stream(stream: S) -> Self {
+            let (reader, writer) = tokio::io::split(stream);
+            let codec = P9Codec::new(DEFAULT_MAX_MSIZE);
 
             Self {
-                msg_reader: framedread,
-                msg_writer: framedwrite,
+                msg_reader: FramedRead::new(reader, codec.clone()),
+                msg_writer: FramedWrite::new(writer, codec),
             }
         }
     }
 
+    impl FSAdapter {
+        fn new(server: &InprocServer) -> Self {
+            Self::from_stream(server.attach_client(16384))
+        }
+    }
+
     /// Boilerplate needed for setup testcases
     fn setup() {
         static START: Once = Once::new();
@@ -147,6 +477,20 @@ mod tests {
         // teardown()
     }
 
+    /// Errno from Rlerror, also those without `io::ErrorKind` which `Error::errno` loses
+    fn raw_errno(e: crate::core::error::Error) -> Option<i32> {
+        match e {
+            crate::core::error::Error::No(e) => Some(e as i32),
+            crate::core::error::Error::Io(e) => e.raw_os_error(),
+        }
+    }
+
+    /// Body of the response to request `body` sent with `tag`
+    async fn call(fs_adapter: &mut FSAdapter, tag: u16, body: Fcall) -> Fcall {
+        fs_adapter.send(&Msg { tag, body }).await.unwrap();
+        fs_adapter.receive().await.unwrap().body
+    }
+
     #[tokio::test]
     /// Create Inproc server, use returned endpoint to send "version" request, expect correct response
     async fn can_connect_to_the_inproc_server() {
@@ -161,7 +505,7 @@ mod tests {
             let request = Msg {
                 tag: NOTAG,
                 body: Fcall::Tversion {
-                    msize: 8,
+                    msize: 8192,
                     version: "9P2000.L".to_string(),
                 },
             };
@@ -175,7 +519,7 @@ mod tests {
                 Msg {
                     tag: NOTAG,
                     body: Fcall::Rversion {
-                        msize: 8,
+                        msize: 8192,
                         version: "9P2000.L".to_string()
                     }
                 }
@@ -196,7 +540,7 @@ mod tests {
             let request = Msg {
                 tag: NOTAG,
                 body: Fcall::Tversion {
-                    msize: 8,
+                    msize: 8192,
                     version: "9P2000.L".to_string(),
                 },
             };
@@ -204,7 +548,7 @@ mod tests {
             let expected_response = Msg {
                 tag: NOTAG,
                 body: Fcall::Rversion {
-                    msize: 8,
+                    msize: 8192,
                     version: "9P2000.L".to_string(),
                 },
             };
@@ -230,6 +574,74 @@ mod tests {
         .await
     }
 
+    #[tokio::test]
+    /// Msize below the minimum is refused, reads never exceed the msize agreed instead
+    async fn small_msize_is_refused() {
+        use crate::core::error::errno::EINVAL;
+        use crate::core::fcall::IOHDRSZ;
+        use crate::core::srv::MIN_MSIZE;
+
+        run_test(async {
+            let temp_dir = tempdir::TempDir::new("small_msize_is_refused").unwrap();
+            std::fs::write(
+                temp_dir.path().join("file"),
+                vec![1; 3 * MIN_MSIZE as usize],
+            )
+            .unwrap();
+            let srv = InprocServer::builder(temp_dir.path()).build();
+            let mut fs_adapter = FSAdapter::new(&srv);
+
+            let version = |msize| Fcall::Tversion {
+                msize,
+                version: "9P2000.L".to_string(),
+            };
+            assert_eq!(
+                call(&mut fs_adapter, NOTAG, version(512)).await,
+                Fcall::Rlerror {
+                    ecode: EINVAL as u32
+                }
+            );
+            assert_eq!(
+                call(&mut fs_adapter, NOTAG, version(MIN_MSIZE)).await,
+                Fcall::Rversion {
+                    msize: MIN_MSIZE,
+                    version: "9P2000.L".to_string(),
+                }
+            );
+
+            let attach = Fcall::Tattach {
+                fid: 1,
+                afid: NOFID,
+                uname: "".to_string(),
+                aname: "".to_string(),
+                n_uname: 0,
+            };
+            call(&mut fs_adapter, 1, attach).await;
+            let walk = Fcall::Twalk {
+                fid: 1,
+                newfid: 2,
+                wnames: vec!["file".to_string()],
+            };
+            call(&mut fs_adapter, 1, walk).await;
+            let iounit = match call(&mut fs_adapter, 1, Fcall::Tlopen { fid: 2, flags: 0 }).await {
+                Fcall::Rlopen { iounit, .. } => iounit,
+                other => panic!("Invalid response {other:?}"),
+            };
+            assert_eq!(iounit, MIN_MSIZE - IOHDRSZ);
+
+            let read = Fcall::Tread {
+                fid: 2,
+                offset: 0,
+                count: 2 * MIN_MSIZE,
+            };
+            match call(&mut fs_adapter, 1, read).await {
+                Fcall::Rread { data } => assert_eq!(data.0.len() as u32, iounit),
+                other => panic!("Invalid response {other:?}"),
+            }
+        })
+        .await
+    }
+
     #[tokio::test]
     /// Do simple operation on the server
     async fn can_create_a_file() {
@@ -246,7 +658,7 @@ mod tests {
                 .send(&Msg {
                     tag: NOTAG,
                     body: Fcall::Tversion {
-                        msize: 8,
+                        msize: 8192,
                         version: "9P2000.L".to_string(),
                     },
                 })
@@ -258,7 +670,7 @@ mod tests {
                 Msg {
                     tag: NOTAG,
                     body: Fcall::Rversion {
-                        msize: 8,
+                        msize: 8192,
                         version: "9P2000.L".to_string(),
                     },
                 }
@@ -347,4 +759,2785 @@ mod tests {
         })
         .await
     }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    /// Serve over unix domain socket, replacing stale socket file and applying permissions
+    async fn can_connect_over_unix_socket() {
+        use std::os::unix::fs::PermissionsExt;
+
+        use crate::core::srv::{srv_spawn, ServerConfig};
+
+        run_test(async {
+            let temp_dir = tempdir::TempDir::new("can_connect_over_unix_socket").unwrap();
+            let export_dir = temp_dir.path().join("export");
+            std::fs::create_dir(&export_dir).unwrap();
+            let socket_path = temp_dir.path().join("9p.sock");
+
+            // Socket left behind by a server which is gone
+            drop(std::os::unix::net::UnixListener::bind(&socket_path).unwrap());
+
+            let filesystem = Unpfs {
+                realroot: export_dir,
+                vap: Arc::new(Mutex::new(VirtualAttributesProvider::new())),
+                options: Default::default(),
+                locks: Default::default(),
+                usage: Default::default(),
+            };
+            let config = ServerConfig {
+                socket_mode: Some(0o600),
+                ..Default::default()
+            };
+            let server = srv_spawn(filesystem, "unix", socket_path.to_str().unwrap(), &config);
+
+            let stream = loop {
+                match tokio::net::UnixStream::connect(&socket_path).await {
+                    Ok(stream) => break stream,
+                    Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+                }
+            };
+
+            let mode = std::fs::metadata(&socket_path)
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600);
+            let mut names: Vec<_> = std::fs::read_dir(temp_dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().file_name())
+                .collect();
+            names.sort();
+            assert_eq!(names, ["9p.sock", "export"]);
+
+            let mut fs_adapter = FSAdapter::from_stream(stream);
+
+            let request = Msg {
+                tag: NOTAG,
+                body: Fcall::Tversion {
+                    msize: 8192,
+                    version: "9P2000.L".to_string(),
+                },
+            };
+
+            fs_adapter.send(&request).await.unwrap();
+
+            assert_eq!(
+                fs_adapter.receive().await.unwrap(),
+                Msg {
+                    tag: NOTAG,
+                    body: Fcall::Rversion {
+                        msize: 8192,
+                        version: "9P2000.L".to_string()
+                    }
+                }
+            );
+
+            drop(fs_adapter);
+            server.shutdown(Duration::from_secs(1)).await.unwrap();
+            assert!(!socket_path.exists());
+        })
+        .await
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    /// Socket path taken by a live server or a regular file is never removed
+    async fn unix_socket_does_not_replace_used_path() {
+        use crate::core::srv::srv_async;
+
+        run_test(async {
+            let temp_dir = tempdir::TempDir::new("unix_socket_does_not_replace_used_path").unwrap();
+            let temp_dir_string = temp_dir.path().as_os_str().to_str().unwrap();
+            let srv = InprocServer::new(temp_dir_string);
+
+            let file_path = temp_dir.path().join("not_a_socket");
+            std::fs::write(&file_path, b"data").unwrap();
+            let res = srv_async(srv.filesystem.clone(), "unix", file_path.to_str().unwrap()).await;
+            assert!(res.is_err());
+            assert_eq!(std::fs::read(&file_path).unwrap(), b"data");
+
+            let socket_path = temp_dir.path().join("live.sock");
+            let _live = std::os::unix::net::UnixListener::bind(&socket_path).unwrap();
+            let res = srv_async(
+                srv.filesystem.clone(),
+                "unix",
+                socket_path.to_str().unwrap(),
+            )
+            .await;
+            assert!(res.is_err());
+            assert!(socket_path.exists());
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Requests sent just before the client closes its side are still answered
+    async fn replies_are_sent_after_client_closes_stream() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        run_test(async {
+            let temp_dir =
+                tempdir::TempDir::new("replies_are_sent_after_client_closes_stream").unwrap();
+
+            let temp_dir_string = temp_dir.path().as_os_str().to_str().unwrap();
+            let srv = InprocServer::new(temp_dir_string);
+
+            let mut client = srv.attach_client(16384);
+
+            let mut request = bytes::BytesMut::new().writer();
+            serialize::write_msg(
+                &mut request,
+                &Msg {
+                    tag: NOTAG,
+                    body: Fcall::Tversion {
+                        msize: 8192,
+                        version: "9P2000.L".to_string(),
+                    },
+                },
+            )
+            .unwrap();
+
+            let request = request.into_inner();
+            client.write_u32_le(request.len() as u32 + 4).await.unwrap();
+            client.write_all(&request).await.unwrap();
+            client.shutdown().await.unwrap();
+
+            let mut response = Vec::new();
+            client.read_to_end(&mut response).await.unwrap();
+
+            assert_eq!(
+                serialize::read_msg(&mut &response[4..]).unwrap(),
+                Msg {
+                    tag: NOTAG,
+                    body: Fcall::Rversion {
+                        msize: 8192,
+                        version: "9P2000.L".to_string()
+                    }
+                }
+            );
+        })
+        .await
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    /// Stdio session over blocking pipe handles, as stdin and stdout are, answers every
+    /// request sent before stdin is closed and then ends
+    async fn stdio_session_answers_until_stdin_closes() {
+        use std::os::unix::io::FromRawFd;
+
+        use crate::core::srv::{serve_stdio, Limits, Shutdown};
+
+        /// Read and write ends of a new pipe
+        fn pipe() -> (tokio::fs::File, tokio::fs::File) {
+            let (read, write) = nix::unistd::pipe().unwrap();
+            unsafe {
+                (
+                    tokio::fs::File::from_std(std::fs::File::from_raw_fd(read)),
+                    tokio::fs::File::from_std(std::fs::File::from_raw_fd(write)),
+                )
+            }
+        }
+
+        run_test(async {
+            let temp_dir =
+                tempdir::TempDir::new("stdio_session_answers_until_stdin_closes").unwrap();
+            let filesystem = Unpfs {
+                realroot: temp_dir.path().to_path_buf(),
+                vap: Arc::new(Mutex::new(VirtualAttributesProvider::new())),
+                options: Default::default(),
+                locks: Default::default(),
+                usage: Default::default(),
+            };
+
+            let (stdin, client_stdin) = pipe();
+            let (client_stdout, stdout) = pipe();
+            let session = tokio::spawn(async move {
+                let limits = Limits::default();
+                serve_stdio(filesystem, stdin, stdout, &limits, Shutdown::never()).await
+            });
+
+            let mut requests = FramedWrite::new(client_stdin, P9Codec::new(DEFAULT_MAX_MSIZE));
+            requests
+                .send(Msg {
+                    tag: NOTAG,
+                    body: Fcall::Tversion {
+                        msize: 16384,
+                        version: "9P2000.L".to_string(),
+                    },
+                })
+                .await
+                .unwrap();
+            requests
+                .send(Msg {
+                    tag: 1,
+                    body: Fcall::Tattach {
+                        fid: 0,
+                        afid: NOFID,
+                        uname: "".to_string(),
+                        aname: "".to_string(),
+                        n_uname: 0,
+                    },
+                })
+                .await
+                .unwrap();
+            drop(requests);
+
+            let responses: Vec<Msg> =
+                FramedRead::new(client_stdout, P9Codec::new(DEFAULT_MAX_MSIZE))
+                    .map(|msg| msg.unwrap())
+                    .collect()
+                    .await;
+            assert_eq!(responses.len(), 2);
+            assert!(matches!(
+                responses[0].body,
+                Fcall::Rversion { msize: 16384, .. }
+            ));
+            assert!(matches!(
+                responses[1],
+                Msg {
+                    tag: 1,
+                    body: Fcall::Rattach { .. }
+                }
+            ));
+
+            session.await.unwrap().unwrap();
+        })
+        .await
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    /// Serve over vsock loopback, skipped when host has no `vsock_loopback` transport
+    async fn can_connect_over_vsock_loopback() {
+        use crate::core::srv::srv_async;
+        use crate::core::vsock::{VsockListener, VsockStream, VMADDR_CID_LOCAL};
+
+        run_test(async {
+            const PORT: u32 = 17878;
+
+            if let Err(e) = VsockListener::bind(VMADDR_CID_LOCAL, PORT) {
+                log::warn!("Skipping, vsock loopback is not available: {}", e);
+                return;
+            }
+
+            let temp_dir = tempdir::TempDir::new("can_connect_over_vsock_loopback").unwrap();
+            let temp_dir_string = temp_dir.path().as_os_str().to_str().unwrap();
+            let srv = InprocServer::new(temp_dir_string);
+
+            let address = format!("{}:{}", VMADDR_CID_LOCAL, PORT);
+            let filesystem = srv.filesystem.clone();
+            tokio::spawn(async move { srv_async(filesystem, "vsock", &address).await });
+
+            let stream = loop {
+                match VsockStream::connect(VMADDR_CID_LOCAL, PORT).await {
+                    Ok(stream) => break stream,
+                    Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+                }
+            };
+
+            let mut fs_adapter = FSAdapter::from_stream(stream);
+
+            fs_adapter
+                .send(&Msg {
+                    tag: NOTAG,
+                    body: Fcall::Tversion {
+                        msize: 8192,
+                        version: "9P2000.L".to_string(),
+                    },
+                })
+                .await
+                .unwrap();
+
+            assert_eq!(
+                fs_adapter.receive().await.unwrap(),
+                Msg {
+                    tag: NOTAG,
+                    body: Fcall::Rversion {
+                        msize: 8192,
+                        version: "9P2000.L".to_string()
+                    }
+                }
+            );
+        })
+        .await
+    }
+
+    /// Reserves a free loopback port for a server under test
+    fn free_tcp_address() -> String {
+        std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .to_string()
+    }
+
+    async fn connect_tcp(address: &str) -> tokio::net::TcpStream {
+        loop {
+            match tokio::net::TcpStream::connect(address).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+            }
+        }
+    }
+
+    /// Filesystem with reads taking `read_delay`, counting clunked fids and concurrent reads
+    #[derive(Clone, Default)]
+    struct SlowFs {
+        read_delay: Duration,
+        read_started: Arc<tokio::sync::Notify>,
+        clunked: Arc<std::sync::atomic::AtomicUsize>,
+        reading: Arc<std::sync::atomic::AtomicUsize>,
+        most_reading: Arc<std::sync::atomic::AtomicUsize>,
+    }
+
+    impl SlowFs {
+        fn clunked(&self) -> usize {
+            self.clunked.load(std::sync::atomic::Ordering::SeqCst)
+        }
+
+        /// Largest number of reads that were running at once
+        fn most_reading(&self) -> usize {
+            self.most_reading.load(std::sync::atomic::Ordering::SeqCst)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl Filesystem for SlowFs {
+        type Fid = ();
+
+        async fn rattach(
+            &self,
+            _: &Fid<()>,
+            _afid: Option<&Fid<()>>,
+            _uname: &str,
+            _aname: &str,
+            _n_uname: u32,
+        ) -> Result<Fcall> {
+            Ok(Fcall::Rattach {
+                qid: Default::default(),
+            })
+        }
+
+        async fn rread(&self, _: &Fid<()>, _offset: u64, _count: u32) -> Result<Fcall> {
+            self.read_started.notify_one();
+            let reading = self
+                .reading
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            self.most_reading
+                .fetch_max(reading + 1, std::sync::atomic::Ordering::SeqCst);
+            tokio::time::sleep(self.read_delay).await;
+            self.reading
+                .fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(Fcall::Rread {
+                data: Data(vec![].into()),
+            })
+        }
+
+        async fn rclunk(&self, _: &Fid<()>) -> Result<Fcall> {
+            self.clunked
+                .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok(Fcall::Rclunk)
+        }
+    }
+
+    /// Attaches fid 1 and starts reading it with tag 2
+    async fn attach_and_read<S: AsyncRead + AsyncWrite>(fs_adapter: &mut FSAdapter<S>) {
+        fs_adapter
+            .send(&Msg {
+                tag: 1,
+                body: Fcall::Tattach {
+                    fid: 1,
+                    afid: NOFID,
+                    uname: "".to_string(),
+                    aname: "".to_string(),
+                    n_uname: 0,
+                },
+            })
+            .await
+            .unwrap();
+        assert!(matches!(
+            fs_adapter.receive().await.unwrap().body,
+            Fcall::Rattach { .. }
+        ));
+
+        fs_adapter
+            .send(&Msg {
+                tag: 2,
+                body: Fcall::Tread {
+                    fid: 1,
+                    offset: 0,
+                    count: 4096,
+                },
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    /// Shutdown stops the listener, lets in-flight requests finish and clunks fids
+    async fn shutdown_waits_for_inflight_requests() {
+        run_test(async {
+            let filesystem = SlowFs {
+                read_delay: Duration::from_millis(200),
+                ..Default::default()
+            };
+            let address = free_tcp_address();
+            let server = srv_spawn(
+                filesystem.clone(),
+                "tcp",
+                &address,
+                &ServerConfig::default(),
+            );
+
+            let mut fs_adapter = FSAdapter::from_stream(connect_tcp(&address).await);
+            attach_and_read(&mut fs_adapter).await;
+            filesystem.read_started.notified().await;
+
+            let shutdown = tokio::spawn(server.shutdown(Duration::from_secs(5)));
+
+            assert_eq!(
+                fs_adapter.receive().await.unwrap(),
+                Msg {
+                    tag: 2,
+                    body: Fcall::Rread {
+                        data: Data(vec![].into())
+                    },
+                }
+            );
+
+            let results = shutdown.await.unwrap().unwrap();
+            assert_eq!(results.len(), 1);
+            assert!(results[0].is_ok());
+            assert_eq!(filesystem.clunked(), 1);
+
+            assert!(fs_adapter.receive().await.is_err());
+            assert!(tokio::net::TcpStream::connect(&address).await.is_err());
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Requests still running after the timeout are given up, fids are clunked anyway
+    async fn shutdown_gives_up_on_stuck_requests() {
+        run_test(async {
+            let filesystem = SlowFs {
+                read_delay: Duration::from_secs(60),
+                ..Default::default()
+            };
+            let address = free_tcp_address();
+            let server = srv_spawn(
+                filesystem.clone(),
+                "tcp",
+                &address,
+                &ServerConfig::default(),
+            );
+
+            let mut fs_adapter = FSAdapter::from_stream(connect_tcp(&address).await);
+            attach_and_read(&mut fs_adapter).await;
+            filesystem.read_started.notified().await;
+
+            let results = server.shutdown(Duration::from_millis(50)).await.unwrap();
+            assert_eq!(results.len(), 1);
+            match &results[0] {
+                Err(crate::core::error::Error::Io(e)) => {
+                    assert_eq!(e.kind(), std::io::ErrorKind::TimedOut)
+                }
+                other => panic!("Unexpected result {:?}", other),
+            }
+            assert_eq!(filesystem.clunked(), 1);
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Flushed request is cancelled, Rflush comes instead of its response
+    async fn flush_cancels_inflight_request() {
+        run_test(async {
+            let filesystem = SlowFs {
+                read_delay: Duration::from_secs(60),
+                ..Default::default()
+            };
+            let address = free_tcp_address();
+            let server = srv_spawn(
+                filesystem.clone(),
+                "tcp",
+                &address,
+                &ServerConfig::default(),
+            );
+
+            let mut fs_adapter = FSAdapter::from_stream(connect_tcp(&address).await);
+            attach_and_read(&mut fs_adapter).await;
+            filesystem.read_started.notified().await;
+
+            fs_adapter
+                .send(&Msg {
+                    tag: 3,
+                    body: Fcall::Tflush { oldtag: 2 },
+                })
+                .await
+                .unwrap();
+            assert_eq!(
+                fs_adapter.receive().await.unwrap(),
+                Msg {
+                    tag: 3,
+                    body: Fcall::Rflush,
+                }
+            );
+
+            // Flushing a tag which is not in flight is answered right away
+            fs_adapter
+                .send(&Msg {
+                    tag: 4,
+                    body: Fcall::Tflush { oldtag: 2 },
+                })
+                .await
+                .unwrap();
+            fs_adapter
+                .send(&Msg {
+                    tag: 2,
+                    body: Fcall::Tclunk { fid: 1 },
+                })
+                .await
+                .unwrap();
+
+            let mut responses = vec![
+                fs_adapter.receive().await.unwrap(),
+                fs_adapter.receive().await.unwrap(),
+            ];
+            responses.sort_by_key(|msg| msg.tag);
+            assert_eq!(
+                responses,
+                vec![
+                    Msg {
+                        tag: 2,
+                        body: Fcall::Rclunk,
+                    },
+                    Msg {
+                        tag: 4,
+                        body: Fcall::Rflush,
+                    },
+                ]
+            );
+
+            drop(fs_adapter);
+            server.shutdown(Duration::from_secs(1)).await.unwrap();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Request reusing the tag of one in flight is refused, the earlier one stays flushable
+    async fn reused_tag_is_refused_while_in_flight() {
+        use crate::core::error::errno::EBUSY;
+
+        run_test(async {
+            let filesystem = SlowFs {
+                read_delay: Duration::from_secs(60),
+                ..Default::default()
+            };
+            let address = free_tcp_address();
+            let server = srv_spawn(
+                filesystem.clone(),
+                "tcp",
+                &address,
+                &ServerConfig::default(),
+            );
+
+            let mut fs_adapter = FSAdapter::from_stream(connect_tcp(&address).await);
+            attach_and_read(&mut fs_adapter).await;
+            filesystem.read_started.notified().await;
+
+            fs_adapter
+                .send(&Msg {
+                    tag: 2,
+                    body: Fcall::Tclunk { fid: 1 },
+                })
+                .await
+                .unwrap();
+            assert_eq!(
+                fs_adapter.receive().await.unwrap(),
+                Msg {
+                    tag: 2,
+                    body: Fcall::Rlerror {
+                        ecode: EBUSY as u32
+                    },
+                }
+            );
+            assert_eq!(filesystem.clunked(), 0);
+
+            fs_adapter
+                .send(&Msg {
+                    tag: 3,
+                    body: Fcall::Tflush { oldtag: 2 },
+                })
+                .await
+                .unwrap();
+            assert_eq!(
+                fs_adapter.receive().await.unwrap(),
+                Msg {
+                    tag: 3,
+                    body: Fcall::Rflush,
+                }
+            );
+
+            drop(fs_adapter);
+            server.shutdown(Duration::from_secs(1)).await.unwrap();
+            assert_eq!(filesystem.clunked(), 1);
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Fids left by a client which went away are clunked through the filesystem
+    async fn disconnect_clunks_remaining_fids() {
+        run_test(async {
+            let filesystem = SlowFs::default();
+            let address = free_tcp_address();
+            let server = srv_spawn(
+                filesystem.clone(),
+                "tcp",
+                &address,
+                &ServerConfig::default(),
+            );
+
+            let mut fs_adapter = FSAdapter::from_stream(connect_tcp(&address).await);
+            attach_and_read(&mut fs_adapter).await;
+            fs_adapter.receive().await.unwrap();
+            drop(fs_adapter);
+
+            let deadline = tokio::time::Instant::now() + Duration::from_secs(5);
+            while filesystem.clunked() == 0 {
+                assert!(
+                    tokio::time::Instant::now() < deadline,
+                    "Fid was not clunked"
+                );
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+            assert_eq!(filesystem.clunked(), 1);
+
+            server.shutdown(Duration::from_secs(1)).await.unwrap();
+            assert_eq!(filesystem.clunked(), 1);
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Tversion in the middle of a session aborts requests in flight and clunks all fids
+    async fn tversion_resets_session() {
+        run_test(async {
+            let filesystem = SlowFs {
+                read_delay: Duration::from_secs(60),
+                ..Default::default()
+            };
+            let address = free_tcp_address();
+            let server = srv_spawn(
+                filesystem.clone(),
+                "tcp",
+                &address,
+                &ServerConfig::default(),
+            );
+
+            let mut fs_adapter = FSAdapter::from_stream(connect_tcp(&address).await);
+            attach_and_read(&mut fs_adapter).await;
+            filesystem.read_started.notified().await;
+
+            let version = Fcall::Tversion {
+                msize: 8192,
+                version: "9P2000.L".to_string(),
+            };
+            fs_adapter
+                .send(&Msg {
+                    tag: NOTAG,
+                    body: version,
+                })
+                .await
+                .unwrap();
+            assert!(matches!(
+                fs_adapter.receive().await.unwrap().body,
+                Fcall::Rversion { .. }
+            ));
+            assert_eq!(filesystem.clunked(), 1);
+
+            fs_adapter
+                .send(&Msg {
+                    tag: 3,
+                    body: Fcall::Tclunk { fid: 1 },
+                })
+                .await
+                .unwrap();
+            assert_eq!(
+                fs_adapter.receive().await.unwrap(),
+                Msg {
+                    tag: 3,
+                    body: Fcall::Rlerror {
+                        ecode: crate::core::error::errno::EBADF as u32,
+                    },
+                }
+            );
+
+            drop(fs_adapter);
+            server.shutdown(Duration::from_secs(1)).await.unwrap();
+        })
+        .await
+    }
+
+    /// Attaches fid 1 and reads it `count` times at once, waits for all reads to finish
+    async fn attach_and_read_many<S: AsyncRead + AsyncWrite>(
+        fs_adapter: &mut FSAdapter<S>,
+        count: u16,
+    ) {
+        attach_and_read(fs_adapter).await;
+        for tag in 3..count + 2 {
+            fs_adapter
+                .send(&Msg {
+                    tag,
+                    body: Fcall::Tread {
+                        fid: 1,
+                        offset: 0,
+                        count: 4096,
+                    },
+                })
+                .await
+                .unwrap();
+        }
+        for _ in 0..count {
+            assert!(matches!(
+                fs_adapter.receive().await.unwrap().body,
+                Fcall::Rread { .. }
+            ));
+        }
+    }
+
+    #[tokio::test]
+    /// Connection at its request limit is not read until a request finishes
+    async fn requests_per_connection_are_limited() {
+        run_test(async {
+            let filesystem = SlowFs {
+                read_delay: Duration::from_millis(50),
+                ..Default::default()
+            };
+            let address = free_tcp_address();
+            let server = srv_spawn(
+                filesystem.clone(),
+                "tcp",
+                &address,
+                &ServerConfig {
+                    max_requests_per_connection: Some(2),
+                    ..Default::default()
+                },
+            );
+
+            let mut fs_adapter = FSAdapter::from_stream(connect_tcp(&address).await);
+            attach_and_read_many(&mut fs_adapter, 6).await;
+            assert_eq!(filesystem.most_reading(), 2);
+
+            drop(fs_adapter);
+            server.shutdown(Duration::from_secs(1)).await.unwrap();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Server-wide request limit is shared by all connections
+    async fn requests_are_limited_across_connections() {
+        run_test(async {
+            let filesystem = SlowFs {
+                read_delay: Duration::from_millis(50),
+                ..Default::default()
+            };
+            let address = free_tcp_address();
+            let server = srv_spawn(
+                filesystem.clone(),
+                "tcp",
+                &address,
+                &ServerConfig {
+                    max_requests: Some(3),
+                    ..Default::default()
+                },
+            );
+
+            let mut fs_adapter1 = FSAdapter::from_stream(connect_tcp(&address).await);
+            let mut fs_adapter2 = FSAdapter::from_stream(connect_tcp(&address).await);
+            tokio::join!(
+                attach_and_read_many(&mut fs_adapter1, 4),
+                attach_and_read_many(&mut fs_adapter2, 4),
+            );
+            assert_eq!(filesystem.most_reading(), 3);
+
+            drop(fs_adapter1);
+            drop(fs_adapter2);
+            server.shutdown(Duration::from_secs(1)).await.unwrap();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Client over the connection limit is served once another one disconnects
+    async fn connections_are_limited() {
+        run_test(async {
+            let address = free_tcp_address();
+            let server = srv_spawn(
+                SlowFs::default(),
+                "tcp",
+                &address,
+                &ServerConfig {
+                    max_connections: Some(1),
+                    ..Default::default()
+                },
+            );
+            let version = Msg {
+                tag: NOTAG,
+                body: Fcall::Tversion {
+                    msize: 8192,
+                    version: "9P2000.L".to_string(),
+                },
+            };
+
+            let mut fs_adapter1 = FSAdapter::from_stream(connect_tcp(&address).await);
+            fs_adapter1.send(&version).await.unwrap();
+            fs_adapter1.receive().await.unwrap();
+
+            let mut fs_adapter2 = FSAdapter::from_stream(connect_tcp(&address).await);
+            fs_adapter2.send(&version).await.unwrap();
+            assert!(
+                tokio::time::timeout(Duration::from_millis(100), fs_adapter2.receive())
+                    .await
+                    .is_err()
+            );
+
+            drop(fs_adapter1);
+            fs_adapter2.receive().await.unwrap();
+
+            drop(fs_adapter2);
+            server.shutdown(Duration::from_secs(1)).await.unwrap();
+        })
+        .await
+    }
+
+    /// Starts Unpfs over TCP with msize limited to 8192 and negotiates the session
+    async fn connect_with_small_msize(
+        export_dir: &std::path::Path,
+    ) -> (
+        FSAdapter<tokio::net::TcpStream>,
+        crate::core::srv::ServerHandle,
+    ) {
+        let filesystem = Unpfs {
+            realroot: export_dir.to_path_buf(),
+            vap: Arc::new(Mutex::new(VirtualAttributesProvider::new())),
+            options: Default::default(),
+            locks: Default::default(),
+            usage: Default::default(),
+        };
+        let address = free_tcp_address();
+        let server = srv_spawn(
+            filesystem,
+            "tcp",
+            &address,
+            &ServerConfig {
+                max_msize: Some(8192),
+                ..Default::default()
+            },
+        );
+
+        let mut fs_adapter = FSAdapter::from_stream(connect_tcp(&address).await);
+        fs_adapter
+            .send(&Msg {
+                tag: NOTAG,
+                body: Fcall::Tversion {
+                    msize: 1024 * 1024,
+                    version: "9P2000.L".to_string(),
+                },
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            fs_adapter.receive().await.unwrap().body,
+            Fcall::Rversion {
+                msize: 8192,
+                version: "9P2000.L".to_string(),
+            }
+        );
+
+        (fs_adapter, server)
+    }
+
+    #[tokio::test]
+    /// Msize is lowered to the configured maximum, iounit and reads follow it
+    async fn msize_limits_iounit_and_reads() {
+        run_test(async {
+            let temp_dir = tempdir::TempDir::new("msize_limits_iounit_and_reads").unwrap();
+            let (mut fs_adapter, server) = connect_with_small_msize(temp_dir.path()).await;
+            let iounit = 8192 - crate::core::fcall::IOHDRSZ;
+
+            fs_adapter
+                .send(&Msg {
+                    tag: 1,
+                    body: Fcall::Tattach {
+                        fid: 1,
+                        afid: NOFID,
+                        uname: "".to_string(),
+                        aname: "".to_string(),
+                        n_uname: 0,
+                    },
+                })
+                .await
+                .unwrap();
+            fs_adapter.receive().await.unwrap();
+
+            fs_adapter
+                .send(&Msg {
+                    tag: 1,
+                    body: Fcall::Tlcreate {
+                        fid: 1,
+                        name: "file".to_string(),
+                        flags: crate::core::fcall::FileOpenMode::P9_DOTL_RDWR.bits(),
+                        mode: 0o644,
+                        gid: 0,
+                    },
+                })
+                .await
+                .unwrap();
+            match fs_adapter.receive().await.unwrap().body {
+                Fcall::Rlcreate { iounit: unit, .. } => assert_eq!(unit, iounit),
+                other => panic!("Unexpected response {:?}", other),
+            }
+
+            for offset in [0, iounit as u64] {
+                fs_adapter
+                    .send(&Msg {
+                        tag: 1,
+                        body: Fcall::Twrite {
+                            fid: 1,
+                            offset,
+                            data: Data(vec![7; iounit as usize].into()),
+                        },
+                    })
+                    .await
+                    .unwrap();
+                assert_eq!(
+                    fs_adapter.receive().await.unwrap().body,
+                    Fcall::Rwrite { count: iounit }
+                );
+            }
+
+            fs_adapter
+                .send(&Msg {
+                    tag: 1,
+                    body: Fcall::Tread {
+                        fid: 1,
+                        offset: 0,
+                        count: 1024 * 1024,
+                    },
+                })
+                .await
+                .unwrap();
+            match fs_adapter.receive().await.unwrap().body {
+                Fcall::Rread { data } => assert_eq!(data.0.len(), iounit as usize),
+                other => panic!("Unexpected response {:?}", other),
+            }
+
+            drop(fs_adapter);
+            server.shutdown(Duration::from_secs(1)).await.unwrap();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Message larger than the negotiated msize closes the connection
+    async fn oversized_message_closes_connection() {
+        run_test(async {
+            let temp_dir = tempdir::TempDir::new("oversized_message_closes_connection").unwrap();
+            let (mut fs_adapter, server) = connect_with_small_msize(temp_dir.path()).await;
+
+            fs_adapter
+                .send(&Msg {
+                    tag: 1,
+                    body: Fcall::Twrite {
+                        fid: 1,
+                        offset: 0,
+                        data: Data(vec![7; 8192].into()),
+                    },
+                })
+                .await
+                .unwrap();
+            assert!(fs_adapter.receive().await.is_err());
+
+            let results = server.shutdown(Duration::from_secs(1)).await.unwrap();
+            assert_eq!(results.len(), 1);
+            assert!(results[0].is_err());
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Response which cannot be written ends the connection with an error
+    async fn broken_writer_ends_dispatch_with_error() {
+        run_test(async {
+            let (client_rx, server_tx) = tokio::io::duplex(1024);
+            let (mut client_tx, server_rx) = tokio::io::duplex(1024);
+            drop(client_rx);
+
+            let mut frame = bytes::BytesMut::new();
+            P9Codec::new(DEFAULT_MAX_MSIZE)
+                .encode(
+                    Msg {
+                        tag: NOTAG,
+                        body: Fcall::Tversion {
+                            msize: 8192,
+                            version: "9P2000.L".to_string(),
+                        },
+                    },
+                    &mut frame,
+                )
+                .unwrap();
+            client_tx.write_all(&frame).await.unwrap();
+
+            let limits = Limits::default();
+            let connection = dispatch(
+                SlowFs::default(),
+                server_rx,
+                server_tx,
+                &limits,
+                Shutdown::never(),
+            );
+            let res = tokio::time::timeout(Duration::from_secs(5), connection)
+                .await
+                .expect("Connection was not torn down");
+            assert!(res.is_err());
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Client creates files in the export and reads them back
+    async fn client_seeds_and_inspects_export() {
+        use crate::core::client::P9Client;
+        use crate::core::fcall::{FileOpenMode, GetattrMask};
+
+        run_test(async {
+            let temp_dir = tempdir::TempDir::new("client_seeds_and_inspects_export").unwrap();
+            let temp_dir_string = temp_dir.path().as_os_str().to_str().unwrap();
+            let srv = InprocServer::new(temp_dir_string);
+
+            let client = P9Client::connect_with_msize(srv.attach_client(16384), 8192)
+                .await
+                .unwrap();
+            assert_eq!(client.msize(), 8192);
+
+            let root = client.attach("", "").await.unwrap();
+            assert!(root.qid().typ.contains(QidType::DIR));
+
+            let dir = client.walk(&root, &[]).await.unwrap();
+            client.mkdir(&dir, "dir", 0o755, 0).await.unwrap();
+            client.clunk(dir).await.unwrap();
+
+            // Larger than msize, written and read in several messages
+            let content: Vec<u8> = (0..20000).map(|i| i as u8).collect();
+            let mut file = client.walk(&root, &["dir"]).await.unwrap();
+            client
+                .create(
+                    &mut file,
+                    "file",
+                    FileOpenMode::P9_DOTL_RDWR.bits(),
+                    0o644,
+                    0,
+                )
+                .await
+                .unwrap();
+            assert_eq!(client.write(&file, 0, &content).await.unwrap(), 20000);
+            client.clunk(file).await.unwrap();
+            assert_eq!(
+                std::fs::read(temp_dir.path().join("dir/file")).unwrap(),
+                content
+            );
+
+            let mut file = client.walk(&root, &["dir", "file"]).await.unwrap();
+            client.open(&mut file, 0).await.unwrap();
+            assert_eq!(client.read(&file, 0, 30000).await.unwrap(), content);
+            let stat = client.getattr(&file, GetattrMask::SIZE).await.unwrap();
+            assert_eq!(stat.size, 20000);
+            client.clunk(file).await.unwrap();
+
+            assert!(client.walk(&root, &["dir", "missing"]).await.is_err());
+
+            let mut dir = client.walk(&root, &["dir"]).await.unwrap();
+            client.open(&mut dir, 0).await.unwrap();
+            let names: Vec<String> = client
+                .readdir(&dir)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.name)
+                .collect();
+            assert_eq!(names, [".", "..", "file"]);
+            client.clunk(dir).await.unwrap();
+            client.clunk(root).await.unwrap();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Requests from concurrent tasks are served at the same time on one connection
+    async fn client_multiplexes_requests() {
+        use crate::core::client::P9Client;
+
+        run_test(async {
+            let filesystem = SlowFs {
+                read_delay: Duration::from_millis(50),
+                ..Default::default()
+            };
+            let (client, server) = tokio::io::duplex(16384);
+            tokio::spawn(crate::core::srv::srv_async_inproc(
+                filesystem.clone(),
+                server,
+            ));
+
+            let client = P9Client::connect(client).await.unwrap();
+            let fid = client.attach("", "").await.unwrap();
+            let reads = (0..4).map(|_| client.read(&fid, 0, 4096));
+            for data in futures::future::join_all(reads).await {
+                assert!(data.unwrap().is_empty());
+            }
+            assert_eq!(filesystem.most_reading(), 4);
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Calls dropped before their response are flushed, their tag is kept until Rflush
+    async fn client_flushes_dropped_calls() {
+        use crate::core::client::P9Client;
+
+        run_test(async {
+            let (client, server) = tokio::io::duplex(16384);
+            let mut server = FSAdapter::from_stream(server);
+            let connect = tokio::spawn(P9Client::connect(client));
+            let version = server.receive().await.unwrap();
+            server
+                .send(&Msg {
+                    tag: version.tag,
+                    body: Fcall::Rversion {
+                        msize: 8192,
+                        version: "9P2000.L".to_string(),
+                    },
+                })
+                .await
+                .unwrap();
+            let client = connect.await.unwrap().unwrap();
+
+            let attach = tokio::time::timeout(Duration::from_millis(10), client.attach("", ""));
+            assert!(attach.await.is_err());
+            let given_up = server.receive().await.unwrap();
+            assert!(matches!(given_up.body, Fcall::Tattach { .. }));
+            let flush = server.receive().await.unwrap();
+            assert_eq!(
+                flush.body,
+                Fcall::Tflush {
+                    oldtag: given_up.tag
+                }
+            );
+
+            // The response may still come before Rflush, it is not taken for a later call
+            server
+                .send(&Msg {
+                    tag: given_up.tag,
+                    body: Fcall::Rattach {
+                        qid: Default::default(),
+                    },
+                })
+                .await
+                .unwrap();
+            let (attached, ()) = tokio::join!(client.attach("", ""), async {
+                let request = server.receive().await.unwrap();
+                assert_ne!(request.tag, given_up.tag);
+                server
+                    .send(&Msg {
+                        tag: flush.tag,
+                        body: Fcall::Rflush,
+                    })
+                    .await
+                    .unwrap();
+                server
+                    .send(&Msg {
+                        tag: request.tag,
+                        body: Fcall::Rattach {
+                            qid: Default::default(),
+                        },
+                    })
+                    .await
+                    .unwrap();
+            });
+            assert!(attached.is_ok());
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Shutting down inproc server detaches its clients
+    async fn inproc_server_shutdown_detaches_clients() {
+        run_test(async {
+            let temp_dir =
+                tempdir::TempDir::new("inproc_server_shutdown_detaches_clients").unwrap();
+            let temp_dir_string = temp_dir.path().as_os_str().to_str().unwrap();
+            let srv = InprocServer::new(temp_dir_string);
+
+            let mut fs_adapter = FSAdapter::new(&srv);
+            fs_adapter
+                .send(&Msg {
+                    tag: NOTAG,
+                    body: Fcall::Tversion {
+                        msize: 8192,
+                        version: "9P2000.L".to_string(),
+                    },
+                })
+                .await
+                .unwrap();
+            fs_adapter.receive().await.unwrap();
+
+            let results = srv.shutdown(Duration::from_secs(1)).await;
+            assert_eq!(results.len(), 1);
+            assert!(results[0].is_ok());
+            assert!(fs_adapter.receive().await.is_err());
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Inproc server serves any filesystem and clunks fids of clients which went away
+    async fn inproc_server_serves_any_filesystem() {
+        run_test(async {
+            let filesystem = SlowFs::default();
+            let srv = InprocServer::with_config(filesystem.clone(), &ServerConfig::default());
+
+            let mut fs_adapter = FSAdapter::from_stream(srv.attach_client(16384));
+            attach_and_read(&mut fs_adapter).await;
+            assert!(matches!(
+                fs_adapter.receive().await.unwrap().body,
+                Fcall::Rread { .. }
+            ));
+            drop(fs_adapter);
+
+            let results = srv.shutdown(Duration::from_secs(1)).await;
+            assert_eq!(results.len(), 1);
+            assert_eq!(filesystem.clunked(), 1);
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Detaching one client leaves the others attached
+    async fn inproc_client_detaches() {
+        run_test(async {
+            let temp_dir = tempdir::TempDir::new("inproc_client_detaches").unwrap();
+            let srv = InprocServer::builder(temp_dir.path()).build();
+
+            let first = srv.attach_client(16384);
+            let second = srv.attach_client(16384);
+            assert_ne!(first.id(), second.id());
+            assert_eq!(srv.attached_clients(), 2);
+
+            first.detach().await;
+            assert_eq!(srv.attached_clients(), 1);
+            assert!(second.is_attached());
+
+            srv.shutdown(Duration::from_secs(1)).await;
+            assert!(!second.is_attached());
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Clients which detached are not kept by the server
+    async fn detached_clients_are_collected() {
+        run_test(async {
+            let temp_dir = tempdir::TempDir::new("detached_clients_are_collected").unwrap();
+            let srv = InprocServer::builder(temp_dir.path()).build();
+
+            for _ in 0..100 {
+                srv.attach_client(16384).detach().await;
+            }
+            assert_eq!(srv.attached_clients(), 0);
+
+            // Only connections which ended since the last attach are left to collect
+            let results = srv.shutdown(Duration::from_secs(1)).await;
+            assert!(results.len() <= 2, "{} results", results.len());
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Read-only server lets clients read the export but not modify it
+    async fn read_only_server_refuses_modifications() {
+        use crate::core::client::P9Client;
+        use crate::core::error::errno::EROFS;
+        use crate::core::fcall::FileOpenMode;
+
+        run_test(async {
+            let temp_dir = tempdir::TempDir::new("read_only_server_refuses_modifications").unwrap();
+            std::fs::write(temp_dir.path().join("file"), "content").unwrap();
+            let srv = InprocServer::builder(temp_dir.path())
+                .max_msize(8192)
+                .read_only(true)
+                .build();
+
+            let client = P9Client::connect(srv.attach_client(16384)).await.unwrap();
+            assert_eq!(client.msize(), 8192);
+            let root = client.attach("", "").await.unwrap();
+
+            let e = client.mkdir(&root, "dir", 0o755, 0).await.unwrap_err();
+            assert!(matches!(e.errno(), EROFS));
+            let mut file = client.walk(&root, &["file"]).await.unwrap();
+            let e = client
+                .open(&mut file, FileOpenMode::P9_DOTL_RDWR.bits())
+                .await
+                .unwrap_err();
+            assert!(matches!(e.errno(), EROFS));
+
+            client.open(&mut file, 0).await.unwrap();
+            assert_eq!(client.read(&file, 0, 100).await.unwrap(), b"content");
+            assert!(!temp_dir.path().join("dir").exists());
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Files get owner and permission bits configured for the server
+    async fn attribute_defaults_are_reported() {
+        use crate::core::client::P9Client;
+        use crate::core::fcall::GetattrMask;
+
+        run_test(async {
+            let temp_dir = tempdir::TempDir::new("attribute_defaults_are_reported").unwrap();
+            std::fs::write(temp_dir.path().join("file"), "").unwrap();
+            let srv = InprocServer::builder(temp_dir.path())
+                .attribute_defaults(AttributeDefaults {
+                    uid: 0,
+                    gid: 0,
+                    file_mode: Some(0o600),
+                    dir_mode: Some(0o700),
+                })
+                .build();
+
+            let client = P9Client::connect(srv.attach_client(16384)).await.unwrap();
+            let root = client.attach("", "").await.unwrap();
+            let stat = client.getattr(&root, GetattrMask::BASIC).await.unwrap();
+            assert_eq!(stat.mode, 0o40700);
+
+            let file = client.walk(&root, &["file"]).await.unwrap();
+            let stat = client.getattr(&file, GetattrMask::BASIC).await.unwrap();
+            assert_eq!((stat.uid, stat.gid, stat.mode), (0, 0, 0o100600));
+        })
+        .await
+    }
+
+    /// Sends every request and waits for its response before sending the next one
+    async fn exchange<T>(mut transport: T, requests: &[Msg]) -> Vec<Msg>
+    where
+        T: Sink<Msg, Error = error::Error> + Stream<Item = Result<Msg>> + Unpin,
+    {
+        let mut responses = Vec::new();
+        for request in requests {
+            transport.send(request.clone()).await.unwrap();
+            responses.push(transport.next().await.unwrap().unwrap());
+        }
+        responses
+    }
+
+    #[tokio::test]
+    /// Messages passed over channels get the same responses as encoded ones
+    async fn channel_and_wire_paths_agree() {
+        use crate::core::fcall::FileOpenMode;
+        use tokio_util::codec::Framed;
+
+        run_test(async {
+            let temp_dir = tempdir::TempDir::new("channel_and_wire_paths_agree").unwrap();
+            std::fs::write(temp_dir.path().join("file"), "content").unwrap();
+            let srv = InprocServer::builder(temp_dir.path()).build();
+
+            let requests = [
+                Fcall::Tversion {
+                    msize: 8192,
+                    version: "9P2000.L".to_string(),
+                },
+                Fcall::Tattach {
+                    fid: 0,
+                    afid: NOFID,
+                    uname: "".to_string(),
+                    aname: "".to_string(),
+                    n_uname: 0,
+                },
+                Fcall::Twalk {
+                    fid: 0,
+                    newfid: 1,
+                    wnames: vec!["file".to_string()],
+                },
+                Fcall::Tlopen {
+                    fid: 1,
+                    flags: FileOpenMode::P9_DOTL_RDWR.bits(),
+                },
+                Fcall::Twrite {
+                    fid: 1,
+                    offset: 3,
+                    data: Data(b"TENT".to_vec().into()),
+                },
+                Fcall::Tread {
+                    fid: 1,
+                    offset: 0,
+                    count: 100,
+                },
+                Fcall::Tclunk { fid: 1 },
+                Fcall::Twalk {
+                    fid: 0,
+                    newfid: 2,
+                    wnames: vec!["missing".to_string()],
+                },
+            ]
+            .into_iter()
+            .enumerate()
+            .map(|(tag, body)| Msg {
+                tag: if tag == 0 { NOTAG } else { tag as u16 },
+                body,
+            })
+            .collect::<Vec<_>>();
+
+            let wire = Framed::new(srv.attach_client(16384), P9Codec::new(DEFAULT_MAX_MSIZE));
+            let over_wire = exchange(wire, &requests).await;
+            let over_channel = exchange(srv.attach_channel(4), &requests).await;
+
+            assert_eq!(over_wire, over_channel);
+            assert!(matches!(
+                &over_channel[5].body,
+                Fcall::Rread { data } if data.0 == b"conTENT"[..]
+            ));
+            assert!(matches!(over_channel[7].body, Fcall::Rlerror { .. }));
+        })
+        .await
+    }
+
+    /// Filesystem keeping the last payload written, which is returned by reads
+    #[derive(Clone, Default)]
+    struct PayloadFs {
+        written: Arc<std::sync::Mutex<bytes::Bytes>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Filesystem for PayloadFs {
+        type Fid = ();
+
+        async fn rattach(
+            &self,
+            _: &Fid<()>,
+            _afid: Option<&Fid<()>>,
+            _uname: &str,
+            _aname: &str,
+            _n_uname: u32,
+        ) -> Result<Fcall> {
+            Ok(Fcall::Rattach {
+                qid: Default::default(),
+            })
+        }
+
+        async fn rwrite(&self, _: &Fid<()>, _offset: u64, data: &Data) -> Result<Fcall> {
+            *self.written.lock().unwrap() = data.0.clone();
+            Ok(Fcall::Rwrite {
+                count: data.0.len() as u32,
+            })
+        }
+
+        async fn rread(&self, _: &Fid<()>, _offset: u64, _count: u32) -> Result<Fcall> {
+            Ok(Fcall::Rread {
+                data: Data(self.written.lock().unwrap().clone()),
+            })
+        }
+    }
+
+    #[tokio::test]
+    /// Payloads passed over channels are the buffers of the sender, never copies
+    async fn channel_shares_payloads() {
+        use crate::core::client::P9Client;
+
+        run_test(async {
+            let filesystem = PayloadFs::default();
+            let srv = InprocServer::with_config(filesystem.clone(), &ServerConfig::default());
+
+            let client = P9Client::connect_msgs(srv.attach_channel(4)).await.unwrap();
+            let root = client.attach("", "").await.unwrap();
+
+            let payload = bytes::Bytes::from(vec![7; 4096]);
+            assert_eq!(
+                client.write_bytes(&root, 0, payload.clone()).await.unwrap(),
+                4096
+            );
+            assert_eq!(
+                filesystem.written.lock().unwrap().as_ptr(),
+                payload.as_ptr()
+            );
+
+            let read = client.read_bytes(&root, 0, 4096).await.unwrap();
+            assert_eq!(read, payload);
+            assert_eq!(read.as_ptr(), payload.as_ptr());
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Detaching channel client leaves the others attached
+    async fn inproc_channel_detaches() {
+        run_test(async {
+            let temp_dir = tempdir::TempDir::new("inproc_channel_detaches").unwrap();
+            let srv = InprocServer::builder(temp_dir.path()).build();
+
+            let first = srv.attach_channel(4);
+            let second = srv.attach_channel(4);
+            assert_ne!(first.id(), second.id());
+            assert_eq!(srv.attached_clients(), 2);
+
+            first.detach().await;
+            assert_eq!(srv.attached_clients(), 1);
+            assert!(second.is_attached());
+
+            srv.shutdown(Duration::from_secs(1)).await;
+            assert!(!second.is_attached());
+        })
+        .await
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    /// Symlinks created by clients never point outside the export, neither are host paths shown
+    async fn symlinks_stay_inside_export() {
+        use crate::core::client::P9Client;
+        use crate::core::error::errno::EPERM;
+        use crate::core::fcall::GetattrMask;
+
+        run_test(async {
+            let temp_dir = tempdir::TempDir::new("symlinks_stay_inside_export").unwrap();
+            let export = temp_dir.path().canonicalize().unwrap();
+            std::fs::create_dir(export.join("dir")).unwrap();
+            std::os::unix::fs::symlink("/etc/passwd", export.join("host")).unwrap();
+            let srv = InprocServer::builder(temp_dir.path()).build();
+
+            let client = P9Client::connect(srv.attach_client(16384)).await.unwrap();
+            let root = client.attach("", "").await.unwrap();
+            let dir = client.walk(&root, &["dir"]).await.unwrap();
+
+            client.symlink(&dir, "abs", "/dir/file", 0).await.unwrap();
+            client
+                .symlink(&dir, "up", "/../../etc/passwd", 0)
+                .await
+                .unwrap();
+            client.symlink(&dir, "rel", "../dir/file", 0).await.unwrap();
+            client.symlink(&root, "top", "/dir/file", 0).await.unwrap();
+            assert_eq!(
+                std::fs::read_link(export.join("dir/abs")).unwrap(),
+                std::path::Path::new("./../dir/file")
+            );
+            assert_eq!(
+                std::fs::read_link(export.join("dir/up")).unwrap(),
+                std::path::Path::new("./../etc/passwd")
+            );
+            assert_eq!(
+                std::fs::read_link(export.join("top")).unwrap(),
+                std::path::Path::new("./dir/file")
+            );
+            assert_eq!(
+                std::fs::read_link(export.join("dir/rel")).unwrap(),
+                std::path::Path::new("../dir/file")
+            );
+
+            let e = client
+                .symlink(&dir, "escape", "../../passwd", 0)
+                .await
+                .unwrap_err();
+            assert!(matches!(e.errno(), EPERM));
+            assert!(!export.join("dir/escape").exists());
+
+            for (link, target) in [
+                (&["dir", "abs"][..], "/dir/file"),
+                (&["dir", "up"], "/etc/passwd"),
+                (&["dir", "rel"], "../dir/file"),
+                (&["top"], "/dir/file"),
+            ] {
+                let link = client.walk(&root, link).await.unwrap();
+                assert_eq!(client.readlink(&link).await.unwrap(), target);
+                let stat = client.getattr(&link, GetattrMask::BASIC).await.unwrap();
+                assert_eq!(stat.mode & 0o170000, 0o120000);
+            }
+
+            let host = client.walk(&root, &["host"]).await.unwrap();
+            let e = client.readlink(&host).await.unwrap_err();
+            assert!(matches!(e.errno(), EPERM));
+
+            // Links keep pointing into the export after it moves on the host
+            drop(srv);
+            let moved = temp_dir.path().join("moved");
+            std::fs::create_dir(&moved).unwrap();
+            for name in ["dir", "top"] {
+                std::fs::rename(export.join(name), moved.join(name)).unwrap();
+            }
+            std::fs::write(moved.join("dir/file"), "content").unwrap();
+            for link in ["dir/abs", "top"] {
+                assert_eq!(std::fs::read(moved.join(link)).unwrap(), b"content");
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Hard links share contents and attributes, link counts follow them
+    async fn hard_links_share_attributes() {
+        use crate::core::client::P9Client;
+        use crate::core::fcall::{GetattrMask, SetAttr, SetattrMask, Time};
+
+        run_test(async {
+            let temp_dir = tempdir::TempDir::new("hard_links_share_attributes").unwrap();
+            std::fs::write(temp_dir.path().join("file"), "content").unwrap();
+            let srv = InprocServer::builder(temp_dir.path()).build();
+
+            let client = P9Client::connect(srv.attach_client(16384)).await.unwrap();
+            let root = client.attach("", "").await.unwrap();
+            let file = client.walk(&root, &["file"]).await.unwrap();
+            let stat = client.getattr(&file, GetattrMask::BASIC).await.unwrap();
+            assert_eq!(stat.nlink, 1);
+
+            client.link(&root, &file, "link").await.unwrap();
+            assert_eq!(
+                std::fs::read(temp_dir.path().join("link")).unwrap(),
+                b"content"
+            );
+
+            client
+                .setattr(
+                    &file,
+                    SetattrMask::MODE,
+                    SetAttr {
+                        mode: 0o100600,
+                        uid: 0,
+                        gid: 0,
+                        size: 0,
+                        atime: Time { sec: 0, nsec: 0 },
+                        mtime: Time { sec: 0, nsec: 0 },
+                    },
+                )
+                .await
+                .unwrap();
+            let link = client.walk(&root, &["link"]).await.unwrap();
+            let linked = client.getattr(&link, GetattrMask::BASIC).await.unwrap();
+            let stat = client.getattr(&file, GetattrMask::BASIC).await.unwrap();
+            assert_eq!((linked.nlink, linked.mode), (2, 0o100600));
+            assert_eq!(stat.nlink, 2);
+            assert_eq!(
+                client.walk(&root, &["link"]).await.unwrap().qid(),
+                file.qid()
+            );
+
+            client.unlinkat(&root, "link", 0).await.unwrap();
+            let stat = client.getattr(&file, GetattrMask::BASIC).await.unwrap();
+            assert_eq!(stat.nlink, 1);
+        })
+        .await
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    /// FIFOs are made on the host, emulated device nodes and sockets only in virtual attributes
+    async fn mknod_makes_fifos_and_emulated_devices() {
+        use std::os::unix::fs::FileTypeExt;
+
+        use crate::core::client::P9Client;
+        use crate::core::fcall::{GetattrMask, S_IFBLK, S_IFCHR, S_IFIFO, S_IFSOCK};
+
+        run_test(async {
+            let temp_dir = tempdir::TempDir::new("mknod_makes_fifos_and_emulated_devices").unwrap();
+            let srv = InprocServer::builder(temp_dir.path())
+                .emulate_devices(true)
+                .build();
+
+            let client = P9Client::connect(srv.attach_client(16384)).await.unwrap();
+            let root = client.attach("", "").await.unwrap();
+
+            client
+                .mknod(&root, "fifo", S_IFIFO | 0o640, 0, 0, 0)
+                .await
+                .unwrap();
+            let host = std::fs::symlink_metadata(temp_dir.path().join("fifo")).unwrap();
+            assert!(host.file_type().is_fifo());
+            let fifo = client.walk(&root, &["fifo"]).await.unwrap();
+            let stat = client.getattr(&fifo, GetattrMask::BASIC).await.unwrap();
+            assert_eq!(stat.mode & 0o170000, S_IFIFO);
+
+            for (name, mode, rdev) in [
+                ("null", S_IFCHR | 0o666, 0x103),
+                ("sda", S_IFBLK | 0o660, 0x800),
+                ("socket", S_IFSOCK | 0o755, 0),
+            ] {
+                client
+                    .mknod(
+                        &root,
+                        name,
+                        mode,
+                        (rdev >> 8) as u32,
+                        (rdev & 0xff) as u32,
+                        0,
+                    )
+                    .await
+                    .unwrap();
+                let host = std::fs::symlink_metadata(temp_dir.path().join(name)).unwrap();
+                assert!(host.is_file());
+                assert_eq!(host.len(), 0);
+
+                let node = client.walk(&root, &[name]).await.unwrap();
+                let stat = client.getattr(&node, GetattrMask::BASIC).await.unwrap();
+                assert_eq!((stat.mode, stat.rdev), (mode, rdev));
+            }
+
+            let e = client
+                .mknod(&root, "null", S_IFCHR | 0o666, 1, 3, 0)
+                .await
+                .unwrap_err();
+            assert!(matches!(e.errno(), crate::core::error::errno::EEXIST));
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Renamed files and files beneath renamed directories are known by the new path only
+    async fn renamed_files_move_their_attributes() {
+        use crate::core::client::P9Client;
+        use crate::core::error::errno::ENODATA;
+
+        run_test(async {
+            let temp_dir = tempdir::TempDir::new("renamed_files_move_their_attributes").unwrap();
+            let root = temp_dir.path().to_path_buf();
+            std::fs::write(root.join("old"), b"").unwrap();
+            std::fs::create_dir(root.join("dir")).unwrap();
+            std::fs::write(root.join("dir").join("file"), b"").unwrap();
+
+            let vap = Arc::new(Mutex::new(VirtualAttributesProvider::new()));
+            let filesystem = Unpfs {
+                realroot: root.clone(),
+                vap: vap.clone(),
+                options: UnpfsOptions {
+                    virtual_xattrs: true,
+                    ..Default::default()
+                },
+                locks: Default::default(),
+                usage: Default::default(),
+            };
+            let srv = InprocServer::with_config(filesystem, &Default::default());
+            let client = P9Client::connect(srv.attach_client(16384)).await.unwrap();
+            let attach = client.attach("", "").await.unwrap();
+
+            for name in [&["old"][..], &["dir", "file"]] {
+                let fid = client.walk(&attach, name).await.unwrap();
+                client
+                    .setxattr(&fid, "user.test", b"value", 0)
+                    .await
+                    .unwrap();
+                client.clunk(fid).await.unwrap();
+            }
+            client
+                .renameat(&attach, "old", &attach, "new")
+                .await
+                .unwrap();
+            client
+                .renameat(&attach, "dir", &attach, "moved")
+                .await
+                .unwrap();
+
+            {
+                let vap = vap.lock().await;
+                assert!(!vap.paths.contains_key(&root.join("old")));
+                assert!(!vap.paths.contains_key(&root.join("dir").join("file")));
+                assert!(vap.paths.contains_key(&root.join("new")));
+                assert!(vap.paths.contains_key(&root.join("moved").join("file")));
+            }
+
+            // A file made at the old path is a different one
+            std::fs::write(root.join("old"), b"").unwrap();
+            let fid = client.walk(&attach, &["old"]).await.unwrap();
+            let e = client.getxattr(&fid, "user.test").await.unwrap_err();
+            assert_eq!(raw_errno(e), Some(ENODATA as i32));
+            for name in [&["new"][..], &["moved", "file"]] {
+                let fid = client.walk(&attach, name).await.unwrap();
+                assert_eq!(client.getxattr(&fid, "user.test").await.unwrap(), b"value");
+            }
+        })
+        .await
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    /// Files made after an unlink get fresh attributes, even when the host reuses the inode
+    async fn removed_files_leave_no_attributes() {
+        use crate::core::client::P9Client;
+        use crate::core::error::errno::ENODATA;
+        use crate::core::fcall::FileOpenMode;
+        use crate::core::fcall::{GetattrMask, S_IFCHR, S_IFREG};
+
+        run_test(async {
+            let temp_dir = tempdir::TempDir::new("removed_files_leave_no_attributes").unwrap();
+            let srv = InprocServer::builder(temp_dir.path())
+                .emulate_devices(true)
+                .virtual_xattrs(true)
+                .build();
+
+            let client = P9Client::connect(srv.attach_client(16384)).await.unwrap();
+            let root = client.attach("", "").await.unwrap();
+
+            client
+                .mknod(&root, "null", S_IFCHR | 0o666, 1, 3, 0)
+                .await
+                .unwrap();
+            let node = client.walk(&root, &["null"]).await.unwrap();
+            client
+                .setxattr(&node, "user.test", b"value", 0)
+                .await
+                .unwrap();
+            let removed = client.getattr(&node, GetattrMask::BASIC).await.unwrap();
+            assert_eq!((removed.mode, removed.rdev), (S_IFCHR | 0o666, 0x103));
+            client.clunk(node).await.unwrap();
+            client.unlinkat(&root, "null", 0).await.unwrap();
+
+            // Hosts such as ext4 give the next file the inode of the removed one
+            let mut file = client.walk(&root, &[]).await.unwrap();
+            client
+                .create(
+                    &mut file,
+                    "file",
+                    FileOpenMode::P9_DOTL_RDWR.bits(),
+                    0o644,
+                    0,
+                )
+                .await
+                .unwrap();
+            let stat = client.getattr(&file, GetattrMask::BASIC).await.unwrap();
+            assert_eq!((stat.mode, stat.rdev), (S_IFREG | 0o644, 0));
+            assert!(client.getxattr(&file, "").await.unwrap().is_empty());
+            let e = client.getxattr(&file, "user.test").await.unwrap_err();
+            assert_eq!(raw_errno(e), Some(ENODATA as i32));
+
+            // The same for a node replaced by a rename and a file made on the host
+            std::fs::write(temp_dir.path().join("other"), b"").unwrap();
+            client
+                .mknod(&root, "null", S_IFCHR | 0o666, 1, 3, 0)
+                .await
+                .unwrap();
+            client
+                .renameat(&root, "other", &root, "null")
+                .await
+                .unwrap();
+            std::fs::write(temp_dir.path().join("host"), b"").unwrap();
+            let node = client.walk(&root, &["host"]).await.unwrap();
+            let stat = client.getattr(&node, GetattrMask::BASIC).await.unwrap();
+            assert_eq!(stat.mode & 0o170000, S_IFREG);
+            assert_eq!(stat.rdev, 0);
+        })
+        .await
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    /// Statfs reports the host disk, or capacity configured for the server and space used in it
+    async fn statfs_reports_host_or_virtual_capacity() {
+        use crate::core::client::P9Client;
+        use crate::core::fcall::V9FS_MAGIC;
+
+        run_test(async {
+            let temp_dir =
+                tempdir::TempDir::new("statfs_reports_host_or_virtual_capacity").unwrap();
+            std::fs::write(temp_dir.path().join("file"), vec![1; 1 << 20]).unwrap();
+
+            let srv = InprocServer::builder(temp_dir.path()).build();
+            let client = P9Client::connect(srv.attach_client(16384)).await.unwrap();
+            let root = client.attach("", "").await.unwrap();
+            let statfs = client.statfs(&root).await.unwrap();
+            let host = nix::sys::statvfs::statvfs(temp_dir.path()).unwrap();
+            assert_eq!(statfs.typ, V9FS_MAGIC);
+            assert_eq!(
+                (statfs.bsize as u64, statfs.blocks, statfs.files),
+                (host.fragment_size(), host.blocks(), host.files())
+            );
+
+            // Hard links take no more space
+            std::fs::hard_link(temp_dir.path().join("file"), temp_dir.path().join("link")).unwrap();
+            let srv = InprocServer::builder(temp_dir.path())
+                .virtual_capacity(64 << 20)
+                .build();
+            let client = P9Client::connect(srv.attach_client(16384)).await.unwrap();
+            let root = client.attach("", "").await.unwrap();
+            let statfs = client.statfs(&root).await.unwrap();
+            assert_eq!((statfs.bsize, statfs.blocks), (4096, 16384));
+            assert!(statfs.bavail <= 16384 - 256);
+            assert!(statfs.bavail > 16384 - 512);
+            assert_eq!(statfs.bfree, statfs.bavail);
+
+            // Space used is not scanned again for each request
+            std::fs::write(temp_dir.path().join("other"), vec![1; 1 << 20]).unwrap();
+            let cached = client.statfs(&root).await.unwrap();
+            assert_eq!((cached.bfree, cached.bavail), (statfs.bfree, statfs.bavail));
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// A fid whose clunk fails is still released and may be used again
+    async fn failed_clunk_releases_fid() {
+        use crate::core::error::errno::{EBADF, EINVAL};
+
+        run_test(async {
+            let temp_dir = tempdir::TempDir::new("failed_clunk_releases_fid").unwrap();
+            std::fs::write(temp_dir.path().join("file"), b"content").unwrap();
+            let srv = InprocServer::builder(temp_dir.path())
+                .virtual_xattrs(true)
+                .build();
+            let mut fs_adapter = FSAdapter::new(&srv);
+
+            let version = Fcall::Tversion {
+                msize: 8192,
+                version: "9P2000.L".to_string(),
+            };
+            call(&mut fs_adapter, NOTAG, version).await;
+            let attach = Fcall::Tattach {
+                fid: 1,
+                afid: NOFID,
+                uname: "".to_string(),
+                aname: "".to_string(),
+                n_uname: 0,
+            };
+            call(&mut fs_adapter, 1, attach).await;
+
+            // Value shorter than announced fails to be set when the fid is clunked
+            let walk = Fcall::Twalk {
+                fid: 1,
+                newfid: 2,
+                wnames: vec!["file".to_string()],
+            };
+            call(&mut fs_adapter, 1, walk.clone()).await;
+            let create = Fcall::Txattrcreate {
+                fid: 2,
+                name: "user.test".to_string(),
+                attr_size: 5,
+                flags: 0,
+            };
+            assert_eq!(call(&mut fs_adapter, 1, create).await, Fcall::Rxattrcreate);
+            let write = Fcall::Twrite {
+                fid: 2,
+                offset: 0,
+                data: Data(b"abc".to_vec().into()),
+            };
+            assert_eq!(
+                call(&mut fs_adapter, 1, write).await,
+                Fcall::Rwrite { count: 3 }
+            );
+            assert_eq!(
+                call(&mut fs_adapter, 1, Fcall::Tclunk { fid: 2 }).await,
+                Fcall::Rlerror {
+                    ecode: EINVAL as u32
+                }
+            );
+            assert_eq!(
+                call(&mut fs_adapter, 1, Fcall::Tclunk { fid: 2 }).await,
+                Fcall::Rlerror {
+                    ecode: EBADF as u32
+                }
+            );
+
+            assert!(matches!(
+                call(&mut fs_adapter, 1, walk).await,
+                Fcall::Rwalk { .. }
+            ));
+            assert!(matches!(
+                call(&mut fs_adapter, 1, Fcall::Tlopen { fid: 2, flags: 0 }).await,
+                Fcall::Rlopen { .. }
+            ));
+            let read = Fcall::Tread {
+                fid: 2,
+                offset: 0,
+                count: 100,
+            };
+            match call(&mut fs_adapter, 1, read).await {
+                Fcall::Rread { data } => assert_eq!(&data.0[..], b"content"),
+                other => panic!("Invalid response {other:?}"),
+            }
+        })
+        .await
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    /// Extended attributes are kept on the host files, or in the server when virtual
+    async fn xattrs_are_kept_on_host_or_virtually() {
+        use crate::core::client::P9Client;
+        use crate::core::error::errno::{EEXIST, ENODATA};
+        use crate::core::fcall::{XATTR_CREATE, XATTR_REPLACE};
+        use crate::implementation::xattr;
+
+        run_test(async {
+            let temp_dir = tempdir::TempDir::new("xattrs_are_kept_on_host_or_virtually").unwrap();
+            let file = temp_dir.path().join("file");
+            std::fs::write(&file, b"data").unwrap();
+
+            for virtual_xattrs in [false, true] {
+                let srv = InprocServer::builder(temp_dir.path())
+                    .virtual_xattrs(virtual_xattrs)
+                    .build();
+                let client = P9Client::connect(srv.attach_client(16384)).await.unwrap();
+                let root = client.attach("", "").await.unwrap();
+                let node = client.walk(&root, &["file"]).await.unwrap();
+
+                client
+                    .setxattr(&node, "user.test", b"value", XATTR_CREATE)
+                    .await
+                    .unwrap();
+                assert_eq!(client.getxattr(&node, "user.test").await.unwrap(), b"value");
+                assert_eq!(client.getxattr(&node, "").await.unwrap(), b"user.test\0");
+                assert_eq!(
+                    xattr::get(&file, "user.test").ok(),
+                    (!virtual_xattrs).then(|| b"value".to_vec())
+                );
+
+                let e = client
+                    .setxattr(&node, "user.test", b"other", XATTR_CREATE)
+                    .await
+                    .unwrap_err();
+                assert_eq!(raw_errno(e), Some(EEXIST as i32));
+                let e = client
+                    .setxattr(&node, "user.missing", b"other", XATTR_REPLACE)
+                    .await
+                    .unwrap_err();
+                assert_eq!(raw_errno(e), Some(ENODATA as i32));
+                let e = client.getxattr(&node, "user.missing").await.unwrap_err();
+                assert_eq!(raw_errno(e), Some(ENODATA as i32));
+
+                client.setxattr(&node, "user.test", b"", 0).await.unwrap();
+                assert!(client.getxattr(&node, "").await.unwrap().is_empty());
+                assert!(xattr::list(&file).unwrap().is_empty());
+            }
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Byte-range locks of one connection are seen by others and go away with the fid
+    async fn byte_range_locks_are_shared_by_connections() {
+        use crate::core::client::{ClientFid, P9Client};
+        use crate::core::fcall::{Flock, Getlock, LockFlag, LockStatus, LockType};
+
+        async fn open(client: &P9Client) -> ClientFid {
+            let root = client.attach("", "").await.unwrap();
+            let mut file = client.walk(&root, &["file"]).await.unwrap();
+            client.open(&mut file, 2).await.unwrap();
+            file
+        }
+
+        async fn lock(
+            (client, file, client_id): (&P9Client, &ClientFid, &str),
+            typ: LockType,
+            start: u64,
+            length: u64,
+        ) -> LockStatus {
+            let flock = Flock {
+                typ,
+                flags: LockFlag::BLOCK,
+                start,
+                length,
+                proc_id: 1,
+                client_id: client_id.to_owned(),
+            };
+            client.lock(file, flock).await.unwrap()
+        }
+
+        fn whole_file(client_id: &str) -> Getlock {
+            Getlock {
+                typ: LockType::WRLOCK,
+                start: 0,
+                length: 0,
+                proc_id: 1,
+                client_id: client_id.to_owned(),
+            }
+        }
+
+        run_test(async {
+            let temp_dir =
+                tempdir::TempDir::new("byte_range_locks_are_shared_by_connections").unwrap();
+            std::fs::write(temp_dir.path().join("file"), b"data").unwrap();
+            let srv = InprocServer::builder(temp_dir.path()).build();
+
+            let a_client = P9Client::connect(srv.attach_client(16384)).await.unwrap();
+            let b_client = P9Client::connect(srv.attach_client(16384)).await.unwrap();
+            let a_file = open(&a_client).await;
+            let b_file = open(&b_client).await;
+            let a = (&a_client, &a_file, "a");
+            let b = (&b_client, &b_file, "b");
+
+            assert_eq!(lock(a, LockType::WRLOCK, 0, 100).await, LockStatus::SUCCESS);
+            assert_eq!(
+                lock(a, LockType::RDLOCK, 200, 10).await,
+                LockStatus::SUCCESS
+            );
+            assert_eq!(lock(b, LockType::RDLOCK, 50, 10).await, LockStatus::BLOCKED);
+            assert_eq!(lock(b, LockType::RDLOCK, 100, 0).await, LockStatus::SUCCESS);
+            assert_eq!(lock(b, LockType::WRLOCK, 100, 0).await, LockStatus::BLOCKED);
+
+            let conflict = b_client.getlock(&b_file, whole_file("b")).await.unwrap();
+            assert_eq!(
+                conflict,
+                Getlock {
+                    length: 100,
+                    ..whole_file("a")
+                }
+            );
+
+            // Unlocking part of a range keeps the rest locked
+            assert_eq!(lock(a, LockType::UNLOCK, 0, 50).await, LockStatus::SUCCESS);
+            assert_eq!(lock(b, LockType::RDLOCK, 0, 50).await, LockStatus::SUCCESS);
+            assert_eq!(lock(b, LockType::RDLOCK, 60, 1).await, LockStatus::BLOCKED);
+
+            a_client.clunk(a_file).await.unwrap();
+            assert_eq!(lock(b, LockType::WRLOCK, 0, 0).await, LockStatus::SUCCESS);
+            let a_file = open(&a_client).await;
+            let conflict = a_client.getlock(&a_file, whole_file("a")).await.unwrap();
+            assert_eq!(conflict, whole_file("b"));
+            let free = b_client.getlock(&b_file, whole_file("b")).await.unwrap();
+            assert_eq!(free.typ, LockType::UNLOCK);
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Truncating, appending and exclusive creation asked by clients happen on the host files
+    async fn open_flags_apply_to_host_files() {
+        use crate::core::client::P9Client;
+        use crate::core::error::errno::EEXIST;
+        use crate::core::fcall::FileOpenMode;
+
+        const WRONLY: u32 = FileOpenMode::P9_DOTL_WRONLY.bits();
+        const CREATE: u32 = FileOpenMode::P9_DOTL_CREATE.bits();
+        const EXCL: u32 = FileOpenMode::P9_DOTL_EXCL.bits();
+        const TRUNC: u32 = FileOpenMode::P9_DOTL_TRUNC.bits();
+        const APPEND: u32 = FileOpenMode::P9_DOTL_APPEND.bits();
+
+        run_test(async {
+            let temp_dir = tempdir::TempDir::new("open_flags_apply_to_host_files").unwrap();
+            let path = temp_dir.path();
+            std::fs::write(path.join("truncated"), b"content").unwrap();
+            std::fs::write(path.join("appended"), b"first").unwrap();
+            std::fs::write(path.join("kept"), b"content").unwrap();
+            let srv = InprocServer::builder(path).build();
+
+            let client = P9Client::connect(srv.attach_client(16384)).await.unwrap();
+            let root = client.attach("", "").await.unwrap();
+
+            let mut file = client.walk(&root, &["truncated"]).await.unwrap();
+            client.open(&mut file, WRONLY | TRUNC).await.unwrap();
+            assert_eq!(std::fs::read(path.join("truncated")).unwrap(), b"");
+
+            // Appends land at the end of file whatever the offset
+            let mut file = client.walk(&root, &["appended"]).await.unwrap();
+            client.open(&mut file, WRONLY | APPEND).await.unwrap();
+            client.write(&file, 0, b"second").await.unwrap();
+            assert_eq!(
+                std::fs::read(path.join("appended")).unwrap(),
+                b"firstsecond"
+            );
+
+            let mut file = client.walk(&root, &["kept"]).await.unwrap();
+            let e = client
+                .open(&mut file, WRONLY | CREATE | EXCL)
+                .await
+                .unwrap_err();
+            assert_eq!(raw_errno(e), Some(EEXIST as i32));
+
+            let mut dir = client.walk(&root, &[]).await.unwrap();
+            let e = client
+                .create(&mut dir, "kept", WRONLY | CREATE | EXCL, 0o644, 0)
+                .await
+                .unwrap_err();
+            assert_eq!(raw_errno(e), Some(EEXIST as i32));
+            client
+                .create(&mut dir, "kept", WRONLY | CREATE, 0o644, 0)
+                .await
+                .unwrap();
+            assert_eq!(std::fs::read(path.join("kept")).unwrap(), b"content");
+
+            let mut dir = client.walk(&root, &[]).await.unwrap();
+            client
+                .create(&mut dir, "new", WRONLY | CREATE | EXCL, 0o644, 0)
+                .await
+                .unwrap();
+            client.write(&dir, 0, b"new").await.unwrap();
+            assert_eq!(std::fs::read(path.join("new")).unwrap(), b"new");
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Opening refuses directories for writing, and files of other type than asked for
+    async fn open_flags_check_file_types() {
+        use crate::core::client::P9Client;
+        use crate::core::error::errno::{EINVAL, EISDIR, ELOOP, ENOTDIR};
+        use crate::core::fcall::FileOpenMode;
+
+        const WRONLY: u32 = FileOpenMode::P9_DOTL_WRONLY.bits();
+        const RDWR: u32 = FileOpenMode::P9_DOTL_RDWR.bits();
+        const NOACCESS: u32 = FileOpenMode::P9_DOTL_NOACCESS.bits();
+        const TRUNC: u32 = FileOpenMode::P9_DOTL_TRUNC.bits();
+        const DIRECTORY: u32 = FileOpenMode::P9_DOTL_DIRECTORY.bits();
+        const NOFOLLOW: u32 = FileOpenMode::P9_DOTL_NOFOLLOW.bits();
+
+        run_test(async {
+            let temp_dir = tempdir::TempDir::new("open_flags_check_file_types").unwrap();
+            let path = temp_dir.path();
+            std::fs::create_dir(path.join("dir")).unwrap();
+            std::fs::write(path.join("file"), b"content").unwrap();
+            let srv = InprocServer::builder(path).build();
+
+            let client = P9Client::connect(srv.attach_client(16384)).await.unwrap();
+            let root = client.attach("", "").await.unwrap();
+
+            for flags in [WRONLY, RDWR, TRUNC] {
+                let mut dir = client.walk(&root, &["dir"]).await.unwrap();
+                let e = client.open(&mut dir, flags).await.unwrap_err();
+                assert_eq!(raw_errno(e), Some(EISDIR as i32));
+            }
+            let mut dir = client.walk(&root, &["dir"]).await.unwrap();
+            client.open(&mut dir, DIRECTORY).await.unwrap();
+
+            let mut file = client.walk(&root, &["file"]).await.unwrap();
+            let e = client.open(&mut file, DIRECTORY).await.unwrap_err();
+            assert_eq!(raw_errno(e), Some(ENOTDIR as i32));
+            let e = client.open(&mut file, NOACCESS).await.unwrap_err();
+            assert_eq!(raw_errno(e), Some(EINVAL as i32));
+            client.open(&mut file, NOFOLLOW).await.unwrap();
+            assert_eq!(client.read(&file, 0, 100).await.unwrap(), b"content");
+
+            #[cfg(unix)]
+            {
+                std::os::unix::fs::symlink("file", path.join("link")).unwrap();
+                let mut link = client.walk(&root, &["link"]).await.unwrap();
+                let e = client.open(&mut link, NOFOLLOW).await.unwrap_err();
+                assert_eq!(raw_errno(e), Some(ELOOP as i32));
+            }
+            #[cfg(target_os = "linux")]
+            {
+                let mut dir = client.walk(&root, &[]).await.unwrap();
+                let e = client
+                    .create(&mut dir, "link", WRONLY | NOFOLLOW, 0o644, 0)
+                    .await
+                    .unwrap_err();
+                assert_eq!(raw_errno(e), Some(ELOOP as i32));
+            }
+        })
+        .await
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    /// Synchronous writes, keeping access time and non-blocking mode are asked of the host
+    async fn open_flags_reach_host_descriptors() {
+        use crate::core::client::P9Client;
+        use crate::core::fcall::FileOpenMode;
+
+        /// Flags of descriptors the process has open for `path`
+        fn host_flags(path: &std::path::Path) -> Vec<i32> {
+            let mut flags = vec![];
+            for fd in std::fs::read_dir("/proc/self/fd").unwrap() {
+                let fd = fd.unwrap();
+                if std::fs::read_link(fd.path()).ok().as_deref() != Some(path) {
+                    continue;
+                }
+                let info = std::fs::read_to_string(
+                    std::path::Path::new("/proc/self/fdinfo").join(fd.file_name()),
+                );
+                let info = match info {
+                    Ok(info) => info,
+                    Err(_) => continue,
+                };
+                for line in info.lines() {
+                    if let Some(octal) = line.strip_prefix("flags:") {
+                        flags.push(i32::from_str_radix(octal.trim(), 8).unwrap());
+                    }
+                }
+            }
+            flags
+        }
+
+        run_test(async {
+            let temp_dir = tempdir::TempDir::new("open_flags_reach_host_descriptors").unwrap();
+            let export = temp_dir.path().canonicalize().unwrap();
+            let srv = InprocServer::builder(&export).build();
+
+            let client = P9Client::connect(srv.attach_client(16384)).await.unwrap();
+            let root = client.attach("", "").await.unwrap();
+
+            let cases = [
+                (FileOpenMode::P9_DOTL_SYNC, libc::O_SYNC),
+                (FileOpenMode::P9_DOTL_DSYNC, libc::O_DSYNC),
+                (FileOpenMode::P9_DOTL_NOATIME, libc::O_NOATIME),
+                (FileOpenMode::P9_DOTL_NONBLOCK, libc::O_NONBLOCK),
+            ];
+            for (i, (flag, host_flag)) in cases.into_iter().enumerate() {
+                let name = format!("file{}", i);
+                std::fs::write(export.join(&name), b"content").unwrap();
+
+                let mut file = client.walk(&root, &[&name]).await.unwrap();
+                client
+                    .open(&mut file, (FileOpenMode::P9_DOTL_RDWR | flag).bits())
+                    .await
+                    .unwrap();
+                let flags = host_flags(&export.join(&name));
+                assert_eq!(flags.len(), 1, "{:?}", flag);
+                assert_eq!(flags[0] & host_flag, host_flag, "{:?}", flag);
+
+                client.clunk(file).await.unwrap();
+                assert!(host_flags(&export.join(&name)).is_empty());
+            }
+        })
+        .await
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    /// Files of other users are opened without NOATIME rather than refused
+    async fn noatime_is_dropped_for_files_of_others() {
+        use tokio::io::AsyncReadExt;
+
+        use crate::core::fcall::FileOpenMode;
+        use crate::implementation::utils::open_file;
+
+        run_test(async {
+            // Owned by root, refuses NOATIME unless the server runs as root
+            let path = std::path::Path::new("/etc/passwd");
+            let mut file = open_file(path, FileOpenMode::P9_DOTL_NOATIME)
+                .await
+                .unwrap();
+
+            let mut content = vec![];
+            file.read_to_end(&mut content).await.unwrap();
+            assert_eq!(content, std::fs::read(path).unwrap());
+        })
+        .await
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    /// Paths which are not UTF-8 on the host keep their attributes
+    async fn exports_paths_which_are_not_utf8() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        use crate::core::client::P9Client;
+        use crate::core::fcall::{FileOpenMode, GetattrMask, SetAttr, SetattrMask, Time, S_IFREG};
+
+        run_test(async {
+            let temp_dir = tempdir::TempDir::new("exports_paths_which_are_not_utf8").unwrap();
+            let export = temp_dir.path().join(OsStr::from_bytes(b"export\xff"));
+            std::fs::create_dir(&export).unwrap();
+            let srv = InprocServer::builder(&export).virtual_xattrs(true).build();
+
+            let client = P9Client::connect(srv.attach_client(16384)).await.unwrap();
+            let root = client.attach("", "").await.unwrap();
+            let mut file = client.walk(&root, &[]).await.unwrap();
+            client
+                .create(
+                    &mut file,
+                    "file",
+                    FileOpenMode::P9_DOTL_RDWR.bits(),
+                    0o644,
+                    0,
+                )
+                .await
+                .unwrap();
+            client
+                .setattr(
+                    &file,
+                    SetattrMask::MODE,
+                    SetAttr {
+                        mode: S_IFREG | 0o600,
+                        uid: 0,
+                        gid: 0,
+                        size: 0,
+                        atime: Time { sec: 0, nsec: 0 },
+                        mtime: Time { sec: 0, nsec: 0 },
+                    },
+                )
+                .await
+                .unwrap();
+            client
+                .setxattr(&file, "user.test", b"value", 0)
+                .await
+                .unwrap();
+            client.link(&root, &file, "link").await.unwrap();
+            client
+                .renameat(&root, "link", &root, "moved")
+                .await
+                .unwrap();
+
+            let moved = client.walk(&root, &["moved"]).await.unwrap();
+            let stat = client.getattr(&moved, GetattrMask::BASIC).await.unwrap();
+            assert_eq!(stat.mode, S_IFREG | 0o600);
+            assert_eq!(
+                client.getxattr(&moved, "user.test").await.unwrap(),
+                b"value"
+            );
+            client.unlinkat(&root, "moved", 0).await.unwrap();
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Files and directories made by clients get the mode and group asked for
+    async fn create_and_mkdir_apply_mode_and_gid() {
+        use crate::core::client::P9Client;
+        use crate::core::fcall::{FileOpenMode, GetattrMask, S_IFDIR, S_IFREG};
+
+        const WRONLY: u32 = FileOpenMode::P9_DOTL_WRONLY.bits();
+
+        run_test(async {
+            let temp_dir = tempdir::TempDir::new("create_and_mkdir_apply_mode_and_gid").unwrap();
+            std::fs::write(temp_dir.path().join("existing"), b"content").unwrap();
+            let srv = InprocServer::builder(temp_dir.path()).build();
+
+            let client = P9Client::connect(srv.attach_client(16384)).await.unwrap();
+            let root = client.attach("", "").await.unwrap();
+
+            for (name, mode, gid) in [("secret", 0o600, 1234), ("script", 0o755, 0)] {
+                let mut file = client.walk(&root, &[]).await.unwrap();
+                client
+                    .create(&mut file, name, WRONLY, S_IFREG | mode, gid)
+                    .await
+                    .unwrap();
+                let stat = client.getattr(&file, GetattrMask::BASIC).await.unwrap();
+                assert_eq!((stat.mode, stat.gid), (S_IFREG | mode, gid));
+            }
+
+            client.mkdir(&root, "dir", 0o1750, 55).await.unwrap();
+            let dir = client.walk(&root, &["dir"]).await.unwrap();
+            let stat = client.getattr(&dir, GetattrMask::BASIC).await.unwrap();
+            assert_eq!((stat.mode, stat.gid), (S_IFDIR | 0o1750, 55));
+
+            // The file type is that of the host file, not one given in mode
+            let mut file = client.walk(&root, &[]).await.unwrap();
+            client
+                .create(&mut file, "typed", WRONLY, S_IFDIR | 0o640, 0)
+                .await
+                .unwrap();
+            let stat = client.getattr(&file, GetattrMask::BASIC).await.unwrap();
+            assert_eq!(stat.mode, S_IFREG | 0o640);
+
+            // Files which already exist are only opened
+            let existing = client.walk(&root, &["existing"]).await.unwrap();
+            let before = client.getattr(&existing, GetattrMask::BASIC).await.unwrap();
+            let mut file = client.walk(&root, &[]).await.unwrap();
+            client
+                .create(&mut file, "existing", WRONLY, S_IFREG | 0o600, 1234)
+                .await
+                .unwrap();
+            let stat = client.getattr(&file, GetattrMask::BASIC).await.unwrap();
+            assert_eq!((stat.mode, stat.gid), (before.mode, before.gid));
+        })
+        .await
+    }
+
+    #[tokio::test]
+    /// Owners set by clients are kept per file in the server, host files keep theirs
+    async fn chown_is_emulated_per_file() {
+        use crate::core::client::P9Client;
+        use crate::core::fcall::{FileOpenMode, GetattrMask, SetAttr, SetattrMask, Time};
+
+        const WRONLY: u32 = FileOpenMode::P9_DOTL_WRONLY.bits();
+
+        fn owner(uid: u32, gid: u32) -> SetAttr {
+            SetAttr {
+                mode: 0,
+                uid,
+                gid,
+                size: 0,
+                atime: Time { sec: 0, nsec: 0 },
+                mtime: Time { sec: 0, nsec: 0 },
+            }
+        }
+
+        run_test(async {
+            let temp_dir = tempdir::TempDir::new("chown_is_emulated_per_file").unwrap();
+            let srv = InprocServer::builder(temp_dir.path()).build();
+
+            // Files belong to the uid clients attached with, or to the default user without one
+            let client = P9Client::connect(srv.attach_client(16384)).await.unwrap();
+            let root = client.attach_with_uid("", "", 1001).await.unwrap();
+            let dir = client.walk(&root, &[]).await.unwrap();
+            client.mkdir(&dir, "dir", 0o755, 1002).await.unwrap();
+            let mut file = client.walk(&root, &["dir"]).await.unwrap();
+            client
+                .create(&mut file, "file", WRONLY, 0o644, 1002)
+                .await
+                .unwrap();
+            let stat = client.getattr(&file, GetattrMask::BASIC).await.unwrap();
+            assert_eq!((stat.uid, stat.gid), (1001, 1002));
+            let dir = client.walk(&root, &["dir"]).await.unwrap();
+            let stat = client.getattr(&dir, GetattrMask::BASIC).await.unwrap();
+            assert_eq!((stat.uid, stat.gid), (1001, 1002));
+
+            let anonymous = client.attach("", "").await.unwrap();
+            let mut other = client.walk(&anonymous, &[]).await.unwrap();
+            client
+                .create(&mut other, "other", WRONLY, 0o644, 1002)
+                .await
+                .unwrap();
+            let stat = client.getattr(&other, GetattrMask::BASIC).await.unwrap();
+            assert_eq!((stat.uid, stat.gid), (1000, 1002));
+
+            client
+                .setattr(&file, SetattrMask::UID, owner(0, 5))
+                .await
+                .unwrap();
+            let stat = client.getattr(&file, GetattrMask::BASIC).await.unwrap();
+            assert_eq!((stat.uid, stat.gid), (0, 1002));
+            client
+                .setattr(&file, SetattrMask::GID, owner(7, 0))
+                .await
+                .unwrap();
+
+            let client = P9Client::connect(srv.attach_client(16384)).await.unwrap();
+            let root = client.attach("", "").await.unwrap();
+            let file = client.walk(&root, &["dir", "file"]).await.unwrap();
+            let stat = client.getattr(&file, GetattrMask::BASIC).await.unwrap();
+            assert_eq!((stat.uid, stat.gid), (0, 0));
+
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+
+                let host_owner = std::fs::metadata(temp_dir.path()).unwrap();
+                let host = std::fs::metadata(temp_dir.path().join("dir/file")).unwrap();
+                assert_eq!(
+                    (host.uid(), host.gid()),
+                    (host_owner.uid(), host_owner.gid())
+                );
+            }
+        })
+        .await
+    }
+
+    #[cfg(feature = "tls")]
+    mod tls {
+        use std::path::Path;
+
+        use rcgen::{BasicConstraints, Certificate, CertificateParams, IsCa};
+        use tokio::net::TcpStream;
+        use tokio_rustls::rustls::{self, RootCertStore};
+        use tokio_rustls::{client::TlsStream, TlsConnector};
+
+        use crate::core::srv::{srv_async_with_config, ServerConfig};
+        use crate::core::tls::TlsConfig;
+
+        use super::*;
+
+        /// CA signing both server and client certificates
+        struct Pki {
+            ca: Certificate,
+            server: Certificate,
+            client: Certificate,
+        }
+
+        impl Pki {
+            fn new() -> Self {
+                let mut ca_params = CertificateParams::new(vec![]);
+                ca_params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+
+                Self {
+                    ca: Certificate::from_params(ca_params).unwrap(),
+                    server: rcgen::generate_simple_self_signed(vec!["localhost".into()]).unwrap(),
+                    client: rcgen::generate_simple_self_signed(vec!["client".into()]).unwrap(),
+                }
+            }
+
+            /// Writes PEM files for the server, verifying clients if `verify_clients` is set
+            fn server_config(&self, dir: &Path, verify_clients: bool) -> TlsConfig {
+                let cert_path = dir.join("server.pem");
+                let key_path = dir.join("server.key");
+                let ca_path = dir.join("ca.pem");
+                std::fs::write(
+                    &cert_path,
+                    self.server.serialize_pem_with_signer(&self.ca).unwrap(),
+                )
+                .unwrap();
+                std::fs::write(&key_path, self.server.serialize_private_key_pem()).unwrap();
+                std::fs::write(&ca_path, self.ca.serialize_pem().unwrap()).unwrap();
+
+                TlsConfig {
+                    cert_path,
+                    key_path,
+                    client_ca_path: verify_clients.then(|| ca_path),
+                    handshake_timeout: None,
+                }
+            }
+
+            fn connector(&self, with_client_cert: bool) -> TlsConnector {
+                let mut roots = RootCertStore::empty();
+                roots
+                    .add(&rustls::Certificate(self.ca.serialize_der().unwrap()))
+                    .unwrap();
+                let builder = rustls::ClientConfig::builder()
+                    .with_safe_defaults()
+                    .with_root_certificates(roots);
+
+                let config = if with_client_cert {
+                    builder
+                        .with_client_auth_cert(
+                            vec![rustls::Certificate(
+                                self.client.serialize_der_with_signer(&self.ca).unwrap(),
+                            )],
+                            rustls::PrivateKey(self.client.serialize_private_key_der()),
+                        )
+                        .unwrap()
+                } else {
+                    builder.with_no_client_auth()
+                };
+
+                TlsConnector::from(Arc::new(config))
+            }
+        }
+
+        fn start_server(root: &Path, tls: TlsConfig) -> String {
+            let config = ServerConfig {
+                tls: Some(tls),
+                ..Default::default()
+            };
+            start_server_with_config(root, config)
+        }
+
+        fn start_server_with_config(root: &Path, config: ServerConfig) -> String {
+            let address = free_tcp_address();
+
+            let filesystem = Unpfs {
+                realroot: root.to_path_buf(),
+                vap: Arc::new(Mutex::new(VirtualAttributesProvider::new())),
+                options: Default::default(),
+                locks: Default::default(),
+                usage: Default::default(),
+            };
+            let listen_address = address.clone();
+            tokio::spawn(async move {
+                srv_async_with_config(filesystem, "tcp", &listen_address, &config).await
+            });
+
+            address
+        }
+
+        async fn connect(
+            address: &str,
+            connector: &TlsConnector,
+        ) -> std::io::Result<TlsStream<TcpStream>> {
+            let stream = loop {
+                match TcpStream::connect(address).await {
+                    Ok(stream) => break stream,
+                    Err(_) => tokio::time::sleep(std::time::Duration::from_millis(10)).await,
+                }
+            };
+            let server_name = rustls::ServerName::try_from("localhost").unwrap();
+            connector.connect(server_name, stream).await
+        }
+
+        fn version_request() -> Msg {
+            Msg {
+                tag: NOTAG,
+                body: Fcall::Tversion {
+                    msize: 8192,
+                    version: "9P2000.L".to_string(),
+                },
+            }
+        }
+
+        #[tokio::test]
+        /// Serve TCP wrapped in TLS using self signed certificates
+        async fn can_connect_over_tls() {
+            run_test(async {
+                let temp_dir = tempdir::TempDir::new("can_connect_over_tls").unwrap();
+                let pki = Pki::new();
+                let address =
+                    start_server(temp_dir.path(), pki.server_config(temp_dir.path(), false));
+
+                let stream = connect(&address, &pki.connector(false)).await.unwrap();
+                let mut fs_adapter = FSAdapter::from_stream(stream);
+
+                fs_adapter.send(&version_request()).await.unwrap();
+                assert!(matches!(
+                    fs_adapter.receive().await.unwrap().body,
+                    Fcall::Rversion { msize: 8192, .. }
+                ));
+            })
+            .await
+        }
+
+        #[tokio::test]
+        /// Clients without certificate signed by the client CA are turned away
+        async fn tls_verifies_client_certificates() {
+            run_test(async {
+                let temp_dir = tempdir::TempDir::new("tls_verifies_client_certificates").unwrap();
+                let pki = Pki::new();
+                let address =
+                    start_server(temp_dir.path(), pki.server_config(temp_dir.path(), true));
+
+                // With TLS 1.3 client learns about rejection only when reading
+                if let Ok(stream) = connect(&address, &pki.connector(false)).await {
+                    let mut fs_adapter = FSAdapter::from_stream(stream);
+                    let _ = fs_adapter.send(&version_request()).await;
+                    assert!(fs_adapter.receive().await.is_err());
+                }
+
+                let stream = connect(&address, &pki.connector(true)).await.unwrap();
+                let mut fs_adapter = FSAdapter::from_stream(stream);
+
+                fs_adapter.send(&version_request()).await.unwrap();
+                assert!(matches!(
+                    fs_adapter.receive().await.unwrap().body,
+                    Fcall::Rversion { msize: 8192, .. }
+                ));
+            })
+            .await
+        }
+
+        #[tokio::test]
+        /// Clients which never finish the handshake lose their connection slot
+        async fn tls_handshake_times_out() {
+            use tokio::io::AsyncReadExt;
+
+            run_test(async {
+                let temp_dir = tempdir::TempDir::new("tls_handshake_times_out").unwrap();
+                let pki = Pki::new();
+                let tls = TlsConfig {
+                    handshake_timeout: Some(Duration::from_millis(100)),
+                    ..pki.server_config(temp_dir.path(), false)
+                };
+                let config = ServerConfig {
+                    tls: Some(tls),
+                    max_connections: Some(1),
+                    ..Default::default()
+                };
+                let address = start_server_with_config(temp_dir.path(), config);
+
+                let mut stalled = loop {
+                    match TcpStream::connect(&address).await {
+                        Ok(stream) => break stream,
+                        Err(_) => tokio::time::sleep(Duration::from_millis(10)).await,
+                    }
+                };
+
+                let stream = connect(&address, &pki.connector(false)).await.unwrap();
+                let mut fs_adapter = FSAdapter::from_stream(stream);
+                fs_adapter.send(&version_request()).await.unwrap();
+                assert!(matches!(
+                    fs_adapter.receive().await.unwrap().body,
+                    Fcall::Rversion { msize: 8192, .. }
+                ));
+
+                let mut buf = [0; 1];
+                assert_eq!(stalled.read(&mut buf).await.unwrap(), 0);
+            })
+            .await
+        }
+    }
 }
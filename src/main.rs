@@ -5,8 +5,9 @@ mod input_args;
 
 use crate::core::attributes_cache::*;
 use crate::core::lib_utils::Result;
-use crate::core::srv::srv_async;
-use crate::implementation::unpfs::Unpfs;
+use crate::core::srv::{srv_async_with_config, ServerConfig};
+use crate::core::tls::TlsConfig;
+use crate::implementation::unpfs::{Unpfs, UnpfsOptions};
 use input_args::ServerOptions;
 use log::LevelFilter;
 use log4rs::append::file::FileAppender;
@@ -49,13 +50,37 @@ async fn unpfs_main(server_options: ServerOptions) -> Result<i32> {
         server_options.network_protocol,
         server_options.network_address
     );
-    srv_async(
+    let tls = server_options.tls_cert.map(|cert_path| TlsConfig {
+        cert_path,
+        key_path: server_options.tls_key.unwrap_or_default(),
+        client_ca_path: server_options.tls_client_ca,
+        handshake_timeout: None,
+    });
+    let config = ServerConfig {
+        socket_mode: server_options.socket_mode,
+        mount_tag: server_options.mount_tag,
+        max_msize: server_options.max_msize,
+        max_requests_per_connection: server_options.max_requests_per_connection,
+        max_requests: server_options.max_requests,
+        max_connections: server_options.max_connections,
+        read_only: server_options.read_only,
+        tls,
+    };
+    srv_async_with_config(
         Unpfs {
             realroot: server_options.mount_point.into(),
             vap: Arc::new(Mutex::new(VirtualAttributesProvider::new())),
+            options: UnpfsOptions {
+                emulate_devices: server_options.emulate_devices,
+                virtual_capacity: server_options.virtual_capacity,
+                virtual_xattrs: server_options.virtual_xattrs,
+            },
+            locks: Default::default(),
+            usage: Default::default(),
         },
         &server_options.network_protocol,
         &server_options.network_address,
+        &config,
     )
     .await
     .and(Ok(0))
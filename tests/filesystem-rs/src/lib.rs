@@ -13,6 +13,7 @@ use std::ffi::OsString;
 use std::fs::Metadata;
 use std::io::Result;
 use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 #[cfg(feature = "fake")]
 pub use fake::{FakeFileSystem, FakeTempDir};
@@ -185,6 +186,16 @@ pub trait FileSystem {
     fn metadata<P: AsRef<Path>>(&self, _path: P) -> Metadata {
         unimplemented!()
     }
+
+    /// Returns the last access time of the node at the path.
+    fn accessed<P: AsRef<Path>>(&self, path: P) -> Result<SystemTime> {
+        self.metadata(path).accessed()
+    }
+
+    /// Returns the last modification time of the node at the path.
+    fn modified<P: AsRef<Path>>(&self, path: P) -> Result<SystemTime> {
+        self.metadata(path).modified()
+    }
 }
 
 pub trait DirEntry {
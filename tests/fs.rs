@@ -1,6 +1,3 @@
-#![feature(io_error_more)]
-#![feature(io_error_uncategorized)]
-
 use std::io::ErrorKind;
 use std::path::{Path, PathBuf};
 use std::thread;
@@ -12,20 +9,23 @@ use filesystem_rs::{DirEntry, FileSystem, OsFileSystem, TempDir, TempFileSystem}
 
 use filesystem_rs::*;
 
+mod p9fs;
+use p9fs::P9FileSystem;
+
 macro_rules! make_test {
     ($test:ident, $fs:expr) => {
         #[test]
         fn $test() {
             let fs = $fs();
             let path = Path::new("./mnt_tests");
-            if (!path.exists()) {
+            if (!fs.is_dir(path)) {
                 let _unused = fs.create_dir(path);
             }
             let local: DateTime<Local> = Local::now();
             let str = local.format("%Y-%m-%d_%H-%M-%S-%f").to_string();
             let mut path_custom_test = path.join(Path::new(&str));
 
-            while (path_custom_test.exists()) {
+            while (fs.is_dir(&path_custom_test)) {
                 let local: DateTime<Local> = Local::now();
                 let str = local.format("%Y-%m-%d_%H-%M-%S-%f").to_string();
                 path_custom_test = path.join(Path::new(&str));
@@ -169,6 +169,7 @@ macro_rules! test_fs {
 }
 
 test_fs!(os, OsFileSystem::new);
+test_fs!(p9, P9FileSystem::new);
 //test_fs!(fake, FakeFileSystem::new);
 
 fn set_current_dir_fails_if_node_does_not_exists<T: FileSystem>(fs: &T, parent: &Path) {
@@ -333,10 +334,7 @@ fn remove_dir_fails_if_dir_is_not_empty<T: FileSystem>(fs: &T, parent: &Path) {
 
     assert!(result.is_err());
 
-    // io::ErrorKind::DirectoryNotEmpty is not in stable rust, hence srv cannot raise it.
-    // Instead Uncategorized is returned.
-    // assert_eq!(result.unwrap_err().kind(), ErrorKind::DirectoryNotEmpty);
-    assert_eq!(result.unwrap_err().kind(), ErrorKind::Uncategorized);
+    assert_eq!(result.unwrap_err().kind(), ErrorKind::DirectoryNotEmpty);
 
     assert!(fs.is_dir(&path));
     assert!(fs.is_file(&child));
@@ -1054,16 +1052,14 @@ fn newly_created_directory_has_correct_timestamps<T: FileSystem>(fs: &T, parent:
     assert!(result.is_ok());
 
     // Server is on the same machine as client, timestamps should be very similar
-    let meta = fs.metadata(&path);
-
-    let accessed = meta.accessed().unwrap().elapsed().unwrap();
+    let accessed = fs.accessed(&path).unwrap().elapsed().unwrap();
     assert!(
         accessed <= Duration::from_secs(1),
         "accessed elapsed time {}",
         accessed.as_secs()
     );
 
-    let modified = meta.modified().unwrap().elapsed().unwrap();
+    let modified = fs.modified(&path).unwrap().elapsed().unwrap();
 
     assert!(
         modified <= Duration::from_secs(1),
@@ -1078,16 +1074,14 @@ fn newly_created_file_has_correct_timestamps<T: FileSystem>(fs: &T, parent: &Pat
     assert!(result.is_ok());
 
     // Server is on the same machine as client, timestamps should be very similar
-    let meta = fs.metadata(&path);
-
-    let accessed = meta.accessed().unwrap().elapsed().unwrap();
+    let accessed = fs.accessed(&path).unwrap().elapsed().unwrap();
     assert!(
         accessed <= Duration::from_secs(1),
         "accessed elapsed time {}",
         accessed.as_secs()
     );
 
-    let modified = meta.modified().unwrap().elapsed().unwrap();
+    let modified = fs.modified(&path).unwrap().elapsed().unwrap();
 
     assert!(
         modified <= Duration::from_secs(1),
@@ -1109,11 +1103,8 @@ fn timestamps_has_less_than_second_resolution<T: FileSystem>(fs: &T, parent: &Pa
     let result = fs.create_file(&path_b, "");
     assert!(result.is_ok(), "File B creation result {:?}", result);
 
-    let meta_a = fs.metadata(&path_a);
-    let meta_b = fs.metadata(&path_b);
-
-    let accessed_a = meta_a.accessed().unwrap();
-    let accessed_b = meta_b.accessed().unwrap();
+    let accessed_a = fs.accessed(&path_a).unwrap();
+    let accessed_b = fs.accessed(&path_b).unwrap();
     let delta = accessed_b.duration_since(accessed_a).unwrap();
 
     // Make sure timestamps are less than one sec,
@@ -0,0 +1,631 @@
+//! `FileSystem` backed by `P9Client` talking to `InprocServer`.
+//!
+//! Runs the conformance suite against the server without mounting it.
+//! Unpfs keeps permission bits as attributes only and leaves enforcing them
+//! to the client, like the Linux kernel does for a mounted export.
+//! Here they are checked the same way for the owner of the file.
+
+use std::ffi::OsString;
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::future::{FutureExt, LocalBoxFuture};
+use tokio::runtime::Runtime;
+
+#[cfg(unix)]
+use filesystem_rs::UnixFileSystem;
+use filesystem_rs::{DirEntry, FileSystem, ReadDir, TempDir, TempFileSystem};
+use ya_vm_file_server::core::client::{ClientFid, P9Client};
+use ya_vm_file_server::core::error::{self, errno::*};
+use ya_vm_file_server::core::fcall::{
+    FileOpenMode, GetattrMask, QidType, SetAttr, SetattrMask, Stat, Time, AT_REMOVEDIR,
+};
+use ya_vm_file_server::InprocServer;
+
+/// Size of the in-memory pipe between client and server
+const PIPE_SIZE: usize = 65536;
+
+/// File type bits of `Stat::mode`
+const S_IFMT: u32 = 0o170000;
+
+/// Owner permission bits, as in `access(2)`
+const R_OK: u32 = 4;
+const W_OK: u32 = 2;
+const X_OK: u32 = 1;
+
+fn errno(errno: Errno) -> Error {
+    Error::from_raw_os_error(errno as i32)
+}
+
+fn io_error(e: error::Error) -> Error {
+    match e {
+        error::Error::Io(e) => e,
+        error::Error::No(e) => errno(e),
+    }
+}
+
+fn is_dir(fid: &ClientFid) -> bool {
+    fid.qid().typ.contains(QidType::DIR)
+}
+
+/// Fails with `EACCES` unless the owner of the file has all of `bits`
+fn access(stat: &Stat, bits: u32) -> Result<()> {
+    match (stat.mode >> 6) & bits {
+        granted if granted == bits => Ok(()),
+        _ => Err(errno(EACCES)),
+    }
+}
+
+fn system_time(time: Time) -> SystemTime {
+    UNIX_EPOCH + Duration::new(time.sec, time.nsec as u32)
+}
+
+#[derive(Debug)]
+pub struct P9DirEntry {
+    name: String,
+    path: PathBuf,
+}
+
+impl DirEntry for P9DirEntry {
+    fn file_name(&self) -> OsString {
+        self.name.clone().into()
+    }
+
+    fn path(&self) -> PathBuf {
+        self.path.clone()
+    }
+}
+
+#[derive(Debug)]
+pub struct P9ReadDir(std::vec::IntoIter<P9DirEntry>);
+
+impl Iterator for P9ReadDir {
+    type Item = Result<P9DirEntry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.0.next().map(Ok)
+    }
+}
+
+impl ReadDir<P9DirEntry> for P9ReadDir {}
+
+/// Temporary directory under `/tmp` of the export, removed on drop
+pub struct P9TempDir {
+    path: PathBuf,
+    fs: P9FileSystem,
+}
+
+impl TempDir for P9TempDir {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for P9TempDir {
+    fn drop(&mut self) {
+        let _ = self.fs.remove_dir_all(&self.path);
+    }
+}
+
+struct Inner {
+    client: P9Client,
+    root: ClientFid,
+    cwd: Mutex<PathBuf>,
+    temp_dirs: AtomicUsize,
+    _server: InprocServer,
+    _export: tempdir::TempDir,
+    runtime: Runtime,
+}
+
+/// Each instance exports its own temporary directory over a new connection
+#[derive(Clone)]
+pub struct P9FileSystem {
+    inner: Arc<Inner>,
+}
+
+impl P9FileSystem {
+    pub fn new() -> Self {
+        let export = tempdir::TempDir::new("p9fs").unwrap();
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let server = InprocServer::new(export.path().to_str().unwrap());
+
+        let (client, root) = runtime.block_on(async {
+            let client = P9Client::connect(server.attach_client(PIPE_SIZE))
+                .await
+                .unwrap();
+            let root = client.attach("", "").await.unwrap();
+            (client, root)
+        });
+
+        Self {
+            inner: Arc::new(Inner {
+                client,
+                root,
+                cwd: Mutex::new(PathBuf::from("/")),
+                temp_dirs: AtomicUsize::new(0),
+                _server: server,
+                _export: export,
+                runtime,
+            }),
+        }
+    }
+
+    fn block_on<F: Future>(&self, future: F) -> F::Output {
+        self.inner.runtime.block_on(future)
+    }
+}
+
+impl Inner {
+    /// Names walked from the root of the export to reach `path`
+    fn names(&self, path: &Path) -> Vec<String> {
+        let path = self.cwd.lock().unwrap().join(path);
+        let mut names = Vec::new();
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => names.push(name.to_string_lossy().into_owned()),
+                Component::ParentDir => {
+                    names.pop();
+                }
+                _ => {}
+            }
+        }
+        names
+    }
+
+    /// Clunks `fid` once done with it, keeping the first error
+    async fn clunking<T>(&self, fid: ClientFid, res: Result<T>) -> Result<T> {
+        let clunked = self.client.clunk(fid).await.map_err(io_error);
+        let value = res?;
+        clunked?;
+        Ok(value)
+    }
+
+    async fn walk(&self, names: &[String]) -> Result<ClientFid> {
+        let names: Vec<&str> = names.iter().map(String::as_str).collect();
+        self.client.walk(&self.root, &names).await.map_err(io_error)
+    }
+
+    async fn lookup(&self, path: &Path) -> Result<(ClientFid, Stat)> {
+        let fid = self.walk(&self.names(path)).await?;
+        match self.client.getattr(&fid, GetattrMask::BASIC).await {
+            Ok(stat) => Ok((fid, stat)),
+            Err(e) => self.clunking(fid, Err(io_error(e))).await,
+        }
+    }
+
+    async fn stat(&self, path: &Path) -> Result<Stat> {
+        let (fid, stat) = self.lookup(path).await?;
+        self.clunking(fid, Ok(stat)).await
+    }
+
+    async fn qid_type(&self, path: &Path) -> Result<QidType> {
+        let fid = self.walk(&self.names(path)).await?;
+        let typ = fid.qid().typ;
+        self.clunking(fid, Ok(typ)).await
+    }
+
+    /// Walks to the directory holding `path` and checks it grants `bits`,
+    /// returns the directory with the last name of `path`
+    async fn parent(&self, path: &Path, bits: u32) -> Result<(ClientFid, String)> {
+        let mut names = self.names(path);
+        let name = names.pop().ok_or_else(|| errno(EBUSY))?;
+
+        let dir = self.walk(&names).await?;
+        let checked = match self.client.getattr(&dir, GetattrMask::BASIC).await {
+            Ok(_) if !is_dir(&dir) => Err(errno(ENOTDIR)),
+            Ok(stat) => access(&stat, bits),
+            Err(e) => Err(io_error(e)),
+        };
+        match checked {
+            Ok(()) => Ok((dir, name)),
+            Err(e) => self.clunking(dir, Err(e)).await,
+        }
+    }
+
+    async fn set_current_dir(&self, path: &Path) -> Result<()> {
+        let names = self.names(path);
+        if !self.qid_type(path).await?.contains(QidType::DIR) {
+            return Err(errno(ENOTDIR));
+        }
+
+        *self.cwd.lock().unwrap() = names
+            .iter()
+            .fold(PathBuf::from("/"), |cwd, name| cwd.join(name));
+        Ok(())
+    }
+
+    async fn create_dir(&self, path: &Path) -> Result<()> {
+        let (dir, name) = self.parent(path, W_OK | X_OK).await?;
+        let res = self.client.mkdir(&dir, &name, 0o755, 0).await;
+        self.clunking(dir, res.map(|_| ()).map_err(io_error)).await
+    }
+
+    async fn create_dir_all(&self, path: &Path) -> Result<()> {
+        let mut dir = PathBuf::from("/");
+        for name in self.names(path) {
+            dir.push(name);
+            match self.create_dir(&dir).await {
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {
+                    if !self.qid_type(&dir).await?.contains(QidType::DIR) {
+                        return Err(e);
+                    }
+                }
+                res => res?,
+            }
+        }
+        Ok(())
+    }
+
+    async fn unlink(&self, path: &Path, flags: u32) -> Result<()> {
+        let (dir, name) = self.parent(path, W_OK | X_OK).await?;
+        let res = self.client.unlinkat(&dir, &name, flags).await;
+        self.clunking(dir, res.map_err(io_error)).await
+    }
+
+    fn remove_dir_all<'a>(&'a self, path: &'a Path) -> LocalBoxFuture<'a, Result<()>> {
+        async move {
+            for entry in self.read_dir(path).await? {
+                match entry.is_dir {
+                    true => self.remove_dir_all(&entry.path).await?,
+                    false => self.unlink(&entry.path, 0).await?,
+                }
+            }
+            self.unlink(path, AT_REMOVEDIR).await
+        }
+        .boxed_local()
+    }
+
+    async fn read_dir(&self, path: &Path) -> Result<Vec<Entry>> {
+        let (mut fid, stat) = self.lookup(path).await?;
+        let res = async {
+            if !is_dir(&fid) {
+                return Err(errno(ENOTDIR));
+            }
+            access(&stat, R_OK)?;
+            self.client.open(&mut fid, 0).await.map_err(io_error)?;
+            self.client.readdir(&fid).await.map_err(io_error)
+        }
+        .await;
+
+        Ok(self
+            .clunking(fid, res)
+            .await?
+            .into_iter()
+            .filter(|entry| entry.name != "." && entry.name != "..")
+            .map(|entry| Entry {
+                path: path.join(&entry.name),
+                is_dir: entry.qid.typ.contains(QidType::DIR),
+                name: entry.name,
+            })
+            .collect())
+    }
+
+    /// Opens existing file at `path` for reading or writing, as `bits` tell
+    async fn open(&self, path: &Path, bits: u32) -> Result<ClientFid> {
+        let (mut fid, stat) = self.lookup(path).await?;
+        let flags = match bits {
+            R_OK => 0,
+            W_OK => FileOpenMode::P9_DOTL_WRONLY.bits(),
+            _ => FileOpenMode::P9_DOTL_RDWR.bits(),
+        };
+
+        let res = async {
+            if is_dir(&fid) {
+                return Err(errno(EISDIR));
+            }
+            access(&stat, bits)?;
+            self.client.open(&mut fid, flags).await.map_err(io_error)
+        }
+        .await;
+
+        match res {
+            Ok(()) => Ok(fid),
+            Err(e) => self.clunking(fid, Err(e)).await,
+        }
+    }
+
+    /// Creates new file at `path`, opened for writing
+    async fn create(&self, path: &Path) -> Result<ClientFid> {
+        let (mut fid, name) = self.parent(path, W_OK | X_OK).await?;
+        let flags = FileOpenMode::P9_DOTL_WRONLY
+            | FileOpenMode::P9_DOTL_CREATE
+            | FileOpenMode::P9_DOTL_EXCL;
+
+        let res = async {
+            // Kernel finds existing file during lookup, before asking the server
+            match self.walk(&self.names(path)).await {
+                Ok(existing) => {
+                    self.client.clunk(existing).await.map_err(io_error)?;
+                    return Err(errno(EEXIST));
+                }
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                Err(_) => {}
+            }
+            self.client
+                .create(&mut fid, &name, flags.bits(), 0o644, 0)
+                .await
+                .map_err(io_error)
+        }
+        .await;
+
+        match res {
+            Ok(()) => Ok(fid),
+            Err(e) => self.clunking(fid, Err(e)).await,
+        }
+    }
+
+    /// Truncates opened `fid` and writes `buf` to it, like `O_TRUNC` does over a mount
+    async fn overwrite(&self, fid: ClientFid, buf: &[u8]) -> Result<()> {
+        let res = async {
+            let stat = SetAttr {
+                mode: 0,
+                uid: 0,
+                gid: 0,
+                size: 0,
+                atime: Time { sec: 0, nsec: 0 },
+                mtime: Time { sec: 0, nsec: 0 },
+            };
+            self.client
+                .setattr(&fid, SetattrMask::SIZE, stat)
+                .await
+                .map_err(io_error)?;
+            self.write(&fid, buf).await
+        }
+        .await;
+        self.clunking(fid, res).await
+    }
+
+    async fn write(&self, fid: &ClientFid, buf: &[u8]) -> Result<()> {
+        let written = self.client.write(fid, 0, buf).await.map_err(io_error)?;
+        match written as usize == buf.len() {
+            true => Ok(()),
+            false => Err(Error::from(ErrorKind::WriteZero)),
+        }
+    }
+
+    async fn write_file(&self, path: &Path, buf: &[u8]) -> Result<()> {
+        match self.open(path, W_OK).await {
+            Ok(fid) => self.overwrite(fid, buf).await,
+            Err(e) if e.kind() == ErrorKind::NotFound => self.create_file(path, buf).await,
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn create_file(&self, path: &Path, buf: &[u8]) -> Result<()> {
+        let fid = self.create(path).await?;
+        let res = self.write(&fid, buf).await;
+        self.clunking(fid, res).await
+    }
+
+    async fn read_file(&self, path: &Path) -> Result<Vec<u8>> {
+        let fid = self.open(path, R_OK).await?;
+        let res = self.client.read(&fid, 0, u32::MAX).await;
+        self.clunking(fid, res.map_err(io_error)).await
+    }
+
+    async fn copy_file(&self, from: &Path, to: &Path) -> Result<()> {
+        if self.qid_type(from).await?.contains(QidType::DIR) {
+            return Err(errno(EINVAL));
+        }
+        let buf = self.read_file(from).await?;
+        self.write_file(to, &buf).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let (olddir, oldname) = self.parent(from, W_OK | X_OK).await?;
+        let (newdir, newname) = match self.parent(to, W_OK | X_OK).await {
+            Ok(parent) => parent,
+            Err(e) => return self.clunking(olddir, Err(e)).await,
+        };
+
+        let res = self
+            .client
+            .renameat(&olddir, &oldname, &newdir, &newname)
+            .await
+            .map_err(io_error);
+        let res = self.clunking(newdir, res).await;
+        self.clunking(olddir, res).await
+    }
+
+    /// Sets permission bits of `path` to the result of `mode` called with the current ones
+    async fn chmod(&self, path: &Path, mode: impl FnOnce(u32) -> u32) -> Result<()> {
+        let (fid, stat) = self.lookup(path).await?;
+        let attr = SetAttr {
+            mode: (stat.mode & S_IFMT) | (mode(stat.mode) & !S_IFMT),
+            uid: 0,
+            gid: 0,
+            size: 0,
+            atime: Time { sec: 0, nsec: 0 },
+            mtime: Time { sec: 0, nsec: 0 },
+        };
+        let res = self.client.setattr(&fid, SetattrMask::MODE, attr).await;
+        self.clunking(fid, res.map_err(io_error)).await
+    }
+}
+
+/// Directory entry with its type, which `P9DirEntry` does not expose
+struct Entry {
+    name: String,
+    path: PathBuf,
+    is_dir: bool,
+}
+
+impl FileSystem for P9FileSystem {
+    type DirEntry = P9DirEntry;
+    type ReadDir = P9ReadDir;
+
+    fn current_dir(&self) -> Result<PathBuf> {
+        Ok(self.inner.cwd.lock().unwrap().clone())
+    }
+
+    fn set_current_dir<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.block_on(self.inner.set_current_dir(path.as_ref()))
+    }
+
+    fn is_dir<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.block_on(self.inner.qid_type(path.as_ref()))
+            .map(|typ| typ.contains(QidType::DIR))
+            .unwrap_or(false)
+    }
+
+    fn is_file<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.block_on(self.inner.qid_type(path.as_ref()))
+            .map(|typ| !typ.intersects(QidType::DIR | QidType::SYMLINK))
+            .unwrap_or(false)
+    }
+
+    fn create_dir<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.block_on(self.inner.create_dir(path.as_ref()))
+    }
+
+    fn create_dir_all<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.block_on(self.inner.create_dir_all(path.as_ref()))
+    }
+
+    fn remove_dir<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.block_on(self.inner.unlink(path.as_ref(), AT_REMOVEDIR))
+    }
+
+    fn remove_dir_all<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.block_on(self.inner.remove_dir_all(path.as_ref()))
+    }
+
+    fn read_dir<P: AsRef<Path>>(&self, path: P) -> Result<Self::ReadDir> {
+        let entries = self.block_on(self.inner.read_dir(path.as_ref()))?;
+        let entries: Vec<P9DirEntry> = entries
+            .into_iter()
+            .map(|entry| P9DirEntry {
+                name: entry.name,
+                path: entry.path,
+            })
+            .collect();
+        Ok(P9ReadDir(entries.into_iter()))
+    }
+
+    fn create_file<P, B>(&self, path: P, buf: B) -> Result<()>
+    where
+        P: AsRef<Path>,
+        B: AsRef<[u8]>,
+    {
+        self.block_on(self.inner.create_file(path.as_ref(), buf.as_ref()))
+    }
+
+    fn write_file<P, B>(&self, path: P, buf: B) -> Result<()>
+    where
+        P: AsRef<Path>,
+        B: AsRef<[u8]>,
+    {
+        self.block_on(self.inner.write_file(path.as_ref(), buf.as_ref()))
+    }
+
+    fn overwrite_file<P, B>(&self, path: P, buf: B) -> Result<()>
+    where
+        P: AsRef<Path>,
+        B: AsRef<[u8]>,
+    {
+        self.block_on(async {
+            let fid = self.inner.open(path.as_ref(), W_OK).await?;
+            self.inner.overwrite(fid, buf.as_ref()).await
+        })
+    }
+
+    fn read_file<P: AsRef<Path>>(&self, path: P) -> Result<Vec<u8>> {
+        self.block_on(self.inner.read_file(path.as_ref()))
+    }
+
+    fn read_file_to_string<P: AsRef<Path>>(&self, path: P) -> Result<String> {
+        String::from_utf8(self.read_file(path)?).map_err(|e| Error::new(ErrorKind::InvalidData, e))
+    }
+
+    fn read_file_into<P, B>(&self, path: P, mut buf: B) -> Result<usize>
+    where
+        P: AsRef<Path>,
+        B: AsMut<Vec<u8>>,
+    {
+        let contents = self.read_file(path)?;
+        buf.as_mut().extend_from_slice(&contents);
+        Ok(contents.len())
+    }
+
+    fn remove_file<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.block_on(self.inner.unlink(path.as_ref(), 0))
+    }
+
+    fn copy_file<P, Q>(&self, from: P, to: Q) -> Result<()>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        self.block_on(self.inner.copy_file(from.as_ref(), to.as_ref()))
+    }
+
+    fn rename<P, Q>(&self, from: P, to: Q) -> Result<()>
+    where
+        P: AsRef<Path>,
+        Q: AsRef<Path>,
+    {
+        self.block_on(self.inner.rename(from.as_ref(), to.as_ref()))
+    }
+
+    fn readonly<P: AsRef<Path>>(&self, path: P) -> Result<bool> {
+        let stat = self.block_on(self.inner.stat(path.as_ref()))?;
+        Ok(stat.mode & 0o222 == 0)
+    }
+
+    fn set_readonly<P: AsRef<Path>>(&self, path: P, readonly: bool) -> Result<()> {
+        self.block_on(self.inner.chmod(path.as_ref(), |mode| match readonly {
+            true => mode & !0o222,
+            false => mode | 0o222,
+        }))
+    }
+
+    fn len<P: AsRef<Path>>(&self, path: P) -> u64 {
+        self.block_on(self.inner.stat(path.as_ref()))
+            .map(|stat| stat.size)
+            .unwrap_or(0)
+    }
+
+    fn accessed<P: AsRef<Path>>(&self, path: P) -> Result<SystemTime> {
+        let stat = self.block_on(self.inner.stat(path.as_ref()))?;
+        Ok(system_time(stat.atime))
+    }
+
+    fn modified<P: AsRef<Path>>(&self, path: P) -> Result<SystemTime> {
+        let stat = self.block_on(self.inner.stat(path.as_ref()))?;
+        Ok(system_time(stat.mtime))
+    }
+}
+
+#[cfg(unix)]
+impl UnixFileSystem for P9FileSystem {
+    fn mode<P: AsRef<Path>>(&self, path: P) -> Result<u32> {
+        let stat = self.block_on(self.inner.stat(path.as_ref()))?;
+        Ok(stat.mode)
+    }
+
+    fn set_mode<P: AsRef<Path>>(&self, path: P, mode: u32) -> Result<()> {
+        self.block_on(self.inner.chmod(path.as_ref(), |_| mode))
+    }
+}
+
+impl TempFileSystem for P9FileSystem {
+    type TempDir = P9TempDir;
+
+    fn temp_dir<S: AsRef<str>>(&self, prefix: S) -> Result<Self::TempDir> {
+        let id = self.inner.temp_dirs.fetch_add(1, Ordering::SeqCst);
+        let path = Path::new("/tmp").join(format!("{}.{}", prefix.as_ref(), id));
+
+        self.create_dir_all(&path)?;
+        Ok(P9TempDir {
+            path,
+            fs: self.clone(),
+        })
+    }
+}
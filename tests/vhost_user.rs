@@ -0,0 +1,548 @@
+//! vhost-user backend driven by a minimal master living in the test process.
+//!
+//! The master shares memfd backed "guest memory", lays out split virtqueue in it,
+//! and submits 9P requests the same way virtio-9p driver of the guest would.
+#![cfg(target_os = "linux")]
+
+use std::ffi::CString;
+use std::io::{IoSlice, Read};
+use std::num::NonZeroUsize;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::Path;
+use std::ptr;
+use std::sync::atomic::{fence, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use byteorder::{ByteOrder, LittleEndian};
+use nix::sys::eventfd::{eventfd, EfdFlags};
+use nix::sys::memfd::{memfd_create, MemFdCreateFlag};
+use nix::sys::mman::{mmap, MapFlags, ProtFlags};
+use nix::sys::socket::{sendmsg, ControlMessage, MsgFlags};
+use tokio::sync::Mutex;
+
+use ya_vm_file_server::core::attributes_cache::VirtualAttributesProvider;
+use ya_vm_file_server::core::fcall::{Data, Fcall, GetattrMask, Msg, QidType, NOFID, NOTAG};
+use ya_vm_file_server::core::serialize;
+use ya_vm_file_server::core::srv::{srv_async_with_config, ServerConfig};
+use ya_vm_file_server::implementation::unpfs::Unpfs;
+
+const GET_FEATURES: u32 = 1;
+const SET_FEATURES: u32 = 2;
+const SET_OWNER: u32 = 3;
+const SET_MEM_TABLE: u32 = 5;
+const SET_VRING_NUM: u32 = 8;
+const SET_VRING_ADDR: u32 = 9;
+const SET_VRING_BASE: u32 = 10;
+const GET_VRING_BASE: u32 = 11;
+const SET_VRING_KICK: u32 = 12;
+const SET_VRING_CALL: u32 = 13;
+const GET_PROTOCOL_FEATURES: u32 = 15;
+const SET_PROTOCOL_FEATURES: u32 = 16;
+const SET_VRING_ENABLE: u32 = 18;
+const GET_CONFIG: u32 = 24;
+
+const NEED_REPLY: u32 = 0x1 << 3;
+const PROTOCOL_FEATURES: u64 = 0x1 << 30;
+const PROTOCOL_F_REPLY_ACK: u64 = 0x1 << 3;
+const PROTOCOL_F_CONFIG: u64 = 0x1 << 9;
+
+const MEMORY_SIZE: usize = 1 << 20;
+const GUEST_PHYS_BASE: u64 = 0x4000_0000;
+const QUEUE_SIZE: u16 = 16;
+const DESC_OFFSET: usize = 0x0;
+const AVAIL_OFFSET: usize = 0x1000;
+const USED_OFFSET: usize = 0x2000;
+const BUFFERS_OFFSET: usize = 0x10000;
+const BUFFER_SIZE: usize = 0x4000;
+
+/// Master side of vhost-user connection, as hypervisor would drive it
+struct Master {
+    stream: UnixStream,
+}
+
+impl Master {
+    fn connect(path: &Path) -> Master {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        loop {
+            match UnixStream::connect(path) {
+                Ok(stream) => return Master { stream },
+                Err(e) if Instant::now() > deadline => panic!("Backend not started: {}", e),
+                Err(_) => std::thread::sleep(Duration::from_millis(10)),
+            }
+        }
+    }
+
+    fn send(&mut self, request: u32, flags: u32, payload: &[u8], fds: &[RawFd]) {
+        let mut message = vec![0u8; 12];
+        LittleEndian::write_u32(&mut message[0..], request);
+        LittleEndian::write_u32(&mut message[4..], 0x1 | flags);
+        LittleEndian::write_u32(&mut message[8..], payload.len() as u32);
+        message.extend_from_slice(payload);
+
+        let cmsgs = [ControlMessage::ScmRights(fds)];
+        let cmsgs = if fds.is_empty() {
+            &cmsgs[..0]
+        } else {
+            &cmsgs[..]
+        };
+        let sent = sendmsg::<()>(
+            self.stream.as_raw_fd(),
+            &[IoSlice::new(&message)],
+            cmsgs,
+            MsgFlags::empty(),
+            None,
+        )
+        .unwrap();
+        assert_eq!(sent, message.len());
+    }
+
+    fn receive(&mut self, request: u32) -> Vec<u8> {
+        let mut header = [0u8; 12];
+        self.stream.read_exact(&mut header).unwrap();
+        assert_eq!(LittleEndian::read_u32(&header[0..]), request);
+        assert_eq!(LittleEndian::read_u32(&header[4..]), 0x1 | 0x4);
+
+        let mut payload = vec![0u8; LittleEndian::read_u32(&header[8..]) as usize];
+        self.stream.read_exact(&mut payload).unwrap();
+        payload
+    }
+
+    fn get_u64(&mut self, request: u32) -> u64 {
+        self.send(request, 0, &[], &[]);
+        LittleEndian::read_u64(&self.receive(request))
+    }
+
+    /// Sends request asking for acknowledgment, returns true on success
+    fn acked(&mut self, request: u32, payload: &[u8], fds: &[RawFd]) -> bool {
+        self.send(request, NEED_REPLY, payload, fds);
+        LittleEndian::read_u64(&self.receive(request)) == 0
+    }
+}
+
+fn u32s(values: &[u32]) -> Vec<u8> {
+    let mut payload = vec![0u8; 4 * values.len()];
+    LittleEndian::write_u32_into(values, &mut payload);
+    payload
+}
+
+fn u64s(values: &[u64]) -> Vec<u8> {
+    let mut payload = vec![0u8; 8 * values.len()];
+    LittleEndian::write_u64_into(values, &mut payload);
+    payload
+}
+
+/// Guest driver side of the virtqueue
+struct Driver {
+    memory: *mut u8,
+    /// Memory table the master shares, with fd of the guest memory
+    mem_table: (Vec<u8>, OwnedFd),
+    kick: OwnedFd,
+    call: OwnedFd,
+    next_avail: u16,
+}
+
+impl Driver {
+    fn at(&self, offset: usize) -> *mut u8 {
+        unsafe { self.memory.add(offset) }
+    }
+
+    fn buffer(&self, head: u16) -> usize {
+        BUFFERS_OFFSET + head as usize * BUFFER_SIZE
+    }
+
+    fn write_desc(&self, index: u16, offset: usize, len: u32, flags: u16, next: u16) {
+        let mut desc = [0u8; 16];
+        LittleEndian::write_u64(&mut desc[0..], GUEST_PHYS_BASE + offset as u64);
+        LittleEndian::write_u32(&mut desc[8..], len);
+        LittleEndian::write_u16(&mut desc[12..], flags);
+        LittleEndian::write_u16(&mut desc[14..], next);
+        unsafe {
+            ptr::copy_nonoverlapping(
+                desc.as_ptr(),
+                self.at(DESC_OFFSET + 16 * index as usize),
+                16,
+            )
+        };
+    }
+
+    /// Puts request into readable descriptor followed by writable one for the response
+    fn submit(&mut self, msg: &Msg) {
+        let head = (self.next_avail % (QUEUE_SIZE / 2)) * 2;
+        let request_offset = self.buffer(head);
+        let response_offset = request_offset + BUFFER_SIZE / 2;
+
+        let mut request = vec![0u8; 4];
+        serialize::write_msg(&mut request, msg).unwrap();
+        let len = request.len() as u32;
+        LittleEndian::write_u32(&mut request, len);
+        unsafe {
+            ptr::copy_nonoverlapping(request.as_ptr(), self.at(request_offset), request.len())
+        };
+
+        self.write_desc(head, request_offset, len, 0x1, head + 1);
+        self.write_desc(head + 1, response_offset, (BUFFER_SIZE / 2) as u32, 0x2, 0);
+
+        unsafe {
+            let slot = (self.next_avail % QUEUE_SIZE) as usize;
+            ptr::write_volatile(self.at(AVAIL_OFFSET + 4 + 2 * slot) as *mut u16, head);
+            self.next_avail = self.next_avail.wrapping_add(1);
+            fence(Ordering::Release);
+            ptr::write_volatile(self.at(AVAIL_OFFSET + 2) as *mut u16, self.next_avail);
+        }
+        fence(Ordering::SeqCst);
+
+        nix::unistd::write(self.kick.as_raw_fd(), &1u64.to_ne_bytes()).unwrap();
+    }
+
+    fn used_idx(&self) -> u16 {
+        unsafe { ptr::read_volatile(self.at(USED_OFFSET + 2) as *const u16) }
+    }
+
+    /// Waits for the backend to consume `count` buffers in total, returns responses by head
+    fn wait_used(&mut self, count: u16) -> Vec<(u16, Msg)> {
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while self.used_idx() != count {
+            assert!(Instant::now() < deadline, "Backend did not respond");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        fence(Ordering::Acquire);
+
+        let mut counter = [0u8; 8];
+        nix::unistd::read(self.call.as_raw_fd(), &mut counter)
+            .expect("Used buffers were not signalled");
+
+        (0..count)
+            .map(|i| unsafe {
+                let elem = self.at(USED_OFFSET + 4 + 8 * (i % QUEUE_SIZE) as usize);
+                let head = ptr::read_volatile(elem as *const u32) as u16;
+                let len = ptr::read_volatile(elem.add(4) as *const u32) as usize;
+
+                let mut response = vec![0u8; len];
+                let response_offset = self.buffer(head) + BUFFER_SIZE / 2;
+                ptr::copy_nonoverlapping(self.at(response_offset), response.as_mut_ptr(), len);
+                assert_eq!(LittleEndian::read_u32(&response) as usize, len);
+
+                (head, serialize::read_msg(&mut &response[4..]).unwrap())
+            })
+            .collect()
+    }
+}
+
+fn start_backend(root: &Path, socket: &Path) {
+    let filesystem = Unpfs {
+        realroot: root.to_path_buf(),
+        vap: Arc::new(Mutex::new(VirtualAttributesProvider::new())),
+        options: Default::default(),
+        locks: Default::default(),
+        usage: Default::default(),
+    };
+    let socket = socket.to_str().unwrap().to_string();
+
+    std::thread::spawn(move || {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        let config = ServerConfig {
+            mount_tag: Some("test-tag".to_string()),
+            ..Default::default()
+        };
+        runtime
+            .block_on(srv_async_with_config(
+                filesystem,
+                "vhost-user",
+                &socket,
+                &config,
+            ))
+            .unwrap();
+    });
+}
+
+/// Negotiates features, shares memory and starts the virtqueue
+fn setup_device(master: &mut Master) -> Driver {
+    setup_device_with_size(master, Some(QUEUE_SIZE))
+}
+
+/// Sets the device up as `setup_device`, without giving the virtqueue size if it is `None`
+fn setup_device_with_size(master: &mut Master, queue_size: Option<u16>) -> Driver {
+    let features = master.get_u64(GET_FEATURES);
+    assert_ne!(features & 0x1, 0, "Mount tag feature is not offered");
+    assert_ne!(features & PROTOCOL_FEATURES, 0);
+    master.send(SET_FEATURES, 0, &u64s(&[features]), &[]);
+
+    let protocol_features = master.get_u64(GET_PROTOCOL_FEATURES);
+    assert_ne!(protocol_features & PROTOCOL_F_REPLY_ACK, 0);
+    assert_ne!(protocol_features & PROTOCOL_F_CONFIG, 0);
+    master.send(
+        SET_PROTOCOL_FEATURES,
+        0,
+        &u64s(&[PROTOCOL_F_REPLY_ACK | PROTOCOL_F_CONFIG]),
+        &[],
+    );
+    assert!(master.acked(SET_OWNER, &[], &[]));
+
+    let memfd = memfd_create(
+        &CString::new("guest-memory").unwrap(),
+        MemFdCreateFlag::empty(),
+    )
+    .unwrap();
+    let memfd = unsafe { OwnedFd::from_raw_fd(memfd) };
+    nix::unistd::ftruncate(memfd.as_raw_fd(), MEMORY_SIZE as i64).unwrap();
+    let memory = unsafe {
+        mmap(
+            None,
+            NonZeroUsize::new(MEMORY_SIZE).unwrap(),
+            ProtFlags::PROT_READ | ProtFlags::PROT_WRITE,
+            MapFlags::MAP_SHARED,
+            memfd.as_raw_fd(),
+            0,
+        )
+        .unwrap() as *mut u8
+    };
+    let userspace_addr = memory as u64;
+
+    let mut mem_table = u32s(&[1, 0]);
+    mem_table.extend(u64s(&[
+        GUEST_PHYS_BASE,
+        MEMORY_SIZE as u64,
+        userspace_addr,
+        0,
+    ]));
+    assert!(master.acked(SET_MEM_TABLE, &mem_table, &[memfd.as_raw_fd()]));
+
+    let kick = unsafe { OwnedFd::from_raw_fd(eventfd(0, EfdFlags::EFD_CLOEXEC).unwrap()) };
+    let call = unsafe {
+        OwnedFd::from_raw_fd(eventfd(0, EfdFlags::EFD_CLOEXEC | EfdFlags::EFD_NONBLOCK).unwrap())
+    };
+
+    if let Some(queue_size) = queue_size {
+        master.send(SET_VRING_NUM, 0, &u32s(&[0, queue_size as u32]), &[]);
+    }
+    let mut addr = u32s(&[0, 0]);
+    addr.extend(u64s(&[
+        userspace_addr + DESC_OFFSET as u64,
+        userspace_addr + USED_OFFSET as u64,
+        userspace_addr + AVAIL_OFFSET as u64,
+        0,
+    ]));
+    master.send(SET_VRING_ADDR, 0, &addr, &[]);
+    master.send(SET_VRING_BASE, 0, &u32s(&[0, 0]), &[]);
+    master.send(SET_VRING_KICK, 0, &u64s(&[0]), &[kick.as_raw_fd()]);
+    master.send(SET_VRING_CALL, 0, &u64s(&[0]), &[call.as_raw_fd()]);
+    assert!(master.acked(SET_VRING_ENABLE, &u32s(&[0, 1]), &[]));
+
+    Driver {
+        memory,
+        mem_table: (mem_table, memfd),
+        kick,
+        call,
+        next_avail: 0,
+    }
+}
+
+#[test]
+fn reports_mount_tag_in_config_space() {
+    let temp_dir = tempdir::TempDir::new("reports_mount_tag_in_config_space").unwrap();
+    let socket = temp_dir.path().join("vhost.sock");
+    start_backend(temp_dir.path(), &socket);
+
+    let mut master = Master::connect(&socket);
+    let features = master.get_u64(GET_FEATURES);
+    master.send(SET_FEATURES, 0, &u64s(&[features]), &[]);
+    master.send(SET_PROTOCOL_FEATURES, 0, &u64s(&[PROTOCOL_F_CONFIG]), &[]);
+
+    let mut request = u32s(&[0, 10, 0]);
+    request.extend([0u8; 10]);
+    master.send(GET_CONFIG, 0, &request, &[]);
+    let config = master.receive(GET_CONFIG);
+
+    assert_eq!(LittleEndian::read_u32(&config[4..]), 10);
+    assert_eq!(LittleEndian::read_u16(&config[12..]), 8);
+    assert_eq!(&config[14..22], b"test-tag");
+}
+
+#[test]
+fn serves_9p_requests_from_virtqueue() {
+    let temp_dir = tempdir::TempDir::new("serves_9p_requests_from_virtqueue").unwrap();
+    let root = temp_dir.path().join("export");
+    std::fs::create_dir(&root).unwrap();
+    let socket = temp_dir.path().join("vhost.sock");
+    start_backend(&root, &socket);
+
+    let mut master = Master::connect(&socket);
+    let mut driver = setup_device(&mut master);
+
+    driver.submit(&Msg {
+        tag: NOTAG,
+        body: Fcall::Tversion {
+            msize: 8192,
+            version: "9P2000.L".to_string(),
+        },
+    });
+    assert_eq!(
+        driver.wait_used(1)[0].1.body,
+        Fcall::Rversion {
+            msize: 8192,
+            version: "9P2000.L".to_string(),
+        }
+    );
+
+    driver.submit(&Msg {
+        tag: 1,
+        body: Fcall::Tattach {
+            fid: 1,
+            afid: NOFID,
+            uname: "".to_string(),
+            aname: "".to_string(),
+            n_uname: 0,
+        },
+    });
+    match &driver.wait_used(2)[1].1.body {
+        Fcall::Rattach { qid } => assert_eq!(qid.typ, QidType::DIR),
+        other => panic!("Invalid response {:?}", other),
+    }
+
+    driver.submit(&Msg {
+        tag: 2,
+        body: Fcall::Twalk {
+            fid: 1,
+            newfid: 2,
+            wnames: vec![],
+        },
+    });
+    driver.wait_used(3);
+
+    driver.submit(&Msg {
+        tag: 3,
+        body: Fcall::Tlcreate {
+            fid: 2,
+            name: "test.txt".to_string(),
+            flags: 2,
+            mode: 0o644,
+            gid: 0,
+        },
+    });
+    match &driver.wait_used(4)[3].1.body {
+        Fcall::Rlcreate { qid, .. } => assert_eq!(qid.typ, QidType::FILE),
+        other => panic!("Invalid response {:?}", other),
+    }
+
+    // Two requests in flight at the same time
+    driver.submit(&Msg {
+        tag: 4,
+        body: Fcall::Twrite {
+            fid: 2,
+            offset: 0,
+            data: Data(b"hello".to_vec().into()),
+        },
+    });
+    driver.submit(&Msg {
+        tag: 5,
+        body: Fcall::Tgetattr {
+            fid: 1,
+            req_mask: GetattrMask::ALL,
+        },
+    });
+    let used = driver.wait_used(6);
+    let mut responses: Vec<_> = used[4..].iter().map(|(_, msg)| msg.clone()).collect();
+    responses.sort_by_key(|msg| msg.tag);
+    assert_eq!(responses[0].body, Fcall::Rwrite { count: 5 });
+    assert!(matches!(responses[1].body, Fcall::Rgetattr { .. }));
+
+    assert_eq!(std::fs::read(root.join("test.txt")).unwrap(), b"hello");
+
+    // Stopping the ring reports how far the backend got
+    master.send(GET_VRING_BASE, 0, &u32s(&[0]), &[]);
+    let base = master.receive(GET_VRING_BASE);
+    assert_eq!(LittleEndian::read_u32(&base[4..]), 6);
+}
+
+#[test]
+fn waits_for_virtqueue_size_before_serving() {
+    let temp_dir = tempdir::TempDir::new("waits_for_virtqueue_size_before_serving").unwrap();
+    let socket = temp_dir.path().join("vhost.sock");
+    start_backend(temp_dir.path(), &socket);
+
+    let mut master = Master::connect(&socket);
+    let mut driver = setup_device_with_size(&mut master, None);
+
+    // Kick of the ring without size is left for when the ring is started
+    let version = Msg {
+        tag: NOTAG,
+        body: Fcall::Tversion {
+            msize: 8192,
+            version: "9P2000.L".to_string(),
+        },
+    };
+    driver.submit(&version);
+    std::thread::sleep(Duration::from_millis(50));
+    assert_eq!(driver.used_idx(), 0);
+
+    master.send(SET_VRING_NUM, 0, &u32s(&[0, QUEUE_SIZE as u32]), &[]);
+    assert!(master.acked(SET_VRING_ENABLE, &u32s(&[0, 1]), &[]));
+    assert!(matches!(
+        driver.wait_used(1)[0].1.body,
+        Fcall::Rversion { msize: 8192, .. }
+    ));
+}
+
+#[test]
+fn keeps_requests_in_flight_across_memory_remap() {
+    let temp_dir = tempdir::TempDir::new("keeps_requests_in_flight_across_memory_remap").unwrap();
+    let root = temp_dir.path().join("export");
+    std::fs::create_dir(&root).unwrap();
+    nix::unistd::mkfifo(&root.join("fifo"), nix::sys::stat::Mode::S_IRWXU).unwrap();
+    let socket = temp_dir.path().join("vhost.sock");
+    start_backend(&root, &socket);
+
+    let mut master = Master::connect(&socket);
+    let mut driver = setup_device(&mut master);
+
+    driver.submit(&Msg {
+        tag: NOTAG,
+        body: Fcall::Tversion {
+            msize: 8192,
+            version: "9P2000.L".to_string(),
+        },
+    });
+    driver.wait_used(1);
+    driver.submit(&Msg {
+        tag: 1,
+        body: Fcall::Tattach {
+            fid: 1,
+            afid: NOFID,
+            uname: "".to_string(),
+            aname: "".to_string(),
+            n_uname: 0,
+        },
+    });
+    driver.wait_used(2);
+    driver.submit(&Msg {
+        tag: 2,
+        body: Fcall::Twalk {
+            fid: 1,
+            newfid: 2,
+            wnames: vec!["fifo".to_string()],
+        },
+    });
+    assert!(matches!(driver.wait_used(3)[2].1.body, Fcall::Rwalk { .. }));
+
+    // Opening FIFO for reading waits for a writer, meanwhile the master maps memory again
+    driver.submit(&Msg {
+        tag: 3,
+        body: Fcall::Tlopen { fid: 2, flags: 0 },
+    });
+    std::thread::sleep(Duration::from_millis(50));
+    let (mem_table, memfd) = &driver.mem_table;
+    assert!(master.acked(SET_MEM_TABLE, mem_table, &[memfd.as_raw_fd()]));
+
+    let _writer = std::fs::OpenOptions::new()
+        .write(true)
+        .open(root.join("fifo"))
+        .unwrap();
+    let used = driver.wait_used(4);
+    assert_eq!(used[3].1.tag, 3);
+    assert!(matches!(used[3].1.body, Fcall::Rlopen { .. }));
+}